@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE user_roles;
+DROP TYPE user_role;
@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TYPE user_role AS ENUM ('user', 'moderator', 'admin');
+
+CREATE TABLE user_roles (
+user_id UUID REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+role user_role NOT NULL,
+granted_at TIMESTAMP WITH TIME ZONE NOT NULL,
+PRIMARY KEY (user_id, role)
+)
@@ -0,0 +1,10 @@
+-- Add down migration script here
+DROP TABLE moderation_log;
+DROP TYPE moderation_action;
+DROP TABLE chirp_reports;
+
+ALTER TABLE users
+DROP COLUMN suspended_at;
+
+ALTER TABLE chirps
+DROP COLUMN hidden_at;
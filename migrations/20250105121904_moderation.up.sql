@@ -0,0 +1,31 @@
+-- Add up migration script here
+ALTER TABLE chirps
+ADD COLUMN hidden_at TIMESTAMP WITH TIME ZONE;
+
+ALTER TABLE users
+ADD COLUMN suspended_at TIMESTAMP WITH TIME ZONE;
+
+CREATE TABLE chirp_reports (
+report_id UUID PRIMARY KEY,
+chirp_id UUID REFERENCES chirps(chirp_id) ON DELETE CASCADE NOT NULL,
+reporter_id UUID REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+reason TEXT NOT NULL,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+resolved_at TIMESTAMP WITH TIME ZONE,
+resolved_by UUID REFERENCES users(id) ON DELETE SET NULL,
+UNIQUE (chirp_id, reporter_id)
+);
+
+CREATE TYPE moderation_action AS ENUM ('hide_chirp', 'unhide_chirp', 'suspend_user', 'unsuspend_user', 'resolve_report');
+
+-- Targets are deliberately not foreign keys, so that the log outlives the rows it refers to.
+CREATE TABLE moderation_log (
+entry_id UUID PRIMARY KEY,
+moderator_id UUID REFERENCES users(id) ON DELETE SET NULL,
+action moderation_action NOT NULL,
+target_chirp_id UUID,
+target_user_id UUID,
+target_report_id UUID,
+reason TEXT,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL
+)
@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP INDEX users_handle_key;
+
+ALTER TABLE users
+DROP COLUMN handle,
+DROP COLUMN display_name,
+DROP COLUMN bio,
+DROP COLUMN avatar_url;
@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- The default is evaluated per row, so existing users get distinct placeholder handles.
+ALTER TABLE users
+ADD COLUMN handle TEXT NOT NULL DEFAULT 'user_' || substr(replace(gen_random_uuid()::text, '-', ''), 1, 12),
+ADD COLUMN display_name TEXT,
+ADD COLUMN bio TEXT,
+ADD COLUMN avatar_url TEXT;
+
+CREATE UNIQUE INDEX users_handle_key ON users (lower(handle));
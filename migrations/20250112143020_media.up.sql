@@ -0,0 +1,17 @@
+-- Add up migration script here
+CREATE TABLE media (
+media_id UUID PRIMARY KEY,
+owner_id UUID REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+content_type TEXT NOT NULL,
+size_bytes BIGINT NOT NULL,
+storage_key TEXT NOT NULL,
+url TEXT NOT NULL,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE TABLE chirp_media (
+chirp_id UUID REFERENCES chirps(chirp_id) ON DELETE CASCADE NOT NULL,
+media_id UUID REFERENCES media(media_id) ON DELETE CASCADE NOT NULL,
+position SMALLINT NOT NULL,
+PRIMARY KEY (chirp_id, media_id)
+)
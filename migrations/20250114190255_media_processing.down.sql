@@ -0,0 +1,15 @@
+-- Add down migration script here
+DROP TABLE media_variants;
+
+DROP INDEX media_pending_idx;
+
+ALTER TABLE media
+DROP COLUMN status,
+DROP COLUMN attempts,
+DROP COLUMN processing_started_at,
+DROP COLUMN processed_at,
+DROP COLUMN width,
+DROP COLUMN height,
+DROP COLUMN blurhash;
+
+DROP TYPE media_status;
@@ -0,0 +1,28 @@
+-- Add up migration script here
+CREATE TYPE media_status AS ENUM ('pending', 'processing', 'ready', 'failed');
+
+-- Media uploaded before processing existed was served as-is, so it is considered ready.
+ALTER TABLE media
+ADD COLUMN status media_status NOT NULL DEFAULT 'ready',
+ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0,
+ADD COLUMN processing_started_at TIMESTAMP WITH TIME ZONE,
+ADD COLUMN processed_at TIMESTAMP WITH TIME ZONE,
+ADD COLUMN width INTEGER,
+ADD COLUMN height INTEGER,
+ADD COLUMN blurhash TEXT;
+
+ALTER TABLE media
+ALTER COLUMN status SET DEFAULT 'pending';
+
+CREATE INDEX media_pending_idx ON media (created_at) WHERE status IN ('pending', 'processing');
+
+CREATE TABLE media_variants (
+media_id UUID REFERENCES media(media_id) ON DELETE CASCADE NOT NULL,
+variant TEXT NOT NULL,
+width INTEGER NOT NULL,
+height INTEGER NOT NULL,
+content_type TEXT NOT NULL,
+storage_key TEXT NOT NULL,
+url TEXT NOT NULL,
+PRIMARY KEY (media_id, variant)
+)
@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP TABLE static_visitors;
+
+DROP TABLE static_user_agent_hits;
+
+DROP TABLE static_referrer_hits;
+
+DROP TABLE static_path_hits;
@@ -0,0 +1,29 @@
+-- Add up migration script here
+CREATE TABLE static_path_hits (
+day DATE NOT NULL,
+path TEXT NOT NULL,
+status INTEGER NOT NULL,
+hits BIGINT NOT NULL,
+PRIMARY KEY (day, path, status)
+);
+
+-- Referrers are recorded by host, so that query strings and other personal data in referring URLs are never stored.
+CREATE TABLE static_referrer_hits (
+day DATE NOT NULL,
+referrer TEXT NOT NULL,
+hits BIGINT NOT NULL,
+PRIMARY KEY (day, referrer)
+);
+
+CREATE TABLE static_user_agent_hits (
+day DATE NOT NULL,
+family TEXT NOT NULL,
+hits BIGINT NOT NULL,
+PRIMARY KEY (day, family)
+);
+
+-- HyperLogLog registers, one byte per register. Sketches of different days can be merged by taking the maximum of each register.
+CREATE TABLE static_visitors (
+day DATE PRIMARY KEY,
+registers BYTEA NOT NULL
+);
@@ -0,0 +1,13 @@
+-- Add up migration script here
+-- Redirects are not counted as visits, since `ServeDir` redirects directories to the path with a trailing slash.
+CREATE TABLE static_hourly_visits (
+hour TIMESTAMP WITH TIME ZONE PRIMARY KEY,
+visits BIGINT NOT NULL
+);
+
+-- Earlier visits were only recorded per day, so they are attributed to the first hour of their day.
+INSERT INTO static_hourly_visits (hour, visits)
+SELECT day::TIMESTAMP AT TIME ZONE 'UTC', SUM(hits)
+FROM static_path_hits
+WHERE status NOT BETWEEN 300 AND 399 OR status = 304
+GROUP BY day;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- Processed webhook events, so that redelivered or replayed events are only applied once.
+CREATE TABLE polka_events (
+event_id TEXT PRIMARY KEY,
+event TEXT NOT NULL,
+received_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
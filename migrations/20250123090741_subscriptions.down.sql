@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE subscriptions;
+
+DROP TYPE subscription_status;
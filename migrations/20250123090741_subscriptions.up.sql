@@ -0,0 +1,21 @@
+-- Add up migration script here
+CREATE TYPE subscription_status AS ENUM ('active', 'past_due', 'canceled', 'expired', 'refunded');
+
+-- `users.is_chirpy_red` is derived from this table and kept in sync by the queries that change it. Don't set it directly.
+CREATE TABLE subscriptions (
+user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+status subscription_status NOT NULL,
+started_at TIMESTAMP WITH TIME ZONE NOT NULL,
+current_period_end TIMESTAMP WITH TIME ZONE NOT NULL,
+canceled_at TIMESTAMP WITH TIME ZONE,
+updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX subscriptions_lapsing_idx ON subscriptions (current_period_end)
+WHERE status IN ('active', 'past_due', 'canceled');
+
+-- Upgrades used to last forever. Existing members get one period, after which they need a renewal event like everyone else.
+INSERT INTO subscriptions (user_id, status, started_at, current_period_end, updated_at)
+SELECT id, 'active', updated_at, NOW() + INTERVAL '30 days', NOW()
+FROM users
+WHERE is_chirpy_red;
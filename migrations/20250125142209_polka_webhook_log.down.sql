@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE polka_webhook_log;
+
+DROP TYPE webhook_status;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+CREATE TYPE webhook_status AS ENUM ('received', 'processed', 'duplicate', 'ignored', 'rejected', 'malformed', 'failed');
+
+-- Every request to the Polka webhook, including ones that fail verification, so that missed events can be found and replayed.
+CREATE TABLE polka_webhook_log (
+webhook_id UUID PRIMARY KEY,
+received_at TIMESTAMP WITH TIME ZONE NOT NULL,
+headers TEXT NOT NULL,
+payload BYTEA NOT NULL,
+event_id TEXT,
+event TEXT,
+status webhook_status NOT NULL,
+error TEXT,
+attempts INTEGER NOT NULL DEFAULT 0,
+last_attempt_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX polka_webhook_log_received_at_idx ON polka_webhook_log (received_at);
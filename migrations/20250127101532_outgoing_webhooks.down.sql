@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TABLE webhook_deliveries;
+
+DROP TYPE delivery_status;
+
+DROP TABLE webhook_endpoints;
@@ -0,0 +1,34 @@
+-- Add up migration script here
+-- Endpoints registered by a user only receive events about that user. Endpoints without an owner are registered by admins and receive all events.
+CREATE TABLE webhook_endpoints (
+endpoint_id UUID PRIMARY KEY,
+owner_id UUID REFERENCES users ON DELETE CASCADE,
+url TEXT NOT NULL,
+secret TEXT NOT NULL,
+events TEXT[] NOT NULL,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX webhook_endpoints_owner_id_idx ON webhook_endpoints (owner_id);
+
+CREATE TYPE delivery_status AS ENUM ('pending', 'succeeded', 'failed');
+
+-- One row per event and endpoint. The table doubles as the delivery queue.
+CREATE TABLE webhook_deliveries (
+delivery_id UUID PRIMARY KEY,
+endpoint_id UUID NOT NULL REFERENCES webhook_endpoints ON DELETE CASCADE,
+event_id UUID NOT NULL,
+event TEXT NOT NULL,
+payload TEXT NOT NULL,
+status delivery_status NOT NULL,
+attempts INTEGER NOT NULL DEFAULT 0,
+next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL,
+last_attempt_at TIMESTAMP WITH TIME ZONE,
+response_status INTEGER,
+error TEXT,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+delivered_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX webhook_deliveries_endpoint_id_idx ON webhook_deliveries (endpoint_id, created_at);
+CREATE INDEX webhook_deliveries_pending_idx ON webhook_deliveries (next_attempt_at) WHERE status = 'pending';
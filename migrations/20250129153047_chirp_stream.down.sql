@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TRIGGER chirps_notify ON chirps;
+
+DROP FUNCTION notify_chirp_event;
+
+DROP TABLE follows;
@@ -0,0 +1,28 @@
+-- Add up migration script here
+CREATE TABLE follows (
+follower_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+followee_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+PRIMARY KEY (follower_id, followee_id),
+CHECK (follower_id <> followee_id)
+);
+
+CREATE INDEX follows_followee_id_idx ON follows (followee_id);
+
+-- Tells every server instance about new and removed chirps, for the chirp stream. Hiding a chirp removes it from the stream like deleting it.
+-- Notifications are only delivered when the transaction commits, so listeners can read the chirp and its media right away.
+CREATE FUNCTION notify_chirp_event() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM pg_notify('chirp_events', json_build_object('event', 'created', 'chirp_id', NEW.chirp_id, 'user_id', NEW.user_id)::text);
+    ELSIF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('chirp_events', json_build_object('event', 'deleted', 'chirp_id', OLD.chirp_id, 'user_id', OLD.user_id)::text);
+    ELSIF OLD.hidden_at IS NULL AND NEW.hidden_at IS NOT NULL THEN
+        PERFORM pg_notify('chirp_events', json_build_object('event', 'deleted', 'chirp_id', NEW.chirp_id, 'user_id', NEW.user_id)::text);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER chirps_notify AFTER INSERT OR DELETE OR UPDATE OF hidden_at ON chirps
+FOR EACH ROW EXECUTE FUNCTION notify_chirp_event();
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX webhook_deliveries_endpoint_event_idx;
+
+DROP TABLE outbox;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- Domain events, written in the same transaction as the change they describe and dispatched to subscribers by a background worker.
+CREATE TABLE outbox (
+event_id UUID PRIMARY KEY,
+event_type TEXT NOT NULL,
+payload TEXT NOT NULL,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+attempts INTEGER NOT NULL DEFAULT 0,
+next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL,
+last_error TEXT,
+dispatched_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX outbox_pending_idx ON outbox (next_attempt_at) WHERE dispatched_at IS NULL;
+CREATE INDEX outbox_dispatched_at_idx ON outbox (dispatched_at) WHERE dispatched_at IS NOT NULL;
+
+-- Outbox events are dispatched at least once, so webhook deliveries must not be queued twice for the same event.
+CREATE UNIQUE INDEX webhook_deliveries_endpoint_event_idx ON webhook_deliveries (endpoint_id, event_id);
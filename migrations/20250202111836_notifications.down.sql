@@ -0,0 +1,9 @@
+-- Add down migration script here
+DROP TABLE notification_mutes;
+
+DROP TABLE notifications;
+
+DROP TYPE notification_type;
+
+ALTER TABLE chirps
+DROP COLUMN in_reply_to;
@@ -0,0 +1,30 @@
+-- Add up migration script here
+ALTER TABLE chirps
+ADD COLUMN in_reply_to UUID REFERENCES chirps (chirp_id) ON DELETE SET NULL;
+
+CREATE INDEX chirps_in_reply_to_idx ON chirps (in_reply_to);
+
+CREATE TYPE notification_type AS ENUM ('reply', 'mention', 'follow');
+
+CREATE TABLE notifications (
+notification_id UUID PRIMARY KEY,
+user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+type notification_type NOT NULL,
+actor_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+chirp_id UUID REFERENCES chirps ON DELETE CASCADE,
+-- The outbox event the notification was created from. Events are dispatched at least once, and each event notifies a user at most once.
+event_id UUID NOT NULL,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+read_at TIMESTAMP WITH TIME ZONE,
+UNIQUE (user_id, event_id)
+);
+
+CREATE INDEX notifications_user_id_idx ON notifications (user_id, created_at);
+CREATE INDEX notifications_unread_idx ON notifications (user_id) WHERE read_at IS NULL;
+
+-- Types of notification the user doesn't want.
+CREATE TABLE notification_mutes (
+user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+type notification_type NOT NULL,
+PRIMARY KEY (user_id, type)
+);
@@ -0,0 +1,26 @@
+-- Add up migration script here
+-- A one-to-one conversation. The participants are stored in a canonical order, so each pair of users has at most one conversation.
+CREATE TABLE conversations (
+conversation_id UUID PRIMARY KEY,
+user_a UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+user_b UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+last_message_at TIMESTAMP WITH TIME ZONE NOT NULL,
+CHECK (user_a < user_b),
+UNIQUE (user_a, user_b)
+);
+
+CREATE INDEX conversations_user_b_idx ON conversations (user_b);
+
+CREATE TABLE messages (
+message_id UUID PRIMARY KEY,
+conversation_id UUID NOT NULL REFERENCES conversations ON DELETE CASCADE,
+sender_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+body TEXT NOT NULL,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+-- When the recipient read the message.
+read_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX messages_conversation_id_idx ON messages (conversation_id, created_at, message_id);
+CREATE INDEX messages_unread_idx ON messages (conversation_id) WHERE read_at IS NULL;
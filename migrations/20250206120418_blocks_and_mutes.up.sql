@@ -0,0 +1,18 @@
+-- Add up migration script here
+CREATE TABLE blocks (
+blocker_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+blocked_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+PRIMARY KEY (blocker_id, blocked_id),
+CHECK (blocker_id <> blocked_id)
+);
+
+CREATE INDEX blocks_blocked_id_idx ON blocks (blocked_id);
+
+CREATE TABLE mutes (
+muter_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+muted_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+PRIMARY KEY (muter_id, muted_id),
+CHECK (muter_id <> muted_id)
+);
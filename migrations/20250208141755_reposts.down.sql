@@ -0,0 +1,6 @@
+-- Add down migration script here
+DELETE FROM chirps WHERE repost_of IS NOT NULL;
+
+ALTER TABLE chirps
+DROP COLUMN quote_of,
+DROP COLUMN repost_of;
@@ -0,0 +1,11 @@
+-- Add up migration script here
+-- A plain repost is a chirp without a body of its own. Reposts go away with the original.
+ALTER TABLE chirps
+ADD COLUMN repost_of UUID REFERENCES chirps (chirp_id) ON DELETE CASCADE,
+-- Not a foreign key, so that a quote keeps pointing at its original after the original is deleted, and can be shown as quoting an unavailable chirp.
+ADD COLUMN quote_of UUID,
+ADD CONSTRAINT chirps_repost_or_quote CHECK (repost_of IS NULL OR quote_of IS NULL);
+
+-- Each user can repost a chirp once.
+CREATE UNIQUE INDEX chirps_repost_of_key ON chirps (repost_of, user_id);
+CREATE INDEX chirps_quote_of_idx ON chirps (quote_of);
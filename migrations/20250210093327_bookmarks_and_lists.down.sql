@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP TABLE list_members;
+
+DROP TABLE lists;
+
+DROP TYPE list_visibility;
+
+DROP TABLE bookmarks;
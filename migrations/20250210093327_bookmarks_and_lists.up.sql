@@ -0,0 +1,30 @@
+-- Add up migration script here
+CREATE TABLE bookmarks (
+user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+chirp_id UUID NOT NULL REFERENCES chirps ON DELETE CASCADE,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+PRIMARY KEY (user_id, chirp_id)
+);
+
+CREATE TYPE list_visibility AS ENUM ('private', 'public');
+
+CREATE TABLE lists (
+list_id UUID PRIMARY KEY,
+owner_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+name TEXT NOT NULL,
+description TEXT,
+visibility list_visibility NOT NULL,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX lists_owner_id_idx ON lists (owner_id);
+
+CREATE TABLE list_members (
+list_id UUID NOT NULL REFERENCES lists ON DELETE CASCADE,
+user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+added_at TIMESTAMP WITH TIME ZONE NOT NULL,
+PRIMARY KEY (list_id, user_id)
+);
+
+CREATE INDEX list_members_user_id_idx ON list_members (user_id);
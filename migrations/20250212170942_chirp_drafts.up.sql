@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- Chirps that are saved but not published yet. Scheduled chirps are drafts with a publish time, and are moved to `chirps` once it has passed.
+CREATE TABLE chirp_drafts (
+draft_id UUID PRIMARY KEY,
+user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+body TEXT NOT NULL,
+media_ids UUID[] NOT NULL,
+in_reply_to UUID,
+quote_of UUID,
+publish_at TIMESTAMP WITH TIME ZONE,
+created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+updated_at TIMESTAMP WITH TIME ZONE NOT NULL,
+-- Why the chirp couldn't be published when it was due. Drafts that fail to publish are unscheduled.
+last_error TEXT
+);
+
+CREATE INDEX chirp_drafts_user_id_idx ON chirp_drafts (user_id);
+CREATE INDEX chirp_drafts_publish_at_idx ON chirp_drafts (publish_at) WHERE publish_at IS NOT NULL;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE chirp_drafts DROP COLUMN expires_in;
+
+ALTER TABLE chirps DROP COLUMN expires_at;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- Ephemeral chirps disappear once they expire. They are left out of every read right away, and deleted by a background job soon after.
+ALTER TABLE chirps ADD COLUMN expires_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX chirps_expires_at_idx ON chirps (expires_at) WHERE expires_at IS NOT NULL;
+
+-- Seconds from publishing until the chirp expires, so that a scheduled chirp lives as long as one posted right away.
+ALTER TABLE chirp_drafts ADD COLUMN expires_in INTEGER;
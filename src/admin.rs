@@ -1,22 +1,155 @@
 use axum::http::StatusCode;
-use axum::{extract::State, response::Html, Extension};
+use axum::{
+    extract::{Path, Query, State},
+    response::{Html, IntoResponse},
+    Extension, Json,
+};
+use color_eyre::eyre::{ensure, Result};
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use time::{macros::format_description, Duration, OffsetDateTime};
+use uuid::Uuid;
 
-use crate::queries::delete_all_users;
+use crate::analytics::estimate_visitors;
+use crate::api::process_polka_webhook;
+use crate::list_dir::escape_html;
+use crate::queries::{
+    count_users_with_role, delete_all_users, delete_static_analytics, get_daily_visits,
+    get_hourly_visits, get_polka_webhook, get_polka_webhooks, get_status_totals, get_top_paths,
+    get_top_referrers, get_user_agent_totals, get_user_by_email, get_user_roles,
+    get_visitor_registers, grant_role, insert_user, record_polka_webhook_attempt, revoke_role,
+    PolkaWebhookSummary, Role, User, WebhookStatus,
+};
 use crate::state::{AppState, Platform};
 
-pub async fn metrics(State(state): State<AppState>) -> Html<String> {
-    let hits = { state.data.lock().unwrap().fileserver_hits };
+/// How far back the breakdowns on the metrics page go.
+const METRICS_WINDOW: Duration = Duration::days(30);
 
-    format!(
+/// How far back the hourly visits chart goes.
+const HOURLY_HISTORY: Duration = Duration::hours(48);
+
+/// Number of rows in the top paths and top referrers tables.
+const METRICS_TOP_LIMIT: i64 = 10;
+
+pub async fn metrics(
+    Extension(db): Extension<PgPool>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    // Include the hits since the last periodic flush.
+    if let Err(e) = state.analytics.flush(&db).await {
+        eprintln!("Failed to flush static analytics: {e}");
+    }
+    match metrics_page(&db, state.analytics.total_visits()).await {
+        Ok(page) => Html(page).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn metrics_page(db: &PgPool, hits: u64) -> Result<String, sqlx::Error> {
+    let now = OffsetDateTime::now_utc();
+    let today = now.date();
+    let since = today - METRICS_WINDOW;
+
+    let visitors_today = estimate_visitors(&get_visitor_registers(db, today).await?);
+    let visitors_window = estimate_visitors(&get_visitor_registers(db, since).await?);
+
+    let statuses = get_status_totals(db, since).await?;
+    let statuses = hits_table(
+        "Status",
+        statuses
+            .iter()
+            .map(|total| (total.status.to_string(), total.hits)),
+    );
+    let paths = get_top_paths(db, since, METRICS_TOP_LIMIT).await?;
+    let paths = hits_table("Path", paths.into_iter().map(|p| (p.name, p.hits)));
+    let referrers = get_top_referrers(db, since, METRICS_TOP_LIMIT).await?;
+    let referrers = hits_table("Referrer", referrers.into_iter().map(|r| (r.name, r.hits)));
+    let user_agents = get_user_agent_totals(db, since).await?;
+    let user_agents = hits_table("Browser", user_agents.into_iter().map(|u| (u.name, u.hits)));
+    let days = METRICS_WINDOW.whole_days();
+
+    let hourly = get_hourly_visits(db, now - HOURLY_HISTORY).await?;
+    let hourly = svg_bar_chart(
+        hourly
+            .iter()
+            .map(|bucket| (format_hour(bucket.hour), bucket.visits)),
+    );
+    let daily = get_daily_visits(db, since).await?;
+    let daily = svg_bar_chart(
+        daily
+            .iter()
+            .map(|bucket| (bucket.day.to_string(), bucket.visits)),
+    );
+    let hours = HOURLY_HISTORY.whole_hours();
+
+    Ok(format!(
         "<html>
   <body>
     <h1>Welcome, Chirpy Admin</h1>
     <p>Chirpy has been visited {hits} times!</p>
+    <p>Unique visitors: about {visitors_today} today, {visitors_window} in the last {days} days.</p>
+    <h2>Visits per hour, last {hours} hours</h2>
+    {hourly}
+    <h2>Visits per day, last {days} days</h2>
+    {daily}
+    <h2>Top paths, last {days} days</h2>
+    {paths}
+    <h2>Status codes, last {days} days</h2>
+    {statuses}
+    <h2>Top referrers, last {days} days</h2>
+    {referrers}
+    <h2>Browsers, last {days} days</h2>
+    {user_agents}
   </body>
 </html>"
-    )
-    .into()
+    ))
+}
+
+/// A bar chart scaled to the largest value. Hovering over a bar shows its label and value.
+fn svg_bar_chart(bars: impl ExactSizeIterator<Item = (String, i64)>) -> String {
+    const WIDTH: f64 = 600.0;
+    const HEIGHT: f64 = 100.0;
+    let bar_width = WIDTH / bars.len().max(1) as f64;
+    let bars: Vec<_> = bars.collect();
+    let max = bars
+        .iter()
+        .map(|(_, value)| *value)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{WIDTH}\" height=\"{HEIGHT}\" viewBox=\"0 0 {WIDTH} {HEIGHT}\">"
+    );
+    for (i, (label, value)) in bars.iter().enumerate() {
+        let height = *value as f64 / max as f64 * HEIGHT;
+        svg.push_str(&format!(
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{height:.1}\" fill=\"steelblue\"><title>{}: {value}</title></rect>",
+            i as f64 * bar_width,
+            HEIGHT - height,
+            bar_width * 0.8,
+            escape_html(label),
+        ));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+fn format_hour(hour: OffsetDateTime) -> String {
+    hour.format(format_description!("[year]-[month]-[day] [hour]:00 UTC"))
+        .unwrap_or_default()
+}
+
+fn hits_table(heading: &str, rows: impl Iterator<Item = (String, i64)>) -> String {
+    let mut table = format!("<table><tr><th>{heading}</th><th>Hits</th></tr>");
+    for (name, hits) in rows {
+        table.push_str(&format!(
+            "<tr><td>{}</td><td>{hits}</td></tr>",
+            escape_html(&name)
+        ));
+    }
+    table.push_str("</table>");
+    table
 }
 
 pub async fn reset(Extension(db): Extension<PgPool>, state: State<AppState>) -> StatusCode {
@@ -24,10 +157,163 @@ pub async fn reset(Extension(db): Extension<PgPool>, state: State<AppState>) ->
         return StatusCode::FORBIDDEN;
     }
 
-    state.data.lock().unwrap().fileserver_hits = 0;
+    state.analytics.reset();
+    if delete_static_analytics(&db, state.config.platform)
+        .await
+        .is_err()
+    {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
 
     match delete_all_users(db, state.config.platform).await {
         Ok(_) => StatusCode::OK,
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
+
+pub async fn list_roles(
+    Extension(db): Extension<PgPool>,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match get_user_roles(&db, user_id).await {
+        Ok(roles) => Json(roles).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GrantRolePayload {
+    role: Role,
+}
+
+pub async fn grant(
+    Extension(db): Extension<PgPool>,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<GrantRolePayload>,
+) -> impl IntoResponse {
+    // The foreign key on user_roles makes this fail for unknown users.
+    match grant_role(&db, user_id, payload.role).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::NOT_FOUND,
+    }
+}
+
+pub async fn revoke(
+    Extension(db): Extension<PgPool>,
+    Path((user_id, role)): Path<(Uuid, Role)>,
+) -> impl IntoResponse {
+    match revoke_role(&db, user_id, role).await {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Default and maximum number of entries returned by `list_webhooks`.
+const WEBHOOK_LIST_LIMIT: i64 = 100;
+const MAX_WEBHOOK_LIST_LIMIT: i64 = 1000;
+
+#[derive(Deserialize)]
+pub struct WebhookListParams {
+    status: Option<WebhookStatus>,
+    limit: Option<i64>,
+}
+
+pub async fn list_webhooks(
+    Extension(db): Extension<PgPool>,
+    Query(params): Query<WebhookListParams>,
+) -> impl IntoResponse {
+    let limit = params
+        .limit
+        .unwrap_or(WEBHOOK_LIST_LIMIT)
+        .clamp(1, MAX_WEBHOOK_LIST_LIMIT);
+    match get_polka_webhooks(&db, params.status, limit).await {
+        Ok(webhooks) => Json(webhooks).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Serialize)]
+pub struct WebhookDetails {
+    #[serde(flatten)]
+    summary: PolkaWebhookSummary,
+    headers: String,
+    /// Polka sends JSON, but the payload is logged as received, so anything else is shown lossily.
+    payload: String,
+}
+
+pub async fn get_webhook(
+    Extension(db): Extension<PgPool>,
+    Path(webhook_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match get_polka_webhook(&db, webhook_id).await {
+        Ok(webhook) => Json(WebhookDetails {
+            summary: webhook.summary,
+            headers: webhook.headers,
+            payload: String::from_utf8_lossy(&webhook.payload).into_owned(),
+        })
+        .into_response(),
+        Err(sqlx::Error::RowNotFound) => StatusCode::NOT_FOUND.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReplayError {
+    error: String,
+}
+
+/// Processes a logged webhook again, e.g. after creating a subscription that an event was missing.
+/// The signature is not checked again, since the timestamp will usually be too old by now. Instead only webhooks that passed verification and then failed can be replayed.
+pub async fn replay_webhook(
+    Extension(db): Extension<PgPool>,
+    Path(webhook_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let webhook = match get_polka_webhook(&db, webhook_id).await {
+        Ok(webhook) => webhook,
+        Err(sqlx::Error::RowNotFound) => return StatusCode::NOT_FOUND.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    if webhook.summary.status != WebhookStatus::Failed {
+        return (
+            StatusCode::CONFLICT,
+            Json(ReplayError {
+                error: "Only failed webhooks can be replayed".to_owned(),
+            }),
+        )
+            .into_response();
+    }
+
+    let outcome = process_polka_webhook(&db, &webhook.payload).await;
+    match record_polka_webhook_attempt(
+        &db,
+        webhook_id,
+        outcome.status,
+        outcome.event_id.as_deref(),
+        outcome.event,
+        outcome.error.as_deref(),
+    )
+    .await
+    {
+        Ok(summary) => Json(summary).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Creates the first admin account, or promotes the account if the email is already registered.
+/// Refuses to run once an admin exists, so that it cannot be used to take over a running deployment.
+pub async fn bootstrap_admin(db: &PgPool, email: &str, password: &str) -> Result<User> {
+    ensure!(
+        count_users_with_role(db, Role::Admin).await? == 0,
+        "An admin already exists"
+    );
+
+    let user = match get_user_by_email(db, email).await {
+        Ok(user) => user,
+        Err(sqlx::Error::RowNotFound) => insert_user(db, email, password).await?,
+        Err(e) => return Err(e.into()),
+    };
+    grant_role(db, user.id, Role::Admin).await?;
+
+    Ok(user)
+}
@@ -0,0 +1,296 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+
+use axum::http::{HeaderValue, StatusCode, Uri};
+use dashmap::DashMap;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+
+use crate::queries::{get_total_visits, record_static_analytics, StaticAnalyticsDelta};
+
+/// Bounds memory use when clients request many distinct paths, e.g. while scanning for vulnerabilities.
+/// Paths beyond this are counted as `(other)` until the next flush.
+const MAX_TRACKED_PATHS: usize = 10_000;
+
+const MAX_TRACKED_REFERRERS: usize = 1_000;
+
+const OTHER: &str = "(other)";
+
+/// Hit counts of the static file server.
+///
+/// Counters live in sharded maps of atomics, so requests never wait on a global lock, and are periodically flushed to Postgres by `flush`.
+/// Only the hits since the last flush are kept in memory, apart from the total number of visits, which is restored from the database at boot.
+pub struct StaticAnalytics {
+    total_visits: AtomicU64,
+    path_hits: DashMap<(String, u16), AtomicU64>,
+    referrer_hits: DashMap<String, AtomicU64>,
+    user_agent_hits: DashMap<&'static str, AtomicU64>,
+    visitors: HyperLogLog,
+}
+
+impl StaticAnalytics {
+    pub fn new() -> Self {
+        Self {
+            total_visits: AtomicU64::new(0),
+            path_hits: DashMap::new(),
+            referrer_hits: DashMap::new(),
+            user_agent_hits: DashMap::new(),
+            visitors: HyperLogLog::new(),
+        }
+    }
+
+    /// A visitor is identified by their IP address and user agent, which are only ever stored as part of a HyperLogLog sketch.
+    pub fn record(
+        &self,
+        path: &str,
+        status: StatusCode,
+        referrer: Option<&HeaderValue>,
+        user_agent: Option<&HeaderValue>,
+        client: Option<IpAddr>,
+    ) {
+        if is_visit(status) {
+            self.total_visits.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut key = (path.to_owned(), status.as_u16());
+        if !self.path_hits.contains_key(&key) && self.path_hits.len() >= MAX_TRACKED_PATHS {
+            key.0 = OTHER.to_owned();
+        }
+        add(&self.path_hits, key, 1);
+
+        let mut referrer = referrer_host(referrer);
+        if !self.referrer_hits.contains_key(&referrer)
+            && self.referrer_hits.len() >= MAX_TRACKED_REFERRERS
+        {
+            referrer = OTHER.to_owned();
+        }
+        add(&self.referrer_hits, referrer, 1);
+
+        let user_agent = user_agent.and_then(|ua| ua.to_str().ok()).unwrap_or("");
+        add(&self.user_agent_hits, user_agent_family(user_agent), 1);
+
+        if let Some(client) = client {
+            self.visitors.insert(&(client, user_agent));
+        }
+    }
+
+    /// Adds the visits recorded by earlier runs of the server to the total.
+    pub async fn restore_total_visits(&self, db: &PgPool) -> Result<(), sqlx::Error> {
+        let visits = get_total_visits(db).await?;
+        self.total_visits
+            .fetch_add(visits as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn total_visits(&self) -> u64 {
+        self.total_visits.load(Ordering::Relaxed)
+    }
+
+    /// Forgets all hits, including the total number of visits.
+    pub fn reset(&self) {
+        self.take();
+        self.total_visits.store(0, Ordering::Relaxed);
+    }
+
+    /// Writes the hits since the last flush to the database. They are kept in memory if that fails, to be retried on the next flush.
+    pub async fn flush(&self, db: &PgPool) -> Result<(), sqlx::Error> {
+        let delta = self.take();
+        if delta.path_hits.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = record_static_analytics(db, OffsetDateTime::now_utc(), &delta).await {
+            self.restore(delta);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Removes and returns all hits recorded since the last call.
+    fn take(&self) -> StaticAnalyticsDelta {
+        let path_hits: Vec<_> = drain(&self.path_hits)
+            .into_iter()
+            .map(|((path, status), hits)| (path, status as i32, hits))
+            .collect();
+        let visits = path_hits
+            .iter()
+            .filter(|(_, status, _)| StatusCode::from_u16(*status as u16).is_ok_and(is_visit))
+            .map(|(_, _, hits)| hits)
+            .sum();
+        StaticAnalyticsDelta {
+            path_hits,
+            visits,
+            referrer_hits: drain(&self.referrer_hits),
+            user_agent_hits: drain(&self.user_agent_hits)
+                .into_iter()
+                .map(|(family, hits)| (family.to_owned(), hits))
+                .collect(),
+            visitor_registers: self.visitors.take(),
+        }
+    }
+
+    fn restore(&self, delta: StaticAnalyticsDelta) {
+        for (path, status, hits) in delta.path_hits {
+            add(&self.path_hits, (path, status as u16), hits);
+        }
+        for (referrer, hits) in delta.referrer_hits {
+            add(&self.referrer_hits, referrer, hits);
+        }
+        for (family, hits) in delta.user_agent_hits {
+            add(
+                &self.user_agent_hits,
+                USER_AGENT_FAMILIES
+                    .iter()
+                    .find(|&&known| known == family)
+                    .copied()
+                    .unwrap_or("Other"),
+                hits,
+            );
+        }
+        self.visitors.merge(&delta.visitor_registers);
+    }
+}
+
+/// `ServeDir` redirects directories to the path with a trailing slash, so counting redirects would count those visits twice.
+fn is_visit(status: StatusCode) -> bool {
+    !status.is_redirection() || status == StatusCode::NOT_MODIFIED
+}
+
+fn add<K: Hash + Eq>(map: &DashMap<K, AtomicU64>, key: K, hits: i64) {
+    // Most hits are for keys that already exist, which only needs a read lock on one shard.
+    if let Some(counter) = map.get(&key) {
+        counter.fetch_add(hits as u64, Ordering::Relaxed);
+    } else {
+        map.entry(key)
+            .or_default()
+            .fetch_add(hits as u64, Ordering::Relaxed);
+    }
+}
+
+/// Removing the entries while holding the shard's write lock ensures that no concurrent increment is lost.
+fn drain<K: Hash + Eq + Clone>(map: &DashMap<K, AtomicU64>) -> Vec<(K, i64)> {
+    let mut drained = Vec::new();
+    map.retain(|key, counter| {
+        let hits = counter.swap(0, Ordering::Relaxed);
+        if hits > 0 {
+            drained.push((key.clone(), hits as i64));
+        }
+        false
+    });
+    drained
+}
+
+fn referrer_host(referrer: Option<&HeaderValue>) -> String {
+    let Some(referrer) = referrer else {
+        return "(direct)".to_owned();
+    };
+    referrer
+        .to_str()
+        .ok()
+        .and_then(|referrer| referrer.parse::<Uri>().ok())
+        .and_then(|uri| uri.host().map(|host| host.to_ascii_lowercase()))
+        .unwrap_or_else(|| "(invalid)".to_owned())
+}
+
+const USER_AGENT_FAMILIES: [&str; 9] = [
+    "Bot", "curl", "Edge", "Opera", "Firefox", "Chrome", "Safari", "Other", "(none)",
+];
+
+/// Order matters, since most browsers claim to be several others for compatibility.
+fn user_agent_family(user_agent: &str) -> &'static str {
+    let lowercase = user_agent.to_ascii_lowercase();
+    if user_agent.is_empty() {
+        "(none)"
+    } else if ["bot", "crawler", "spider"]
+        .iter()
+        .any(|marker| lowercase.contains(marker))
+    {
+        "Bot"
+    } else if lowercase.starts_with("curl/") {
+        "curl"
+    } else if user_agent.contains("Edg/") {
+        "Edge"
+    } else if user_agent.contains("OPR/") {
+        "Opera"
+    } else if user_agent.contains("Firefox/") {
+        "Firefox"
+    } else if user_agent.contains("Chrome/") || user_agent.contains("CriOS/") {
+        "Chrome"
+    } else if user_agent.contains("Safari/") {
+        "Safari"
+    } else {
+        "Other"
+    }
+}
+
+/// Number of bits of the hash used to pick a register. 2^12 registers give a standard error of about 1.6%.
+const HLL_PRECISION: u32 = 12;
+
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+
+/// A HyperLogLog sketch for estimating the number of distinct items, with registers that can be updated concurrently.
+///
+/// Items are hashed with fixed keys, so sketches from different runs of the server can be merged.
+/// `DefaultHasher` may change between Rust releases, which makes visitors seen both before and after an upgrade count twice.
+struct HyperLogLog {
+    registers: Box<[AtomicU8]>,
+}
+
+impl HyperLogLog {
+    fn new() -> Self {
+        Self {
+            registers: (0..HLL_REGISTERS).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+
+    fn insert(&self, item: &impl Hash) {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let hash = hasher.finish();
+        let index = (hash >> (64 - HLL_PRECISION)) as usize;
+        // The position of the first set bit in the remaining bits. The sentinel bit bounds it when they are all zero.
+        let rank = ((hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() + 1;
+        self.registers[index].fetch_max(rank as u8, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> Vec<u8> {
+        self.registers
+            .iter()
+            .map(|register| register.swap(0, Ordering::Relaxed))
+            .collect()
+    }
+
+    fn merge(&self, registers: &[u8]) {
+        for (register, &value) in self.registers.iter().zip(registers) {
+            register.fetch_max(value, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Estimates the number of distinct items in the union of the given sketches.
+pub fn estimate_visitors(sketches: &[Vec<u8>]) -> u64 {
+    let mut registers = vec![0u8; HLL_REGISTERS];
+    for sketch in sketches {
+        for (register, &value) in registers.iter_mut().zip(sketch) {
+            *register = (*register).max(value);
+        }
+    }
+
+    let m = HLL_REGISTERS as f64;
+    let alpha = 0.7213 / (1.0 + 1.079 / m);
+    let sum: f64 = registers
+        .iter()
+        .map(|&register| 2f64.powi(-(register as i32)))
+        .sum();
+    let estimate = alpha * m * m / sum;
+
+    // The raw estimate is biased for small cardinalities, where linear counting of empty registers is more accurate.
+    let zeros = registers.iter().filter(|&&register| register == 0).count();
+    if estimate <= 2.5 * m && zeros > 0 {
+        (m * (m / zeros as f64).ln()).round() as u64
+    } else {
+        estimate.round() as u64
+    }
+}
@@ -1,8 +1,12 @@
 use std::{collections::HashMap, ops::Deref};
 
 use axum::{
+    body::Bytes,
     extract::{Path, Query},
-    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    http::{
+        header::{AUTHORIZATION, COOKIE},
+        HeaderMap, StatusCode,
+    },
     response::IntoResponse,
     Extension, Json,
 };
@@ -13,47 +17,355 @@ use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
 use crate::{
-    auth::{JwtKey, PolkaAPIKey},
+    auth::{JwtKey, PolkaWebhookKeys},
+    entitlements::{
+        Entitlement, Entitlements, RateLimiter, MAX_CHIRP_LENGTH, MAX_LONG_CHIRP_LENGTH,
+    },
+    media::MAX_MEDIA_PER_CHIRP,
     queries::{
-        self, delete_chirp_if_author, get_all_chirps_by_author_sorted_by_creation,
-        get_all_chirps_sorted_by_creation, get_refresh_token_entry, get_user_by_email,
-        insert_chirp, insert_user, make_user_red, new_refresh_token, revoke_refresh_token,
-        update_user_credentials, RefreshTokenEntry, SortOrder, User,
+        self, apply_polka_event, block_user, cancel_user_deletion, count_owned_media,
+        delete_chirp_if_author, delete_repost, delete_user, follow_user,
+        get_all_chirps_by_author_sorted_by_creation, get_all_chirps_sorted_by_creation,
+        get_author_summaries, get_blocked_users, get_media_for_chirps, get_muted_users,
+        get_owned_media, get_public_profile, get_refresh_token_entry, get_repost_counts, get_user,
+        get_user_by_email, get_variants_for_media, get_visible_chirps, insert_chirp, insert_repost,
+        insert_user, is_blocked_between, log_polka_webhook, mute_user, new_refresh_token,
+        record_polka_webhook_attempt, revoke_refresh_token, schedule_user_deletion, unblock_user,
+        unfollow_user, unmute_user, update_chirp_if_author, update_profile,
+        update_user_credentials, AuthorSummary, ChirpMedia, MediaStatus, MediaVariant, PolkaEvent,
+        ProfileUpdate, RefreshTokenEntry, RepostCounts, SortOrder, User, WebhookStatus,
     },
 };
 
+/// Bounds on how long after posting an ephemeral chirp can expire.
+const MIN_CHIRP_TTL: Duration = Duration::minutes(1);
+const MAX_CHIRP_TTL: Duration = Duration::days(30);
+
+/// Polka's events are small, so larger webhook bodies are refused before they are read.
+pub const MAX_POLKA_WEBHOOK_SIZE: usize = 64 * 1024;
+/// How much of the headers and body of a webhook that fails verification is logged.
+const MAX_REJECTED_POLKA_HEADERS: usize = 2048;
+const MAX_REJECTED_POLKA_PAYLOAD: usize = 1024;
+
 #[derive(Deserialize)]
 pub struct PostChirpPayload {
     body: String,
+    /// Ids of media previously uploaded through `POST /api/media`.
+    #[serde(default)]
+    media_ids: Vec<Uuid>,
+    /// Id of the chirp being replied to.
+    in_reply_to: Option<Uuid>,
+    /// Id of the chirp being quoted.
+    quote_of: Option<Uuid>,
+    /// Seconds until the chirp expires. Chirps without one don't expire.
+    expires_in: Option<i32>,
+}
+
+/// A chirp that has passed validation and is ready to be inserted.
+pub struct NewChirp {
+    pub body: ChirpBody,
+    pub media_ids: Vec<Uuid>,
+    pub in_reply_to: Option<Uuid>,
+    pub quote_of: Option<Uuid>,
+    pub expires_in: Option<i32>,
+}
+
+/// Checks a chirp the user is about to post or save for later.
+/// References to reposts are replaced by the reposted chirp, see `referenced_chirp`.
+pub async fn validate_new_chirp(
+    db: &PgPool,
+    user: &User,
+    payload: PostChirpPayload,
+) -> Result<NewChirp, axum::response::Response> {
+    let entitlements = Entitlements::for_user(user);
+    let body = validate_chirp_body(payload.body, &entitlements)?;
+
+    if let Some(expires_in) = payload.expires_in
+        && !(MIN_CHIRP_TTL..=MAX_CHIRP_TTL).contains(&Duration::seconds(expires_in.into()))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ChirpValidationError {
+                error: format!(
+                    "Chirps must expire between {} seconds and {} days after they are posted",
+                    MIN_CHIRP_TTL.whole_seconds(),
+                    MAX_CHIRP_TTL.whole_days()
+                ),
+            }),
+        )
+            .into_response());
+    }
+
+    if let Err(error) = validate_media_ids(db, user.id, &payload.media_ids).await {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ChirpValidationError { error }),
+        )
+            .into_response());
+    }
+
+    let mut referenced = [None, None];
+    for (i, (chirp_id, unknown)) in [
+        (payload.in_reply_to, "Unknown chirp to reply to"),
+        (payload.quote_of, "Unknown chirp to quote"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let Some(chirp_id) = chirp_id else {
+            continue;
+        };
+        match referenced_chirp(db, user.id, chirp_id).await {
+            Ok(chirp) => referenced[i] = Some(chirp.chirp_id),
+            Err(StatusCode::NOT_FOUND) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(ChirpValidationError {
+                        error: unknown.to_owned(),
+                    }),
+                )
+                    .into_response())
+            }
+            Err(status) => return Err(status.into_response()),
+        }
+    }
+    let [in_reply_to, quote_of] = referenced;
+
+    Ok(NewChirp {
+        body,
+        media_ids: payload.media_ids,
+        in_reply_to,
+        quote_of,
+        expires_in: payload.expires_in,
+    })
 }
 
 pub async fn post_chirp(
     Extension(db): Extension<PgPool>,
     Extension(key): Extension<JwtKey>,
+    Extension(rate_limiter): Extension<RateLimiter>,
     headers: HeaderMap,
     Json(chirp_payload): Json<PostChirpPayload>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    let new_chirp = match validate_new_chirp(&db, &user, chirp_payload).await {
+        Ok(new_chirp) => new_chirp,
+        Err(response) => return response,
+    };
+
+    let entitlements = Entitlements::for_user(&user);
+    if let Err(limited) = rate_limiter.check(user.id, entitlements.chirp_writes_per_minute()) {
+        return limited.into_response();
+    }
+
+    let Ok(chirp) = insert_chirp(
+        db.clone(),
+        new_chirp.body,
+        user.id,
+        &new_chirp.media_ids,
+        new_chirp.in_reply_to,
+        new_chirp.quote_of,
+        new_chirp.expires_in,
+    )
+    .await
+    else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    match hydrate_chirps(&db, vec![chirp], false).await {
+        Ok(mut chirps) => (StatusCode::CREATED, Json(chirps.remove(0))).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Looks up a chirp that a new chirp refers to, as a reply, quote or repost. References to a repost are taken to refer to the reposted chirp.
+/// Hidden chirps and chirps by suspended users are not found, and chirps by users who have blocked the user, or whom they have blocked, are forbidden.
+pub async fn referenced_chirp(
+    db: &PgPool,
+    user_id: Uuid,
+    chirp_id: Uuid,
+) -> Result<Chirp, StatusCode> {
+    let lookup = |chirp_id| async move {
+        queries::get_chirp(db.clone(), chirp_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => StatusCode::NOT_FOUND,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            })
+    };
+    let mut chirp = lookup(chirp_id).await?;
+    if let Some(original_id) = chirp.repost_of {
+        chirp = lookup(original_id).await?;
+    }
+    match is_blocked_between(db, user_id, chirp.user_id).await {
+        Ok(false) => Ok(chirp),
+        Ok(true) => Err(StatusCode::FORBIDDEN),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Reposts the chirp, or the chirp it reposts. Each user can repost a chirp once.
+pub async fn repost(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    Extension(rate_limiter): Extension<RateLimiter>,
+    headers: HeaderMap,
+    Path(chirp_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    let original = match referenced_chirp(&db, user.id, chirp_id).await {
+        Ok(original) => original,
+        Err(status) => return status.into_response(),
+    };
+
+    let entitlements = Entitlements::for_user(&user);
+    if let Err(limited) = rate_limiter.check(user.id, entitlements.chirp_writes_per_minute()) {
+        return limited.into_response();
+    }
+
+    let chirp = match insert_repost(&db, user.id, original.chirp_id).await {
+        Ok(Some(chirp)) => chirp,
+        Ok(None) => return StatusCode::CONFLICT.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    match hydrate_chirps(&db, vec![chirp], false).await {
+        Ok(mut chirps) => (StatusCode::CREATED, Json(chirps.remove(0))).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Undoes a repost. Takes the id of the reposted chirp, like `repost`.
+pub async fn unrepost(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(chirp_id): Path<Uuid>,
 ) -> impl IntoResponse {
     let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
-        return StatusCode::UNAUTHORIZED.into_response();
+        return StatusCode::UNAUTHORIZED;
     };
+    match delete_repost(&db, user_id, chirp_id).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(sqlx::Error::RowNotFound) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
 
-    let Ok(body) = ChirpBody::try_from(chirp_payload.body) else {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(ChirpValidationError {
-                error: "Chirp is too long".to_string(),
-            }),
-        )
-            .into_response();
+#[derive(Deserialize)]
+pub struct EditChirpPayload {
+    body: String,
+}
+
+/// Replaces the body of a chirp. Media attachments are unchanged.
+pub async fn edit_chirp(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    Extension(rate_limiter): Extension<RateLimiter>,
+    Path(chirp_id): Path<Uuid>,
+    headers: HeaderMap,
+    Json(payload): Json<EditChirpPayload>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    let entitlements = Entitlements::for_user(&user);
+    if let Err(missing) = entitlements.require(Entitlement::EditChirps) {
+        return missing.into_response();
+    }
+
+    let body = match validate_chirp_body(payload.body, &entitlements) {
+        Ok(body) => body,
+        Err(response) => return response,
     };
 
-    match insert_chirp(db, body, user_id).await {
-        Ok(chirp) => (StatusCode::CREATED, Json(chirp)).into_response(),
+    if let Err(limited) = rate_limiter.check(user.id, entitlements.chirp_writes_per_minute()) {
+        return limited.into_response();
+    }
+
+    let Ok(chirp) = update_chirp_if_author(&db, chirp_id, user.id, body).await else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+    match hydrate_chirps(&db, vec![chirp], false).await {
+        Ok(mut chirps) => (StatusCode::OK, Json(chirps.remove(0))).into_response(),
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     }
 }
 
-fn extract_user_id_from_bearer(headers: &HeaderMap, key: &JwtKey) -> Result<Uuid> {
+/// The user making the request, as long as their account is neither deleted nor suspended.
+pub async fn authenticate_active_user(
+    db: &PgPool,
+    headers: &HeaderMap,
+    key: &JwtKey,
+) -> Result<User, StatusCode> {
+    let user_id =
+        extract_user_id_from_bearer(headers, key).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    match get_user(db, user_id).await {
+        Ok(user) if user.is_deleted() => Err(StatusCode::UNAUTHORIZED),
+        Ok(user) if user.is_suspended() => Err(StatusCode::FORBIDDEN),
+        Ok(user) => Ok(user),
+        Err(_) => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Chirps that would fit within the longer limit are rejected with the entitlement the user is missing, so that clients can offer an upgrade.
+fn validate_chirp_body(
+    body: String,
+    entitlements: &Entitlements,
+) -> Result<ChirpBody, axum::response::Response> {
+    let length = body.chars().count();
+    let error = match ChirpBody::new(body, entitlements.max_chirp_length()) {
+        Ok(body) => return Ok(body),
+        Err(error) => error,
+    };
+    match entitlements.require(Entitlement::LongChirps) {
+        Err(missing) if length <= MAX_LONG_CHIRP_LENGTH => Err(missing.into_response()),
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            Json(ChirpValidationError { error }),
+        )
+            .into_response()),
+    }
+}
+
+async fn validate_media_ids(db: &PgPool, user_id: Uuid, media_ids: &[Uuid]) -> Result<(), String> {
+    if media_ids.len() > MAX_MEDIA_PER_CHIRP {
+        return Err(format!(
+            "A chirp can have at most {MAX_MEDIA_PER_CHIRP} media attachments"
+        ));
+    }
+    let mut unique_ids = media_ids.to_vec();
+    unique_ids.sort_unstable();
+    unique_ids.dedup();
+    if unique_ids.len() != media_ids.len() {
+        return Err("Media attachments must be distinct".to_owned());
+    }
+    match count_owned_media(db, user_id, media_ids).await {
+        Ok(count) if count as usize == media_ids.len() => Ok(()),
+        _ => Err("Unknown media id, or media that failed processing".to_owned()),
+    }
+}
+
+/// For endpoints that work without authentication but tailor the response to authenticated users.
+/// A request without credentials is anonymous, but one with invalid credentials is rejected.
+pub fn optional_user_id_from_bearer(
+    headers: &HeaderMap,
+    key: &JwtKey,
+) -> Result<Option<Uuid>, StatusCode> {
+    if !headers.contains_key(AUTHORIZATION) {
+        return Ok(None);
+    }
+    extract_user_id_from_bearer(headers, key)
+        .map(Some)
+        .map_err(|_| StatusCode::UNAUTHORIZED)
+}
+
+pub fn extract_user_id_from_bearer(headers: &HeaderMap, key: &JwtKey) -> Result<Uuid> {
     let token = extract_bearer_token(headers)?;
 
     key.decode_user(token)
@@ -70,36 +382,35 @@ fn extract_bearer_token(headers: &HeaderMap) -> Result<&str> {
         .ok_or_eyre("AUTHORIZATION header is malformed")
 }
 
-pub fn extract_api_key(headers: &HeaderMap) -> Result<&str> {
-    let auth_str = headers
-        .get(AUTHORIZATION)
-        .ok_or_eyre("Headers missing valid AUTHORIZATION header")?
-        .to_str()?;
-
-    auth_str
-        .strip_prefix("ApiKey ")
-        .ok_or_eyre("AUTHORIZATION header is malformed")
-}
-
+/// Authenticated callers don't see chirps by authors they have muted or blocked, unless they ask for that author's chirps by `author_id`.
 pub async fn get_all_chirps(
     Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
     Query(params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    let sort_order = match params.get("sort").unwrap_or(&"asc".to_string()).as_str() {
-        "asc" => SortOrder::Asc,
-        "desc" => SortOrder::Desc,
-        _ => return StatusCode::BAD_REQUEST.into_response(),
+    let viewer_id = match optional_user_id_from_bearer(&headers, &key) {
+        Ok(viewer_id) => viewer_id,
+        Err(status) => return status.into_response(),
+    };
+
+    let Some(sort_order) = sort_order_param(&params) else {
+        return StatusCode::BAD_REQUEST.into_response();
     };
 
     let chirps = match params.get("author_id").map(|s| Uuid::try_parse(s)) {
         Some(Ok(author_id)) => {
             get_all_chirps_by_author_sorted_by_creation(&db, author_id, sort_order).await
         }
-        None => get_all_chirps_sorted_by_creation(&db, sort_order).await,
+        None => get_all_chirps_sorted_by_creation(&db, sort_order, viewer_id).await,
         Some(Err(_)) => return StatusCode::NOT_FOUND.into_response(),
     };
 
-    match chirps {
+    let Ok(chirps) = chirps else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    match hydrate_chirps(&db, chirps, wants_author_embedded(&params)).await {
         Ok(chirps) => Json(chirps).into_response(),
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     }
@@ -108,11 +419,145 @@ pub async fn get_all_chirps(
 pub async fn get_chirp(
     Extension(db): Extension<PgPool>,
     Path(chirp_id): Path<Uuid>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    match queries::get_chirp(db, chirp_id).await {
-        Ok(chirp) => Json(chirp).into_response(),
-        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    let Ok(chirp) = queries::get_chirp(db.clone(), chirp_id).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    match hydrate_chirps(&db, vec![chirp], wants_author_embedded(&params)).await {
+        Ok(mut chirps) => Json(chirps.remove(0)).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Chirp listings are sorted oldest first, unless the `sort` query parameter is `desc`.
+pub fn sort_order_param(params: &HashMap<String, String>) -> Option<SortOrder> {
+    match params.get("sort").map_or("asc", String::as_str) {
+        "asc" => Some(SortOrder::Asc),
+        "desc" => Some(SortOrder::Desc),
+        _ => None,
+    }
+}
+
+/// Authors are embedded in chirp responses with the `embed=author` query parameter.
+pub fn wants_author_embedded(params: &HashMap<String, String>) -> bool {
+    params.get("embed").is_some_and(|embed| embed == "author")
+}
+
+/// A chirp as returned by the API, together with the data that lives in other tables.
+#[derive(Serialize, Clone)]
+pub struct ChirpResponse {
+    #[serde(flatten)]
+    chirp: Chirp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<AuthorSummary>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    media: Vec<ChirpMediaResponse>,
+    repost_count: i64,
+    quote_count: i64,
+    /// The reposted or quoted chirp, always with its author embedded for attribution.
+    /// Left out if the original has been deleted or hidden since, in which case `repost_of` or `quote_of` still says what it was.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original: Option<Box<ChirpResponse>>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ChirpMediaResponse {
+    #[serde(flatten)]
+    media: ChirpMedia,
+    variants: Vec<MediaVariant>,
+}
+
+/// Looks up the related data of all chirps with one query per kind of data, rather than one per chirp.
+/// Reposted and quoted chirps are embedded one level deep: the original of an original is not embedded.
+pub async fn hydrate_chirps(
+    db: &PgPool,
+    chirps: Vec<Chirp>,
+    embed_author: bool,
+) -> Result<Vec<ChirpResponse>> {
+    let mut original_ids: Vec<Uuid> = chirps
+        .iter()
+        .filter_map(|chirp| chirp.repost_of.or(chirp.quote_of))
+        .collect();
+    original_ids.sort_unstable();
+    original_ids.dedup();
+    let originals = get_visible_chirps(db, &original_ids).await?;
+    let originals: HashMap<Uuid, ChirpResponse> =
+        hydrate_chirps_without_originals(db, originals, true)
+            .await?
+            .into_iter()
+            .map(|original| (original.chirp.chirp_id, original))
+            .collect();
+
+    let mut chirps = hydrate_chirps_without_originals(db, chirps, embed_author).await?;
+    for chirp in &mut chirps {
+        if let Some(original_id) = chirp.chirp.repost_of.or(chirp.chirp.quote_of) {
+            // The same chirp can be reposted several times in one response.
+            chirp.original = originals
+                .get(&original_id)
+                .map(|original| Box::new(original.clone()));
+        }
+    }
+    Ok(chirps)
+}
+
+async fn hydrate_chirps_without_originals(
+    db: &PgPool,
+    chirps: Vec<Chirp>,
+    embed_author: bool,
+) -> Result<Vec<ChirpResponse>> {
+    let chirp_ids: Vec<Uuid> = chirps.iter().map(|chirp| chirp.chirp_id).collect();
+    let chirp_media = get_media_for_chirps(db, &chirp_ids).await?;
+    let media_ids: Vec<Uuid> = chirp_media.iter().map(|m| m.media_id).collect();
+    let mut variants: HashMap<Uuid, Vec<MediaVariant>> = HashMap::new();
+    for variant in get_variants_for_media(db, &media_ids).await? {
+        variants.entry(variant.media_id).or_default().push(variant);
+    }
+    let mut media: HashMap<Uuid, Vec<ChirpMediaResponse>> = HashMap::new();
+    for m in chirp_media {
+        media
+            .entry(m.chirp_id)
+            .or_default()
+            .push(ChirpMediaResponse {
+                // The same media can be attached to several chirps, so the variants are cloned rather than moved.
+                variants: variants.get(&m.media_id).cloned().unwrap_or_default(),
+                media: m,
+            });
+    }
+
+    let mut authors: HashMap<Uuid, AuthorSummary> = HashMap::new();
+    if embed_author {
+        let mut author_ids: Vec<Uuid> = chirps.iter().map(|chirp| chirp.user_id).collect();
+        author_ids.sort_unstable();
+        author_ids.dedup();
+        authors = get_author_summaries(db, &author_ids)
+            .await?
+            .into_iter()
+            .map(|author| (author.id, author))
+            .collect();
     }
+
+    let repost_counts: HashMap<Uuid, RepostCounts> = get_repost_counts(db, &chirp_ids)
+        .await?
+        .into_iter()
+        .map(|counts| (counts.chirp_id, counts))
+        .collect();
+
+    Ok(chirps
+        .into_iter()
+        .map(|chirp| {
+            let counts = repost_counts.get(&chirp.chirp_id);
+            ChirpResponse {
+                author: authors.get(&chirp.user_id).cloned(),
+                media: media.remove(&chirp.chirp_id).unwrap_or_default(),
+                repost_count: counts.map_or(0, |counts| counts.reposts),
+                quote_count: counts.map_or(0, |counts| counts.quotes),
+                original: None,
+                chirp,
+            }
+        })
+        .collect())
 }
 
 pub async fn delete_chirp(
@@ -131,6 +576,44 @@ pub async fn delete_chirp(
     }
 }
 
+#[derive(Deserialize)]
+pub struct ReportChirpPayload {
+    reason: String,
+}
+
+pub async fn report_chirp(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    Path(chirp_id): Path<Uuid>,
+    headers: HeaderMap,
+    Json(payload): Json<ReportChirpPayload>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+
+    // Repeated reports of the same chirp by the same user are accepted but not recorded again.
+    match queries::report_chirp(&db, chirp_id, user_id, &payload.reason).await {
+        Ok(_) => StatusCode::ACCEPTED,
+        Err(_) => StatusCode::NOT_FOUND,
+    }
+}
+
+#[derive(Serialize)]
+pub struct NotFoundError {
+    error: String,
+}
+
+/// Fallback for unknown routes under `/api` and `/admin`.
+pub async fn api_not_found() -> impl IntoResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(NotFoundError {
+            error: "Not found".to_owned(),
+        }),
+    )
+}
+
 #[derive(Serialize)]
 pub struct ChirpValidationError {
     error: String,
@@ -144,6 +627,17 @@ pub struct Chirp {
     pub created_at: Option<OffsetDateTime>,
     pub updated_at: Option<OffsetDateTime>,
     pub body: ChirpBody,
+    /// The chirp this is a reply to. Unset if the parent has since been deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<Uuid>,
+    /// Set on plain reposts, which have an empty body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repost_of: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote_of: Option<Uuid>,
+    /// When the chirp disappears. Expired chirps are never returned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<OffsetDateTime>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::Encode)]
@@ -169,7 +663,8 @@ where
     ) -> Result<Self, Box<dyn std::error::Error + 'static + Send + Sync>> {
         let value = <&str as Decode<DB>>::decode(value)?;
 
-        Ok(Self::try_from(value.to_owned())?)
+        // Stored chirps were validated when they were written, possibly against a longer limit than the default.
+        Ok(Self(value.to_owned()))
     }
 }
 
@@ -187,19 +682,26 @@ impl Deref for ChirpBody {
     }
 }
 
+impl ChirpBody {
+    /// `max_length` is in characters rather than bytes, so that chirps in other scripts aren't cut short.
+    pub fn new(body: String, max_length: usize) -> Result<Self, String> {
+        if body.chars().count() > max_length {
+            Err("Chirp is too long".to_owned())
+        } else {
+            Ok(ChirpBody(clean_chirp(body)))
+        }
+    }
+}
+
 impl TryFrom<String> for ChirpBody {
     type Error = String;
 
     fn try_from(body: String) -> Result<Self, Self::Error> {
-        if body.len() > 140 {
-            Err("Body exceeds the maximum length of a chirp".to_owned())
-        } else {
-            Ok(ChirpBody(clean_chirp(body)))
-        }
+        Self::new(body, MAX_CHIRP_LENGTH)
     }
 }
 
-fn clean_chirp(chirp: String) -> String {
+pub fn clean_chirp(chirp: String) -> String {
     chirp
         .split_whitespace()
         .map(|w| if is_word_bad(w) { "****" } else { w })
@@ -258,6 +760,20 @@ pub async fn login(
     if let Ok(user) = user
         && user.verify(&payload.password).is_ok()
     {
+        if user.is_suspended() {
+            return (StatusCode::FORBIDDEN, "Account is suspended").into_response();
+        }
+
+        // Logging in during the grace period restores an account scheduled for deletion.
+        let user = if user.is_deleted() {
+            match cancel_user_deletion(&db, user.id).await {
+                Ok(user) => user,
+                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            }
+        } else {
+            user
+        };
+
         let (Ok(refresh_token_entry), Ok(jwt_token)) = (
             new_refresh_token(&db, &user).await,
             key.encode_user(&user.id, expires_in),
@@ -295,6 +811,13 @@ pub async fn refresh(
     let Ok(token) = authorize_user_refresh_token(&db, &headers).await else {
         return StatusCode::UNAUTHORIZED.into_response();
     };
+    // Suspended and deleted users keep their tokens only until they are revoked, so they are also checked here.
+    match get_user(&db, token.user_id).await {
+        Ok(user) if user.is_deleted() => return StatusCode::UNAUTHORIZED.into_response(),
+        Ok(user) if user.is_suspended() => return StatusCode::FORBIDDEN.into_response(),
+        Ok(_) => {}
+        Err(_) => return StatusCode::UNAUTHORIZED.into_response(),
+    }
 
     let Ok(jwt_token) = key.encode_user(&token.user_id, Duration::hours(1)) else {
         return StatusCode::INTERNAL_SERVER_ERROR.into_response();
@@ -313,11 +836,8 @@ async fn authorize_user_refresh_token(
 
     let current_time = OffsetDateTime::now_utc();
 
-    ensure!(token_entry.expires_at < current_time, "token has expired");
-
-    if let Some(revoked_at) = token_entry.revoked_at {
-        ensure!(revoked_at < current_time, "token was revoked");
-    }
+    ensure!(token_entry.expires_at > current_time, "token has expired");
+    ensure!(token_entry.revoked_at.is_none(), "token was revoked");
 
     Ok(token_entry)
 }
@@ -362,34 +882,512 @@ pub async fn update_user(
     }
 }
 
+pub async fn get_profile(
+    Extension(db): Extension<PgPool>,
+    Path(handle): Path<String>,
+) -> impl IntoResponse {
+    match get_public_profile(&db, &handle).await {
+        Ok(profile) => Json(profile).into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub async fn follow(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(handle): Path<String>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status,
+    };
+    let Ok(profile) = get_public_profile(&db, &handle).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    if profile.id == user.id {
+        return StatusCode::BAD_REQUEST;
+    }
+    match is_blocked_between(&db, user.id, profile.id).await {
+        Ok(false) => {}
+        Ok(true) => return StatusCode::FORBIDDEN,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+    }
+    match follow_user(&db, user.id, profile.id).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn unfollow(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(handle): Path<String>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    let Ok(profile) = get_public_profile(&db, &handle).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    match unfollow_user(&db, user_id, profile.id).await {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Blocked users can't follow, reply to or message the blocker, and the blocker can't do so to them either.
+/// Blocking someone also removes any follows between the two of you.
+pub async fn block(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(handle): Path<String>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status,
+    };
+    let Ok(profile) = get_public_profile(&db, &handle).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    if profile.id == user.id {
+        return StatusCode::BAD_REQUEST;
+    }
+    match block_user(&db, user.id, profile.id).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn unblock(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(handle): Path<String>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    let Ok(profile) = get_public_profile(&db, &handle).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    match unblock_user(&db, user_id, profile.id).await {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Muted users' chirps are left out of the chirps listing and the chirp stream. Unlike blocking, the muted user isn't otherwise affected.
+pub async fn mute(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(handle): Path<String>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status,
+    };
+    let Ok(profile) = get_public_profile(&db, &handle).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    if profile.id == user.id {
+        return StatusCode::BAD_REQUEST;
+    }
+    match mute_user(&db, user.id, profile.id).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn unmute(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(handle): Path<String>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    let Ok(profile) = get_public_profile(&db, &handle).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    match unmute_user(&db, user_id, profile.id).await {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn list_blocks(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match get_blocked_users(&db, user_id).await {
+        Ok(users) => Json(users).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+pub async fn list_mutes(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match get_muted_users(&db, user_id).await {
+        Ok(users) => Json(users).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+pub async fn get_me(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    match get_user(&db, user_id).await {
+        Ok(user) => Json(user).into_response(),
+        Err(_) => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PatchProfilePayload {
+    handle: Option<String>,
+    display_name: Option<String>,
+    bio: Option<String>,
+    avatar_url: Option<String>,
+    /// Use an image uploaded through `POST /api/media` as avatar. Takes precedence over `avatar_url`.
+    avatar_media_id: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct ProfileValidationError {
+    error: String,
+}
+
+impl PatchProfilePayload {
+    const MAX_DISPLAY_NAME_LENGTH: usize = 50;
+    const MAX_BIO_LENGTH: usize = 160;
+    const MAX_AVATAR_URL_LENGTH: usize = 2048;
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(handle) = &self.handle {
+            validate_handle(handle)?;
+        }
+        if let Some(display_name) = &self.display_name
+            && display_name.chars().count() > Self::MAX_DISPLAY_NAME_LENGTH
+        {
+            return Err("Display name is too long".to_owned());
+        }
+        if let Some(bio) = &self.bio
+            && bio.chars().count() > Self::MAX_BIO_LENGTH
+        {
+            return Err("Bio is too long".to_owned());
+        }
+        if let Some(avatar_url) = &self.avatar_url
+            && !avatar_url.is_empty()
+        {
+            if avatar_url.len() > Self::MAX_AVATAR_URL_LENGTH {
+                return Err("Avatar URL is too long".to_owned());
+            }
+            if !(avatar_url.starts_with("https://") || avatar_url.starts_with("http://")) {
+                return Err("Avatar URL must be an http(s) URL".to_owned());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Handles are 3 to 30 ASCII letters, digits or underscores.
+/// The minimum length also keeps handles from colliding with the `/api/users/me` routes.
+fn validate_handle(handle: &str) -> Result<(), String> {
+    if !(3..=30).contains(&handle.len()) {
+        return Err("Handle must be between 3 and 30 characters long".to_owned());
+    }
+    if !handle
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err("Handle may only contain letters, digits and underscores".to_owned());
+    }
+    Ok(())
+}
+
+pub async fn update_me(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Json(payload): Json<PatchProfilePayload>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    if let Err(error) = payload.validate() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ProfileValidationError { error }),
+        )
+            .into_response();
+    }
+
+    let mut avatar_url = payload.avatar_url;
+    if let Some(media_id) = payload.avatar_media_id {
+        // The URL is copied into the profile, so the media has to be served already.
+        let error = match get_owned_media(&db, media_id, user_id).await {
+            Ok(media) => match media.status {
+                MediaStatus::Ready => {
+                    avatar_url = Some(media.url);
+                    None
+                }
+                MediaStatus::Pending | MediaStatus::Processing => {
+                    Some("The media is still being processed")
+                }
+                MediaStatus::Failed => Some("The media failed processing"),
+            },
+            Err(_) => Some("Unknown media id"),
+        };
+        if let Some(error) = error {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ProfileValidationError {
+                    error: error.to_owned(),
+                }),
+            )
+                .into_response();
+        }
+    }
+
+    let update = ProfileUpdate {
+        handle: payload.handle.as_deref(),
+        display_name: payload.display_name.as_deref(),
+        bio: payload.bio.as_deref(),
+        avatar_url: avatar_url.as_deref(),
+    };
+    match update_profile(&db, user_id, update).await {
+        Ok(user) => Json(user).into_response(),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => (
+            StatusCode::CONFLICT,
+            Json(ProfileValidationError {
+                error: "Handle is already taken".to_owned(),
+            }),
+        )
+            .into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeleteUserPayload {
+    password: String,
+    /// Skip the grace period and delete the account right away.
+    #[serde(default)]
+    immediate: bool,
+}
+
+pub async fn delete_me(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Json(payload): Json<DeleteUserPayload>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+
+    let Ok(user) = get_user(&db, user_id).await else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    if user.verify(&payload.password).is_err() {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let res = if payload.immediate {
+        delete_user(&db, user.id).await.map(|_| ())
+    } else {
+        schedule_user_deletion(&db, user.id).await.map(|_| ())
+    };
+    match res {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 #[derive(Deserialize)]
 pub struct PolkaData {
     pub user_id: Uuid,
+    /// End of the paid period, sent with upgrades and renewals.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
 }
 
 #[derive(Deserialize)]
 pub struct PolkaReq {
-    pub event: String,
+    /// Unique per event and part of the signed body, so that a replayed request cannot pass as a new event.
+    pub id: String,
+    pub event: PolkaEvent,
     pub data: PolkaData,
 }
 
+/// The result of processing a Polka webhook, as recorded in the webhook log.
+pub struct PolkaOutcome {
+    pub status: WebhookStatus,
+    pub event_id: Option<String>,
+    pub event: Option<&'static str>,
+    pub error: Option<String>,
+    /// The response to Polka, which retries anything but a 2xx.
+    pub response: StatusCode,
+}
+
+impl PolkaOutcome {
+    fn new(status: WebhookStatus, response: StatusCode) -> Self {
+        Self {
+            status,
+            event_id: None,
+            event: None,
+            error: None,
+            response,
+        }
+    }
+}
+
+/// Every request is logged with its headers and raw body before it is processed, and its outcome recorded after, so that failed events can be inspected and replayed by admins.
+/// The body is taken as raw bytes, since the signature is computed over them before parsing.
 pub async fn polka_webhook(
     Extension(db): Extension<PgPool>,
-    Extension(polka_api_key): Extension<PolkaAPIKey>,
+    Extension(polka_keys): Extension<PolkaWebhookKeys>,
     headers: HeaderMap,
-    Json(req): Json<PolkaReq>,
+    body: Bytes,
 ) -> impl IntoResponse {
-    if !polka_api_key.request_authorized(&headers) {
-        return StatusCode::UNAUTHORIZED.into_response();
+    // Rejected webhooks can't be replayed, so only the start of their headers and body is kept, for diagnosing bad signatures.
+    // Anyone can send them, so their log entries must stay small.
+    let verified = polka_keys.verify(&headers, &body).is_ok();
+    let mut logged_headers = format_webhook_headers(&headers);
+    let payload = if verified {
+        &body[..]
+    } else {
+        truncate_at_char_boundary(&mut logged_headers, MAX_REJECTED_POLKA_HEADERS);
+        &body[..body.len().min(MAX_REJECTED_POLKA_PAYLOAD)]
+    };
+
+    // Without a log entry, a failure could not be replayed, so Polka is asked to retry instead.
+    let webhook_id = match log_polka_webhook(&db, &logged_headers, payload).await {
+        Ok(webhook_id) => webhook_id,
+        Err(e) => {
+            eprintln!("Failed to log Polka webhook: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+
+    let outcome = if verified {
+        process_polka_webhook(&db, &body).await
+    } else {
+        PolkaOutcome::new(WebhookStatus::Rejected, StatusCode::UNAUTHORIZED)
+    };
+
+    if let Err(e) = record_polka_webhook_attempt(
+        &db,
+        webhook_id,
+        outcome.status,
+        outcome.event_id.as_deref(),
+        outcome.event,
+        outcome.error.as_deref(),
+    )
+    .await
+    {
+        eprintln!("Failed to record outcome of Polka webhook {webhook_id}: {e}");
+    }
+    outcome.response
+}
+
+/// Applies a verified webhook body. Also used to replay logged webhooks.
+pub async fn process_polka_webhook(db: &PgPool, body: &[u8]) -> PolkaOutcome {
+    let req = match Json::<PolkaReq>::from_bytes(body) {
+        Ok(Json(req)) => req,
+        Err(e) => {
+            return PolkaOutcome {
+                error: Some(e.body_text()),
+                ..PolkaOutcome::new(WebhookStatus::Malformed, StatusCode::BAD_REQUEST)
+            }
+        }
+    };
+
+    let (status, error, response) = if req.event == PolkaEvent::Unknown {
+        (WebhookStatus::Ignored, None, StatusCode::NO_CONTENT)
+    } else {
+        // Events that were already processed are acknowledged again, so that Polka stops retrying them.
+        // Unknown users and events that don't apply to the user's subscription are 404, which Polka retries.
+        match apply_polka_event(
+            db,
+            &req.id,
+            req.event,
+            req.data.user_id,
+            req.data.expires_at,
+        )
+        .await
+        {
+            Ok(true) => (WebhookStatus::Processed, None, StatusCode::NO_CONTENT),
+            Ok(false) => (WebhookStatus::Duplicate, None, StatusCode::NO_CONTENT),
+            Err(sqlx::Error::RowNotFound) => (
+                WebhookStatus::Failed,
+                Some(format!(
+                    "User {} doesn't exist or has no subscription this event applies to",
+                    req.data.user_id
+                )),
+                StatusCode::NOT_FOUND,
+            ),
+            Err(e) => (
+                WebhookStatus::Failed,
+                Some(e.to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        }
+    };
+    PolkaOutcome {
+        status,
+        event_id: Some(req.id),
+        event: Some(req.event.as_str()),
+        error,
+        response,
     }
+}
 
-    if req.event != "user.upgraded" {
-        return StatusCode::NO_CONTENT.into_response();
+fn truncate_at_char_boundary(text: &mut String, max_len: usize) {
+    if text.len() > max_len {
+        let end = (0..=max_len)
+            .rev()
+            .find(|&i| text.is_char_boundary(i))
+            .unwrap_or(0);
+        text.truncate(end);
     }
+}
 
-    match make_user_red(&db, req.data.user_id).await {
-        Ok(_) => StatusCode::NO_CONTENT,
-        Err(_) => StatusCode::NOT_FOUND,
+/// Credentials are redacted, in case a client sends them along.
+fn format_webhook_headers(headers: &HeaderMap) -> String {
+    let mut formatted = String::new();
+    for (name, value) in headers {
+        let value = if name == AUTHORIZATION || name == COOKIE {
+            "(redacted)".into()
+        } else {
+            String::from_utf8_lossy(value.as_bytes())
+        };
+        formatted.push_str(&format!("{name}: {value}\n"));
     }
-    .into_response()
+    formatted
 }
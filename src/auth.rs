@@ -1,14 +1,17 @@
 use std::random;
 
 use axum::http::HeaderMap;
-use color_eyre::Result;
+use color_eyre::{
+    eyre::{ensure, OptionExt},
+    Result,
+};
+use hmac::{Hmac, Mac};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
-use time::Duration;
+use sha2::Sha256;
+use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
-use crate::api::extract_api_key;
-
 #[derive(Clone)]
 pub struct JwtKey {
     encoding_key: EncodingKey,
@@ -24,14 +27,65 @@ pub struct JwtClaims {
     sub: String,
 }
 
-#[derive(Clone, PartialEq)]
-pub struct PolkaAPIKey {
-    pub key: String,
+/// Webhooks older or newer than this are rejected, which limits how long a captured request can be replayed.
+const POLKA_TIMESTAMP_TOLERANCE: Duration = Duration::minutes(5);
+
+/// Secrets shared with Polka for signing webhooks.
+///
+/// Several keys can be active at once to allow rotation: add the new key, switch Polka over, then remove the old key.
+#[derive(Clone)]
+pub struct PolkaWebhookKeys {
+    keys: Vec<String>,
 }
 
-impl PolkaAPIKey {
-    pub fn request_authorized(&self, headers: &HeaderMap) -> bool {
-        extract_api_key(headers).is_ok_and(|key| key == self.key)
+impl PolkaWebhookKeys {
+    /// Parses a comma-separated list of keys.
+    pub fn new(keys: &str) -> Option<Self> {
+        let keys: Vec<String> = keys
+            .split(',')
+            .map(|key| key.trim().to_owned())
+            .filter(|key| !key.is_empty())
+            .collect();
+        (!keys.is_empty()).then_some(Self { keys })
+    }
+
+    /// Checks the `X-Polka-Signature` header against the raw body and the `X-Polka-Timestamp` header.
+    ///
+    /// The signature header holds one or more comma-separated `v1=<hex>` entries, each the HMAC-SHA256 of `<timestamp>.<body>`,
+    /// so that Polka can sign with both the old and the new key during a rotation.
+    pub fn verify(&self, headers: &HeaderMap, body: &[u8]) -> Result<()> {
+        let timestamp = headers
+            .get("X-Polka-Timestamp")
+            .ok_or_eyre("Missing X-Polka-Timestamp header")?
+            .to_str()?;
+        let sent_at = OffsetDateTime::from_unix_timestamp(timestamp.parse()?)?;
+        ensure!(
+            (OffsetDateTime::now_utc() - sent_at).abs() <= POLKA_TIMESTAMP_TOLERANCE,
+            "Webhook timestamp is outside the tolerance window"
+        );
+
+        let signatures: Vec<Vec<u8>> = headers
+            .get("X-Polka-Signature")
+            .ok_or_eyre("Missing X-Polka-Signature header")?
+            .to_str()?
+            .split(',')
+            .filter_map(|entry| entry.trim().strip_prefix("v1="))
+            .filter_map(|signature| hex::decode(signature).ok())
+            .collect();
+
+        let verified = self.keys.iter().any(|key| {
+            signatures.iter().any(|signature| {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())
+                    .expect("HMAC accepts keys of any length");
+                mac.update(timestamp.as_bytes());
+                mac.update(b".");
+                mac.update(body);
+                // Compares in constant time.
+                mac.verify_slice(signature).is_ok()
+            })
+        });
+        ensure!(verified, "No valid webhook signature");
+        Ok(())
     }
 }
 
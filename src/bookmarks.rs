@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    api::{extract_user_id_from_bearer, hydrate_chirps, wants_author_embedded},
+    auth::JwtKey,
+    queries::{add_bookmark, get_bookmarked_chirps, get_chirp, remove_bookmark},
+};
+
+/// Bookmarks are private to the user. Only visible chirps can be bookmarked.
+pub async fn bookmark(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(chirp_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    match get_chirp(db.clone(), chirp_id).await {
+        Ok(_) => {}
+        Err(sqlx::Error::RowNotFound) => return StatusCode::NOT_FOUND,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+    }
+    match add_bookmark(&db, user_id, chirp_id).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn unbookmark(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(chirp_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    match remove_bookmark(&db, user_id, chirp_id).await {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// The user's bookmarked chirps, most recently bookmarked first.
+pub async fn list(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let Ok(chirps) = get_bookmarked_chirps(&db, user_id).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    match hydrate_chirps(&db, chirps, wants_author_embedded(&params)).await {
+        Ok(chirps) => Json(chirps).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
@@ -0,0 +1,408 @@
+use axum::{
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use color_eyre::eyre::{eyre, Report, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgPool};
+use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        authenticate_active_user, extract_user_id_from_bearer, hydrate_chirps, referenced_chirp,
+        validate_new_chirp, ChirpBody, NewChirp, PostChirpPayload,
+    },
+    auth::JwtKey,
+    entitlements::{Entitlements, RateLimiter},
+    queries::{
+        count_drafts, delete_draft, get_draft, get_drafts, get_user, insert_draft, lock_draft,
+        lock_due_drafts, publish_draft, unschedule_draft, update_draft, Draft, DraftContent, User,
+    },
+};
+
+/// How often the scheduler checks for scheduled chirps that are due.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Scheduled chirps are published in batches of up to this many.
+const PUBLISH_BATCH_SIZE: i64 = 100;
+
+const MAX_DRAFTS_PER_USER: i64 = 100;
+
+/// How far ahead chirps can be scheduled.
+const MAX_SCHEDULE_AHEAD: Duration = Duration::days(365);
+
+/// Publishes scheduled chirps once they are due.
+///
+/// Schedules live in the database, so chirps that became due while the server was down are published when it comes back up.
+/// Each chirp is inserted in the same transaction that deletes its draft, so it is published exactly once, even with several server instances.
+pub fn spawn_scheduler(db: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            loop {
+                match publish_due(&db).await {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("Failed to publish scheduled chirps: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Returns the number of scheduled chirps that were attempted.
+/// Drafts that are no longer valid are unscheduled. A database error instead stops the batch, leaving the rest of it scheduled to be retried.
+async fn publish_due(db: &PgPool) -> Result<usize> {
+    let mut tx = db.begin().await?;
+    let drafts = lock_due_drafts(&mut tx, PUBLISH_BATCH_SIZE).await?;
+    for draft in &drafts {
+        let published = match recheck_draft(db, draft).await {
+            Ok(in_reply_to) => {
+                // A savepoint, so that the chirps published so far can still be committed if this one fails.
+                let mut savepoint = tx.begin().await?;
+                match publish_draft(&mut savepoint, draft, in_reply_to).await {
+                    Ok(_) => savepoint.commit().await.map_err(Into::into),
+                    Err(e) => {
+                        savepoint.rollback().await?;
+                        Err(eyre!(
+                            "Failed to publish scheduled chirp {}: {e}",
+                            draft.draft_id
+                        ))
+                    }
+                }
+            }
+            Err(DraftCheckError::Invalid(error)) => {
+                unschedule_draft(&mut tx, draft.draft_id, &error).await?;
+                Ok(())
+            }
+            Err(DraftCheckError::Unavailable(e)) => Err(e),
+        };
+        if let Err(e) = published {
+            tx.commit().await?;
+            return Err(e);
+        }
+    }
+    tx.commit().await?;
+    Ok(drafts.len())
+}
+
+enum DraftCheckError {
+    /// The draft can't be published as it is. Meant for the draft's `last_error`.
+    Invalid(String),
+    /// The draft couldn't be checked, so it should be tried again later.
+    Unavailable(Report),
+}
+
+impl From<String> for DraftCheckError {
+    fn from(error: String) -> Self {
+        DraftCheckError::Invalid(error)
+    }
+}
+
+/// Checks a draft again right before it is published, since a lot can change after it was saved.
+/// The author may have been suspended or lost the entitlement to long chirps, and the users they reply to or quote may have blocked them, or been blocked.
+/// Returns the chirp to reply to, which is left out if it is no longer visible.
+async fn recheck_draft(db: &PgPool, draft: &Draft) -> Result<Option<Uuid>, DraftCheckError> {
+    let user = get_user(db, draft.user_id)
+        .await
+        .map_err(|e| DraftCheckError::Unavailable(eyre!("Failed to look up the author: {e}")))?;
+    if user.is_deleted() || user.is_suspended() {
+        return Err("The author's account is suspended or deleted"
+            .to_owned()
+            .into());
+    }
+    ChirpBody::new(
+        draft.body.clone(),
+        Entitlements::for_user(&user).max_chirp_length(),
+    )?;
+
+    let in_reply_to = match draft.in_reply_to {
+        Some(parent_id) => match referenced_chirp(db, user.id, parent_id).await {
+            Ok(parent) => Some(parent.chirp_id),
+            // Like replies to chirps that have been deleted in the meantime.
+            Err(StatusCode::NOT_FOUND) => None,
+            Err(StatusCode::FORBIDDEN) => {
+                return Err("The author of the chirp being replied to has blocked you, or you have blocked them".to_owned().into())
+            }
+            Err(_) => {
+                return Err(DraftCheckError::Unavailable(eyre!(
+                    "Failed to look up the chirp being replied to"
+                )))
+            }
+        },
+        None => None,
+    };
+    if let Some(quoted_id) = draft.quote_of {
+        match referenced_chirp(db, user.id, quoted_id).await {
+            Ok(_) => {}
+            Err(StatusCode::NOT_FOUND) => {
+                return Err("The quoted chirp is no longer available".to_owned().into())
+            }
+            Err(StatusCode::FORBIDDEN) => {
+                return Err(
+                    "The author of the quoted chirp has blocked you, or you have blocked them"
+                        .to_owned()
+                        .into(),
+                )
+            }
+            Err(_) => {
+                return Err(DraftCheckError::Unavailable(eyre!(
+                    "Failed to look up the quoted chirp"
+                )))
+            }
+        }
+    }
+    Ok(in_reply_to)
+}
+
+#[derive(Deserialize)]
+pub struct DraftPayload {
+    #[serde(flatten)]
+    chirp: PostChirpPayload,
+    /// When to publish the chirp. Left out for a draft that is only published on request.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    publish_at: Option<OffsetDateTime>,
+}
+
+#[derive(Serialize)]
+pub struct DraftValidationError {
+    error: String,
+}
+
+/// Drafts are validated like new chirps when they are saved, so that scheduled chirps are ready to go when they are due.
+/// Saving a scheduled chirp counts against the user's chirp rate limit, like posting it would.
+async fn validate_draft(
+    db: &PgPool,
+    rate_limiter: &RateLimiter,
+    user: &User,
+    payload: DraftPayload,
+) -> Result<(NewChirp, Option<OffsetDateTime>), Response> {
+    if let Some(publish_at) = payload.publish_at {
+        let now = OffsetDateTime::now_utc();
+        let error = if publish_at <= now {
+            Some("Chirps can only be scheduled for the future".to_owned())
+        } else if publish_at > now + MAX_SCHEDULE_AHEAD {
+            Some(format!(
+                "Chirps can be scheduled at most {} days ahead",
+                MAX_SCHEDULE_AHEAD.whole_days()
+            ))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(DraftValidationError { error }),
+            )
+                .into_response());
+        }
+    }
+    let new_chirp = validate_new_chirp(db, user, payload.chirp).await?;
+    if payload.publish_at.is_some() {
+        let entitlements = Entitlements::for_user(user);
+        rate_limiter
+            .check(user.id, entitlements.chirp_writes_per_minute())
+            .map_err(IntoResponse::into_response)?;
+    }
+    Ok((new_chirp, payload.publish_at))
+}
+
+fn draft_content(new_chirp: &NewChirp, publish_at: Option<OffsetDateTime>) -> DraftContent<'_> {
+    DraftContent {
+        body: &new_chirp.body,
+        media_ids: &new_chirp.media_ids,
+        in_reply_to: new_chirp.in_reply_to,
+        quote_of: new_chirp.quote_of,
+        publish_at,
+        expires_in: new_chirp.expires_in,
+    }
+}
+
+/// Saves a draft, or schedules a chirp if `publish_at` is given.
+pub async fn create(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    Extension(rate_limiter): Extension<RateLimiter>,
+    headers: HeaderMap,
+    Json(payload): Json<DraftPayload>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    match count_drafts(&db, user.id).await {
+        Ok(count) if count >= MAX_DRAFTS_PER_USER => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(DraftValidationError {
+                    error: format!(
+                        "At most {MAX_DRAFTS_PER_USER} drafts and scheduled chirps are allowed"
+                    ),
+                }),
+            )
+                .into_response()
+        }
+        Ok(_) => {}
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    let (new_chirp, publish_at) = match validate_draft(&db, &rate_limiter, &user, payload).await {
+        Ok(valid) => valid,
+        Err(response) => return response,
+    };
+    match insert_draft(&db, user.id, draft_content(&new_chirp, publish_at)).await {
+        Ok(draft) => (StatusCode::CREATED, Json(draft)).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    /// Only scheduled chirps if true, only unscheduled drafts if false.
+    scheduled: Option<bool>,
+}
+
+pub async fn list(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Query(params): Query<ListParams>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match get_drafts(&db, user_id, params.scheduled).await {
+        Ok(drafts) => Json(drafts).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+pub async fn get(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(draft_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match get_draft(&db, draft_id, user_id).await {
+        Ok(draft) => Json(draft).into_response(),
+        Err(sqlx::Error::RowNotFound) => StatusCode::NOT_FOUND.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Replaces a draft or scheduled chirp. Leaving out `publish_at` turns a scheduled chirp back into a draft.
+pub async fn update(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    Extension(rate_limiter): Extension<RateLimiter>,
+    headers: HeaderMap,
+    Path(draft_id): Path<Uuid>,
+    Json(payload): Json<DraftPayload>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    let (new_chirp, publish_at) = match validate_draft(&db, &rate_limiter, &user, payload).await {
+        Ok(valid) => valid,
+        Err(response) => return response,
+    };
+    match update_draft(
+        &db,
+        draft_id,
+        user.id,
+        draft_content(&new_chirp, publish_at),
+    )
+    .await
+    {
+        Ok(draft) => Json(draft).into_response(),
+        // Also when the scheduled chirp has just been published.
+        Err(sqlx::Error::RowNotFound) => StatusCode::NOT_FOUND.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Discards a draft, or cancels a scheduled chirp.
+pub async fn delete(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(draft_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    match delete_draft(&db, draft_id, user_id).await {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Publishes a draft or scheduled chirp right away.
+/// Drafts that can no longer be published, see `recheck_draft`, are unscheduled and rejected with the reason, which is also kept as the draft's `last_error`.
+pub async fn publish(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    Extension(rate_limiter): Extension<RateLimiter>,
+    headers: HeaderMap,
+    Path(draft_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    let entitlements = Entitlements::for_user(&user);
+    if let Err(limited) = rate_limiter.check(user.id, entitlements.chirp_writes_per_minute()) {
+        return limited.into_response();
+    }
+
+    let Ok(mut tx) = db.begin().await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let draft = match lock_draft(&mut tx, draft_id, user.id).await {
+        Ok(draft) => draft,
+        Err(sqlx::Error::RowNotFound) => return StatusCode::NOT_FOUND.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let in_reply_to = match recheck_draft(&db, &draft).await {
+        Ok(in_reply_to) => in_reply_to,
+        Err(DraftCheckError::Unavailable(e)) => {
+            eprintln!("Failed to check draft {}: {e}", draft.draft_id);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        Err(DraftCheckError::Invalid(error)) => {
+            if unschedule_draft(&mut tx, draft.draft_id, &error)
+                .await
+                .is_err()
+                || tx.commit().await.is_err()
+            {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(DraftValidationError { error }),
+            )
+                .into_response();
+        }
+    };
+    let Ok(chirp) = publish_draft(&mut tx, &draft, in_reply_to).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    if tx.commit().await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    match hydrate_chirps(&db, vec![chirp], false).await {
+        Ok(mut chirps) => (StatusCode::CREATED, Json(chirps.remove(0))).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
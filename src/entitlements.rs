@@ -0,0 +1,175 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use dashmap::DashMap;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::queries::User;
+
+/// Maximum length of a chirp in characters.
+pub const MAX_CHIRP_LENGTH: usize = 140;
+
+/// Maximum length of a chirp in characters for members with the `LongChirps` entitlement.
+pub const MAX_LONG_CHIRP_LENGTH: usize = 1000;
+
+/// Chirps that can be posted or edited per minute.
+const CHIRP_WRITES_PER_MINUTE: u32 = 5;
+
+/// Chirps that can be posted or edited per minute by members with the `HigherRateLimits` entitlement.
+const HIGHER_CHIRP_WRITES_PER_MINUTE: u32 = 30;
+
+/// Features that are only available to some users.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Entitlement {
+    LongChirps,
+    EditChirps,
+    HigherRateLimits,
+}
+
+impl Entitlement {
+    fn description(&self) -> &'static str {
+        match self {
+            Entitlement::LongChirps => "post chirps longer than 140 characters",
+            Entitlement::EditChirps => "edit chirps",
+            Entitlement::HigherRateLimits => "post more chirps per minute",
+        }
+    }
+}
+
+/// What a user is entitled to. Currently everything is included in Chirpy Red.
+pub struct Entitlements {
+    is_chirpy_red: bool,
+}
+
+impl Entitlements {
+    pub fn for_user(user: &User) -> Self {
+        Self {
+            is_chirpy_red: user.is_chirpy_red,
+        }
+    }
+
+    pub fn has(&self, entitlement: Entitlement) -> bool {
+        match entitlement {
+            Entitlement::LongChirps | Entitlement::EditChirps | Entitlement::HigherRateLimits => {
+                self.is_chirpy_red
+            }
+        }
+    }
+
+    pub fn require(&self, entitlement: Entitlement) -> Result<(), MissingEntitlement> {
+        if self.has(entitlement) {
+            Ok(())
+        } else {
+            Err(MissingEntitlement { entitlement })
+        }
+    }
+
+    pub fn max_chirp_length(&self) -> usize {
+        if self.has(Entitlement::LongChirps) {
+            MAX_LONG_CHIRP_LENGTH
+        } else {
+            MAX_CHIRP_LENGTH
+        }
+    }
+
+    pub fn chirp_writes_per_minute(&self) -> u32 {
+        if self.has(Entitlement::HigherRateLimits) {
+            HIGHER_CHIRP_WRITES_PER_MINUTE
+        } else {
+            CHIRP_WRITES_PER_MINUTE
+        }
+    }
+}
+
+/// Responds with 402 Payment Required, since every entitlement can be had by subscribing to Chirpy Red.
+#[derive(Debug)]
+pub struct MissingEntitlement {
+    entitlement: Entitlement,
+}
+
+impl IntoResponse for MissingEntitlement {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct MissingEntitlementError {
+            error: String,
+            entitlement: Entitlement,
+        }
+
+        (
+            StatusCode::PAYMENT_REQUIRED,
+            Json(MissingEntitlementError {
+                error: format!(
+                    "Chirpy Red is required to {}",
+                    self.entitlement.description()
+                ),
+                entitlement: self.entitlement,
+            }),
+        )
+            .into_response()
+    }
+}
+
+/// Counts actions per user in fixed one-minute windows. Limits are only kept in memory, so they reset on restart.
+#[derive(Clone)]
+pub struct RateLimiter {
+    windows: Arc<DashMap<Uuid, (Instant, u32)>>,
+}
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Windows are pruned once this many users are tracked.
+const MAX_TRACKED_USERS: usize = 10_000;
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            windows: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Counts an action by the user, unless they have already reached `limit` in the current window.
+    pub fn check(&self, user_id: Uuid, limit: u32) -> Result<(), RateLimited> {
+        let now = Instant::now();
+        if self.windows.len() >= MAX_TRACKED_USERS {
+            self.windows
+                .retain(|_, (start, _)| now.duration_since(*start) < RATE_LIMIT_WINDOW);
+        }
+
+        let mut window = self.windows.entry(user_id).or_insert((now, 0));
+        let (start, count) = &mut *window;
+        if now.duration_since(*start) >= RATE_LIMIT_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= limit {
+            let retry_after = RATE_LIMIT_WINDOW.saturating_sub(now.duration_since(*start));
+            return Err(RateLimited { retry_after });
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+pub struct RateLimited {
+    retry_after: Duration,
+}
+
+impl IntoResponse for RateLimited {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            // Round up, so that retrying after the given number of seconds always succeeds.
+            [(RETRY_AFTER, (self.retry_after.as_secs() + 1).to_string())],
+        )
+            .into_response()
+    }
+}
@@ -0,0 +1,453 @@
+use std::io::{self, Write};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Query,
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::IntoResponse,
+    Extension,
+};
+use flate2::{write::DeflateEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use uuid::Uuid;
+
+use crate::{
+    api::extract_user_id_from_bearer,
+    auth::JwtKey,
+    queries::{
+        get_user, get_user_roles, get_user_sessions, stream_chirps_for_export, Role, Session, User,
+    },
+};
+
+/// Chunks of the export buffered between the task writing it and the client.
+const EXPORT_BUFFER: usize = 16;
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// A single JSON document, with the chirps in `chirps`.
+    #[default]
+    Json,
+    /// A ZIP archive with `profile.json` and `chirps.json`.
+    Zip,
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+/// Everything in the export except the chirps, which are streamed separately.
+#[derive(Serialize)]
+struct ExportProfile {
+    exported_at: OffsetDateTime,
+    user: User,
+    roles: Vec<Role>,
+    sessions: Vec<Session>,
+}
+
+/// Streams the user's data as it is read from the database, so that users with many chirps are not held in memory.
+/// An error partway through aborts the response, so clients never receive an export that looks complete but isn't.
+pub async fn export_me(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Query(params): Query<ExportParams>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let Ok(user) = get_user(&db, user_id).await else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let (Ok(roles), Ok(sessions)) = (
+        get_user_roles(&db, user_id).await,
+        get_user_sessions(&db, user_id).await,
+    ) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let profile = ExportProfile {
+        exported_at: OffsetDateTime::now_utc(),
+        user,
+        roles,
+        sessions,
+    };
+
+    let (sender, receiver) = mpsc::channel(EXPORT_BUFFER);
+    let format = params.format;
+    tokio::spawn(async move {
+        let mut sink = ExportSink {
+            sender,
+            zip: (format == ExportFormat::Zip).then(|| ZipWriter::new(profile.exported_at)),
+        };
+        let error = match write_export(&db, user_id, &profile, &mut sink).await {
+            Ok(()) | Err(ExportError::Disconnected) => return,
+            Err(ExportError::Database(e)) => io::Error::other(e),
+            Err(ExportError::Archive(e)) => e,
+        };
+        eprintln!("Failed to export user {user_id}: {error}");
+        let _ = sink.sender.send(Err(error)).await;
+    });
+
+    let (content_type, disposition) = match format {
+        ExportFormat::Json => (
+            "application/json",
+            "attachment; filename=\"chirpy-export.json\"",
+        ),
+        ExportFormat::Zip => (
+            "application/zip",
+            "attachment; filename=\"chirpy-export.zip\"",
+        ),
+    };
+    (
+        [
+            (CONTENT_TYPE, content_type),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(ReceiverStream::new(receiver)),
+    )
+        .into_response()
+}
+
+enum ExportError {
+    Database(sqlx::Error),
+    /// The export can't be written as a ZIP archive, most likely because it is too large.
+    Archive(io::Error),
+    /// The client went away, so there is nobody to send the rest to.
+    Disconnected,
+}
+
+impl From<sqlx::Error> for ExportError {
+    fn from(e: sqlx::Error) -> Self {
+        ExportError::Database(e)
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(e: io::Error) -> Self {
+        ExportError::Archive(e)
+    }
+}
+
+/// The JSON export is the profile with the chirps added as `chirps`. The ZIP archive has them in separate files.
+async fn write_export(
+    db: &PgPool,
+    user_id: Uuid,
+    profile: &ExportProfile,
+    sink: &mut ExportSink,
+) -> Result<(), ExportError> {
+    let profile = serde_json::to_vec(profile).expect("Exports serialize to JSON");
+    let zip = sink.zip.is_some();
+    if zip {
+        sink.start_entry("profile.json").await?;
+        sink.write(&profile).await?;
+        sink.finish_entry().await?;
+        sink.start_entry("chirps.json").await?;
+        sink.write(b"[").await?;
+    } else {
+        // Reopens the profile object to add the chirps to it.
+        sink.write(&profile[..profile.len() - 1]).await?;
+        sink.write(br#","chirps":["#).await?;
+    }
+
+    let mut chirps = stream_chirps_for_export(db, user_id);
+    let mut first = true;
+    while let Some(chirp) = chirps.next().await {
+        let mut json = if first { Vec::new() } else { vec![b','] };
+        serde_json::to_writer(&mut json, &chirp?).expect("Chirps serialize to JSON");
+        sink.write(&json).await?;
+        first = false;
+    }
+
+    if zip {
+        sink.write(b"]").await?;
+        sink.finish_entry().await?;
+        sink.finish().await
+    } else {
+        sink.write(b"]}").await
+    }
+}
+
+/// Sends the export to the client, through a ZIP archive if it is exported as one.
+struct ExportSink {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    zip: Option<ZipWriter>,
+}
+
+impl ExportSink {
+    async fn send(&self, bytes: Vec<u8>) -> Result<(), ExportError> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.sender
+            .send(Ok(Bytes::from(bytes)))
+            .await
+            .map_err(|_| ExportError::Disconnected)
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), ExportError> {
+        let bytes = match &mut self.zip {
+            Some(zip) => zip.write(data)?,
+            None => data.to_vec(),
+        };
+        self.send(bytes).await
+    }
+
+    async fn start_entry(&mut self, name: &str) -> Result<(), ExportError> {
+        let bytes = self.zip.as_mut().map(|zip| zip.start_entry(name));
+        self.send(bytes.transpose()?.unwrap_or_default()).await
+    }
+
+    async fn finish_entry(&mut self) -> Result<(), ExportError> {
+        let bytes = self.zip.as_mut().map(ZipWriter::finish_entry);
+        self.send(bytes.transpose()?.unwrap_or_default()).await
+    }
+
+    async fn finish(&mut self) -> Result<(), ExportError> {
+        let bytes = self.zip.take().map(ZipWriter::finish);
+        self.send(bytes.transpose()?.unwrap_or_default()).await
+    }
+}
+
+/// Writes a ZIP archive front to back, without seeking, so that it can be streamed.
+/// Entries are deflated, and their sizes and checksums follow their data in data descriptors.
+/// ZIP64 is not supported, so writing fails once the archive or an entry reaches 4 GiB.
+struct ZipWriter {
+    /// In MS-DOS format, as time and date.
+    modified: (u16, u16),
+    /// Bytes written so far.
+    offset: u32,
+    entry: Option<ZipEntry>,
+    /// The finished entries, for the central directory.
+    entries: Vec<FinishedZipEntry>,
+}
+
+struct ZipEntry {
+    name: String,
+    offset: u32,
+    crc: crc32fast::Hasher,
+    size: u32,
+    compressed_size: u32,
+    encoder: DeflateEncoder<Vec<u8>>,
+}
+
+struct FinishedZipEntry {
+    name: String,
+    offset: u32,
+    crc: u32,
+    size: u32,
+    compressed_size: u32,
+}
+
+const ZIP_VERSION: u16 = 20;
+/// Sizes and checksum in a data descriptor, and UTF-8 names.
+const ZIP_FLAGS: u16 = (1 << 3) | (1 << 11);
+const ZIP_DEFLATE: u16 = 8;
+
+/// Adds `len` to a size or offset, which ZIP without ZIP64 stores in 32 bits.
+fn zip_add(total: u32, len: usize) -> io::Result<u32> {
+    u32::try_from(len)
+        .ok()
+        .and_then(|len| total.checked_add(len))
+        .ok_or_else(|| io::Error::other("The archive is too large for ZIP without ZIP64"))
+}
+
+impl ZipWriter {
+    fn new(modified: OffsetDateTime) -> Self {
+        let time = ((modified.hour() as u16) << 11)
+            | ((modified.minute() as u16) << 5)
+            | (modified.second() as u16 / 2);
+        let date = (((modified.year() - 1980).max(0) as u16) << 9)
+            | ((modified.month() as u16) << 5)
+            | modified.day() as u16;
+        Self {
+            modified: (time, date),
+            offset: 0,
+            entry: None,
+            entries: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+        self.offset = zip_add(self.offset, bytes.len())?;
+        Ok(bytes)
+    }
+
+    fn start_entry(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        assert!(self.entry.is_none(), "The previous entry must be finished");
+        let name_length =
+            u16::try_from(name.len()).map_err(|_| io::Error::other("The name is too long"))?;
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend(0x04034b50u32.to_le_bytes());
+        header.extend(ZIP_VERSION.to_le_bytes());
+        header.extend(ZIP_FLAGS.to_le_bytes());
+        header.extend(ZIP_DEFLATE.to_le_bytes());
+        header.extend(self.modified.0.to_le_bytes());
+        header.extend(self.modified.1.to_le_bytes());
+        // Checksum and sizes, which follow in the data descriptor.
+        header.extend([0; 12]);
+        header.extend(name_length.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(name.as_bytes());
+        self.entry = Some(ZipEntry {
+            name: name.to_owned(),
+            offset: self.offset,
+            crc: crc32fast::Hasher::new(),
+            size: 0,
+            compressed_size: 0,
+            encoder: DeflateEncoder::new(Vec::new(), Compression::default()),
+        });
+        self.emit(header)
+    }
+
+    /// Returns the compressed data that is ready, which may be none.
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let entry = self.entry.as_mut().expect("An entry must be started");
+        entry.size = zip_add(entry.size, data.len())?;
+        entry.crc.update(data);
+        entry.encoder.write_all(data)?;
+        let compressed = std::mem::take(entry.encoder.get_mut());
+        entry.compressed_size = zip_add(entry.compressed_size, compressed.len())?;
+        self.emit(compressed)
+    }
+
+    fn finish_entry(&mut self) -> io::Result<Vec<u8>> {
+        let entry = self.entry.take().expect("An entry must be started");
+        let mut bytes = entry.encoder.finish()?;
+        let entry = FinishedZipEntry {
+            name: entry.name,
+            offset: entry.offset,
+            crc: entry.crc.finalize(),
+            size: entry.size,
+            compressed_size: zip_add(entry.compressed_size, bytes.len())?,
+        };
+        bytes.extend(0x08074b50u32.to_le_bytes());
+        bytes.extend(entry.crc.to_le_bytes());
+        bytes.extend(entry.compressed_size.to_le_bytes());
+        bytes.extend(entry.size.to_le_bytes());
+        self.entries.push(entry);
+        self.emit(bytes)
+    }
+
+    /// Writes the central directory, which ends the archive.
+    fn finish(mut self) -> io::Result<Vec<u8>> {
+        assert!(self.entry.is_none(), "The last entry must be finished");
+        let count = u16::try_from(self.entries.len())
+            .map_err(|_| io::Error::other("Too many entries for ZIP without ZIP64"))?;
+        let directory_offset = self.offset;
+        let mut bytes = Vec::new();
+        for entry in &self.entries {
+            bytes.extend(0x02014b50u32.to_le_bytes());
+            bytes.extend(ZIP_VERSION.to_le_bytes());
+            bytes.extend(ZIP_VERSION.to_le_bytes());
+            bytes.extend(ZIP_FLAGS.to_le_bytes());
+            bytes.extend(ZIP_DEFLATE.to_le_bytes());
+            bytes.extend(self.modified.0.to_le_bytes());
+            bytes.extend(self.modified.1.to_le_bytes());
+            bytes.extend(entry.crc.to_le_bytes());
+            bytes.extend(entry.compressed_size.to_le_bytes());
+            bytes.extend(entry.size.to_le_bytes());
+            // Checked when the entry was started.
+            bytes.extend((entry.name.len() as u16).to_le_bytes());
+            // Extra field and comment lengths, disk number, and internal and external attributes.
+            bytes.extend([0; 12]);
+            bytes.extend(entry.offset.to_le_bytes());
+            bytes.extend(entry.name.as_bytes());
+        }
+        let directory_size = zip_add(0, bytes.len())?;
+        // The end of central directory record has to fit in the archive as well.
+        zip_add(zip_add(self.offset, bytes.len())?, 22)?;
+        bytes.extend(0x06054b50u32.to_le_bytes());
+        // This disk and the disk with the central directory.
+        bytes.extend([0; 4]);
+        bytes.extend(count.to_le_bytes());
+        bytes.extend(count.to_le_bytes());
+        bytes.extend(directory_size.to_le_bytes());
+        bytes.extend(directory_offset.to_le_bytes());
+        // Comment length.
+        bytes.extend([0; 2]);
+        self.emit(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn write_archive(entries: &[(&str, &[&[u8]])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(datetime!(2025-01-31 12:34:56 UTC));
+        let mut archive = Vec::new();
+        for (name, chunks) in entries {
+            archive.extend(zip.start_entry(name).unwrap());
+            for chunk in *chunks {
+                archive.extend(zip.write(chunk).unwrap());
+            }
+            archive.extend(zip.finish_entry().unwrap());
+        }
+        archive.extend(zip.finish().unwrap());
+        archive
+    }
+
+    #[test]
+    fn archives_can_be_read_back() {
+        let chirps = "{\"body\":\"Hello, wörld\"},".repeat(10_000);
+        let archive = write_archive(&[
+            ("profile.json", &[b"{\"user\":{}}"]),
+            ("chirps.json", &[b"[", chirps.as_bytes(), b"]"]),
+            ("empty.json", &[]),
+        ]);
+
+        let mut reader = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(reader.len(), 3);
+        let expected: [(&str, Vec<u8>); 3] = [
+            ("profile.json", b"{\"user\":{}}".to_vec()),
+            ("chirps.json", format!("[{chirps}]").into_bytes()),
+            ("empty.json", Vec::new()),
+        ];
+        for (i, (name, contents)) in expected.iter().enumerate() {
+            let mut file = reader.by_index(i).unwrap();
+            assert_eq!(file.name(), *name);
+            assert_eq!(file.size(), contents.len() as u64);
+            assert_eq!(file.crc32(), crc32fast::hash(contents));
+            let modified = file.last_modified();
+            assert_eq!(
+                (modified.year(), modified.month(), modified.day()),
+                (2025, 1, 31)
+            );
+            assert_eq!(
+                (modified.hour(), modified.minute(), modified.second()),
+                (12, 34, 56)
+            );
+            let mut read = Vec::new();
+            // Also checks the CRC once the end is reached.
+            file.read_to_end(&mut read).unwrap();
+            assert_eq!(&read, contents);
+        }
+        let chirps_entry = reader.by_name("chirps.json").unwrap();
+        assert!(chirps_entry.compressed_size() < chirps_entry.size());
+    }
+
+    #[test]
+    fn sizes_past_four_gib_fail_instead_of_wrapping() {
+        assert_eq!(zip_add(u32::MAX - 1, 1).unwrap(), u32::MAX);
+        assert!(zip_add(u32::MAX, 1).is_err());
+        assert!(zip_add(0, u32::MAX as usize + 1).is_err());
+
+        let mut zip = ZipWriter::new(datetime!(2025-01-31 12:34:56 UTC));
+        zip.offset = u32::MAX - 10;
+        assert!(zip.start_entry("chirps.json").is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use sqlx::PgPool;
+use time::Duration;
+
+use crate::{
+    analytics::StaticAnalytics,
+    queries::{
+        delete_expired_chirps, expire_subscriptions, purge_deleted_users,
+        purge_dispatched_outbox_events,
+    },
+};
+
+/// How long a deleted account can still be restored by logging in before it is purged.
+pub const ACCOUNT_DELETION_GRACE_PERIOD: Duration = Duration::days(30);
+
+/// How long dispatched outbox events are kept, for debugging.
+const OUTBOX_RETENTION: Duration = Duration::days(7);
+
+/// Expired chirps are deleted in batches of up to this many.
+const EXPIRED_CHIRPS_BATCH_SIZE: i64 = 500;
+
+/// Hard-deletes accounts whose deletion grace period has passed, once an hour.
+pub fn spawn_account_purge(db: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge_deleted_users(&db, ACCOUNT_DELETION_GRACE_PERIOD).await {
+                eprintln!("Failed to purge deleted accounts: {e}");
+            }
+        }
+    });
+}
+
+/// Writes static file server analytics to the database once a minute.
+pub fn spawn_analytics_flush(db: PgPool, analytics: Arc<StaticAnalytics>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = analytics.flush(&db).await {
+                eprintln!("Failed to flush static analytics: {e}");
+            }
+        }
+    });
+}
+
+/// Takes away Chirpy Red from members whose subscription has lapsed, every ten minutes.
+pub fn spawn_subscription_expiry(db: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(10 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = expire_subscriptions(&db).await {
+                eprintln!("Failed to expire subscriptions: {e}");
+            }
+        }
+    });
+}
+
+/// Deletes dispatched outbox events once they are older than `OUTBOX_RETENTION`, once an hour.
+pub fn spawn_outbox_purge(db: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge_dispatched_outbox_events(&db, OUTBOX_RETENTION).await {
+                eprintln!("Failed to purge outbox events: {e}");
+            }
+        }
+    });
+}
+
+/// Deletes expired chirps, once a minute. They are already left out of every read until then.
+pub fn spawn_chirp_reaper(db: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            loop {
+                match delete_expired_chirps(&db, EXPIRED_CHIRPS_BATCH_SIZE).await {
+                    Ok(deleted) if deleted < EXPIRED_CHIRPS_BATCH_SIZE as u64 => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("Failed to delete expired chirps: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
@@ -1,41 +1,264 @@
+use std::{cmp::Ordering, path::Path};
+
 use tokio::fs;
 
 use axum::{
-    extract::Path,
+    extract::{Query, Request, State},
     http::StatusCode,
-    response::{Html, IntoResponse},
+    response::{Html, IntoResponse, Response},
+    Json,
 };
-
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use time::{macros::format_description, OffsetDateTime};
 use tokio_stream::{wrappers::ReadDirStream, StreamExt};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+
+use crate::{
+    state::AppState,
+    static_files::{not_found_page, sanitize_path},
+};
+
+/// Characters that don't need to be escaped in a relative link to a file name.
+const HREF_SAFE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ListingFormat {
+    #[default]
+    Html,
+    Json,
+}
 
-pub async fn static_fallback() -> impl IntoResponse {
-    (StatusCode::OK, "No such file".to_string())
+#[derive(Deserialize)]
+pub struct ListingParams {
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    order: SortOrder,
+    #[serde(default)]
+    format: ListingFormat,
 }
 
-pub async fn servedir_fallback(Path(path): Path<String>) -> impl IntoResponse {
-    let path = format!("app/{path}");
-    let metadata = fs::metadata(&path).await;
-    let Ok(metadata) = metadata else {
-        return static_fallback().await.into_response();
+#[derive(Serialize)]
+struct DirListing {
+    /// The path of the directory relative to the static root, with a leading and trailing slash.
+    path: String,
+    entries: Vec<DirEntry>,
+}
+
+#[derive(Serialize)]
+struct DirEntry {
+    name: String,
+    is_dir: bool,
+    /// `None` for directories.
+    size: Option<u64>,
+    modified: Option<OffsetDateTime>,
+}
+
+/// Called by the `/app` file server for paths it cannot serve, with the path relative to the static root.
+/// Directories without an `index.html` get a listing unless listings are disabled, and client-side routes get the app in SPA mode.
+pub async fn servedir_fallback(State(app_state): State<AppState>, request: Request) -> Response {
+    let config = &app_state.config.static_files;
+    let Some(relative_path) = sanitize_path(request.uri().path()) else {
+        return not_found_page(config).await;
+    };
+    let path = config.root.join(&relative_path);
+
+    match fs::metadata(&path).await {
+        Ok(metadata) if metadata.is_dir() && config.directory_listings => {
+            // Malformed parameters only matter for listings, so they are parsed here rather than by an extractor.
+            let Ok(Query(params)) = Query::<ListingParams>::try_from_uri(request.uri()) else {
+                return StatusCode::BAD_REQUEST.into_response();
+            };
+            directory_listing(&path, &relative_path, params).await
+        }
+        Err(_) if config.is_client_route(&relative_path) => {
+            let index = ServeFile::new(config.root.join("index.html"))
+                .precompressed_br()
+                .precompressed_gzip();
+            match index.oneshot(request).await {
+                Ok(response) => response.into_response(),
+                Err(infallible) => match infallible {},
+            }
+        }
+        _ => not_found_page(config).await,
+    }
+}
+
+async fn directory_listing(path: &Path, relative_path: &str, params: ListingParams) -> Response {
+    let Ok(mut entries) = list_dir(path).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    sort_entries(&mut entries, params.sort, params.order);
+
+    let listing = DirListing {
+        path: format!("/{relative_path}"),
+        entries,
     };
+    match params.format {
+        ListingFormat::Json => (StatusCode::OK, Json(listing)).into_response(),
+        ListingFormat::Html => (
+            StatusCode::OK,
+            Html::from(render_listing(&listing, &params)),
+        )
+            .into_response(),
+    }
+}
+
+/// Hidden entries are skipped, since the file server refuses to serve them anyway.
+async fn list_dir(path: &Path) -> std::io::Result<Vec<DirEntry>> {
+    let mut dir_entries = ReadDirStream::new(fs::read_dir(path).await?);
+    let mut entries = Vec::new();
+    while let Some(entry) = dir_entries.next().await {
+        let Ok(name) = entry?.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        // Follow symlinks, like the file server does.
+        let Ok(metadata) = fs::metadata(path.join(&name)).await else {
+            continue;
+        };
+        entries.push(DirEntry {
+            is_dir: metadata.is_dir(),
+            size: (!metadata.is_dir()).then_some(metadata.len()),
+            modified: metadata.modified().ok().map(OffsetDateTime::from),
+            name,
+        });
+    }
+    Ok(entries)
+}
+
+/// Directories always come first. Ties are broken by name, so the order is stable.
+fn sort_entries(entries: &mut [DirEntry], sort: SortKey, order: SortOrder) {
+    entries.sort_by(|a, b| {
+        let ordering = match sort {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let ordering = match order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    });
+}
+
+fn render_listing(listing: &DirListing, params: &ListingParams) -> String {
+    let title = escape_html(&listing.path);
+    let mut rows = String::new();
+    if listing.path != "/" {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in &listing.entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}{suffix}\">{}{suffix}</a></td><td>{}</td><td>{}</td></tr>\n",
+            utf8_percent_encode(&entry.name, HREF_SAFE),
+            escape_html(&entry.name),
+            entry
+                .size
+                .map(format_size)
+                .unwrap_or_else(|| "-".to_owned()),
+            entry.modified.map(format_modified).unwrap_or_default(),
+        ));
+    }
+
+    let header = |label: &str, key: SortKey, query: &str| {
+        // Clicking the current sort column again reverses the order.
+        let order = if params.sort == key && params.order == SortOrder::Asc {
+            "desc"
+        } else {
+            "asc"
+        };
+        format!("<th><a href=\"?sort={query}&amp;order={order}\">{label}</a></th>")
+    };
+
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Index of {title}</title>
+</head>
+<body>
+<h1>Index of {title}</h1>
+<table>
+<thead><tr>{}{}{}</tr></thead>
+<tbody>
+{rows}</tbody>
+</table>
+</body>
+</html>
+",
+        header("Name", SortKey::Name, "name"),
+        header("Size", SortKey::Size, "size"),
+        header("Last modified", SortKey::Modified, "modified"),
+    )
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next_unit in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+    format!("{size:.1} {unit}")
+}
 
-    if metadata.is_dir()
-        && let Ok(listing) = list_dir(&path).await
-    {
-        (StatusCode::OK, Html::from(listing)).into_response()
-    } else {
-        static_fallback().await.into_response()
-    }
-}
-
-async fn list_dir(path: &str) -> std::io::Result<String> {
-    let dir_entries = ReadDirStream::new(fs::read_dir(path).await?);
-    let file_links: Vec<String> = dir_entries
-        .filter_map(|rf| rf.ok().map(|f| f.file_name()))
-        .filter_map(|f| f.into_string().ok())
-        .map(|f| format!("<a href=\"{f}\">{f}</a>",))
-        .collect()
-        .await;
-    let file_links = file_links.join("\n");
-    Ok(format!("<pre>\n{file_links}\n</pre>"))
+fn format_modified(modified: OffsetDateTime) -> String {
+    modified
+        .format(format_description!(
+            "[year]-[month]-[day] [hour]:[minute] UTC"
+        ))
+        .unwrap_or_default()
 }
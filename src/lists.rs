@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        authenticate_active_user, extract_user_id_from_bearer, hydrate_chirps,
+        optional_user_id_from_bearer, sort_order_param, wants_author_embedded,
+    },
+    auth::JwtKey,
+    queries::{
+        add_list_member, count_list_members, count_lists, delete_list,
+        get_all_chirps_by_list_sorted_by_creation, get_list, get_list_members, get_lists,
+        get_public_profile, insert_list, remove_list_member, update_list, AuthorSummary, List,
+        ListUpdate, ListVisibility,
+    },
+};
+
+const MAX_LISTS_PER_USER: i64 = 50;
+const MAX_LIST_MEMBERS: i64 = 500;
+const MAX_LIST_NAME_LENGTH: usize = 50;
+const MAX_LIST_DESCRIPTION_LENGTH: usize = 200;
+
+#[derive(Serialize)]
+pub struct ListValidationError {
+    error: String,
+}
+
+fn validation_error(error: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(ListValidationError { error })).into_response()
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("List name must not be empty".to_owned());
+    }
+    if name.chars().count() > MAX_LIST_NAME_LENGTH {
+        return Err(format!(
+            "List name must be at most {MAX_LIST_NAME_LENGTH} characters long"
+        ));
+    }
+    Ok(())
+}
+
+fn validate_description(description: &str) -> Result<(), String> {
+    if description.chars().count() > MAX_LIST_DESCRIPTION_LENGTH {
+        return Err(format!(
+            "List description must be at most {MAX_LIST_DESCRIPTION_LENGTH} characters long"
+        ));
+    }
+    Ok(())
+}
+
+/// Looks up a list that the user is about to change. Lists the user can't see are not found, and lists they can see but don't own are forbidden.
+async fn owned_list(db: &PgPool, list_id: Uuid, user_id: Uuid) -> Result<List, StatusCode> {
+    match get_list(db, list_id, Some(user_id)).await {
+        Ok(list) if list.owner_id == user_id => Ok(list),
+        Ok(_) => Err(StatusCode::FORBIDDEN),
+        Err(sqlx::Error::RowNotFound) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateListPayload {
+    name: String,
+    description: Option<String>,
+    /// Lists are private unless created as public.
+    #[serde(default = "default_visibility")]
+    visibility: ListVisibility,
+}
+
+fn default_visibility() -> ListVisibility {
+    ListVisibility::Private
+}
+
+pub async fn create_list(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateListPayload>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    if let Err(error) = validate_name(&payload.name) {
+        return validation_error(error);
+    }
+    if let Some(description) = &payload.description
+        && let Err(error) = validate_description(description)
+    {
+        return validation_error(error);
+    }
+    match count_lists(&db, user.id).await {
+        Ok(count) if count >= MAX_LISTS_PER_USER => {
+            return validation_error(format!("At most {MAX_LISTS_PER_USER} lists are allowed"))
+        }
+        Ok(_) => {}
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    let description = payload.description.as_deref().filter(|d| !d.is_empty());
+    match insert_list(&db, user.id, &payload.name, description, payload.visibility).await {
+        Ok(list) => (StatusCode::CREATED, Json(list)).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// The authenticated user's lists, private ones included.
+pub async fn my_lists(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match get_lists(&db, user_id, false).await {
+        Ok(lists) => Json(lists).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// The lists of the user with the given handle. Only the owner sees their private lists.
+pub async fn user_lists(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(handle): Path<String>,
+) -> impl IntoResponse {
+    let viewer_id = match optional_user_id_from_bearer(&headers, &key) {
+        Ok(viewer_id) => viewer_id,
+        Err(status) => return status.into_response(),
+    };
+    let Ok(profile) = get_public_profile(&db, &handle).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match get_lists(&db, profile.id, viewer_id != Some(profile.id)).await {
+        Ok(lists) => Json(lists).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    #[serde(flatten)]
+    list: List,
+    members: Vec<AuthorSummary>,
+}
+
+/// A list with its members. Private lists are only found by their owner.
+pub async fn get_list_details(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(list_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let viewer_id = match optional_user_id_from_bearer(&headers, &key) {
+        Ok(viewer_id) => viewer_id,
+        Err(status) => return status.into_response(),
+    };
+    let list = match get_list(&db, list_id, viewer_id).await {
+        Ok(list) => list,
+        Err(sqlx::Error::RowNotFound) => return StatusCode::NOT_FOUND.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    match get_list_members(&db, list_id).await {
+        Ok(members) => Json(ListResponse { list, members }).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Fields that are left out are not changed. An empty description clears it.
+#[derive(Deserialize)]
+pub struct UpdateListPayload {
+    name: Option<String>,
+    description: Option<String>,
+    visibility: Option<ListVisibility>,
+}
+
+pub async fn update(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(list_id): Path<Uuid>,
+    Json(payload): Json<UpdateListPayload>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    if let Some(name) = &payload.name
+        && let Err(error) = validate_name(name)
+    {
+        return validation_error(error);
+    }
+    if let Some(description) = &payload.description
+        && let Err(error) = validate_description(description)
+    {
+        return validation_error(error);
+    }
+    if let Err(status) = owned_list(&db, list_id, user_id).await {
+        return status.into_response();
+    }
+    let update = ListUpdate {
+        name: payload.name.as_deref(),
+        description: payload.description.as_deref(),
+        visibility: payload.visibility,
+    };
+    match update_list(&db, list_id, user_id, update).await {
+        Ok(list) => Json(list).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+pub async fn delete(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(list_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    if let Err(status) = owned_list(&db, list_id, user_id).await {
+        return status;
+    }
+    match delete_list(&db, list_id, user_id).await {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn add_member(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path((list_id, handle)): Path<(Uuid, String)>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    if let Err(status) = owned_list(&db, list_id, user_id).await {
+        return status.into_response();
+    }
+    let Ok(profile) = get_public_profile(&db, &handle).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match count_list_members(&db, list_id).await {
+        Ok(count) if count >= MAX_LIST_MEMBERS => {
+            return validation_error(format!(
+                "A list can have at most {MAX_LIST_MEMBERS} members"
+            ))
+        }
+        Ok(_) => {}
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    match add_list_member(&db, list_id, profile.id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+pub async fn remove_member(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path((list_id, handle)): Path<(Uuid, String)>,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    if let Err(status) = owned_list(&db, list_id, user_id).await {
+        return status;
+    }
+    let Ok(profile) = get_public_profile(&db, &handle).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    match remove_list_member(&db, list_id, profile.id).await {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Chirps by the members of the list. Takes the same `sort` and `embed` parameters as `GET /api/chirps`, and likewise leaves out authors the caller has muted or blocked.
+pub async fn chirps(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(list_id): Path<Uuid>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let viewer_id = match optional_user_id_from_bearer(&headers, &key) {
+        Ok(viewer_id) => viewer_id,
+        Err(status) => return status.into_response(),
+    };
+    let Some(sort_order) = sort_order_param(&params) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    match get_list(&db, list_id, viewer_id).await {
+        Ok(_) => {}
+        Err(sqlx::Error::RowNotFound) => return StatusCode::NOT_FOUND.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    let Ok(chirps) =
+        get_all_chirps_by_list_sorted_by_creation(&db, list_id, sort_order, viewer_id).await
+    else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    match hydrate_chirps(&db, chirps, wants_author_embedded(&params)).await {
+        Ok(chirps) => Json(chirps).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
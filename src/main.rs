@@ -2,35 +2,70 @@
 #![feature(random)]
 
 use api::{
-    delete_chirp, get_all_chirps, get_chirp, login, polka_webhook, post_chirp, refresh, revoke,
-    update_user,
+    block, delete_chirp, delete_me, edit_chirp, follow, get_all_chirps, get_chirp, get_me,
+    get_profile, list_blocks, list_mutes, login, mute, polka_webhook, post_chirp, refresh,
+    report_chirp, repost, revoke, unblock, unfollow, unmute, unrepost, update_me, update_user,
+    MAX_POLKA_WEBHOOK_SIZE,
 };
-use auth::PolkaAPIKey;
+use auth::PolkaWebhookKeys;
+use std::{net::SocketAddr, sync::Arc};
+
 use axum::{
-    handler::HandlerWithoutStateExt,
+    extract::DefaultBodyLimit,
+    handler::Handler,
+    http::{header::CACHE_CONTROL, HeaderValue, StatusCode},
     middleware::{self},
-    routing::{delete, get, post, put},
+    response::Response,
+    routing::{delete, get, patch, post, put},
     Extension, Router,
 };
 use sqlx::postgres::PgPoolOptions;
 use tower::ServiceBuilder;
-use tower_http::services::ServeDir;
+use tower_http::{services::ServeDir, set_header::SetResponseHeaderLayer};
 
 mod admin;
+mod analytics;
 mod api;
 mod auth;
+mod bookmarks;
+mod drafts;
+mod entitlements;
+mod export;
+mod jobs;
 mod list_dir;
+mod lists;
+mod media;
+mod media_processing;
+mod messages;
 mod middlewarez;
+mod moderation;
+mod notifications;
+mod outbox;
 mod queries;
 mod state;
+mod static_files;
+mod stream;
+mod webhooks;
 
 use self::{
-    admin::{metrics, reset},
-    api::create_user,
+    admin::{
+        bootstrap_admin, get_webhook, grant, list_roles, list_webhooks, metrics, replay_webhook,
+        reset, revoke as revoke_role,
+    },
+    api::{api_not_found, create_user},
     auth::JwtKey,
-    list_dir::{servedir_fallback, static_fallback},
-    middlewarez::fileserver_hits_middleware,
+    entitlements::RateLimiter,
+    list_dir::servedir_fallback,
+    media::{upload_media, LocalStorage, MAX_UPLOAD_SIZE},
+    media_processing::MediaPipeline,
+    middlewarez::{require_role, static_analytics_middleware},
+    notifications::NotificationSubscriber,
+    outbox::OutboxDispatcher,
+    queries::Role,
     state::{AppState, Platform},
+    static_files::{static_file_headers, StaticFilesConfig},
+    stream::ChirpStream,
+    webhooks::WebhookDispatcher,
 };
 
 #[tokio::main]
@@ -43,6 +78,22 @@ async fn main() {
         .await
         .expect("Database must be available");
 
+    // `bootstrap-admin <email>` creates the first admin account, reading its password from stdin.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let [command, email] = args.as_slice()
+        && command == "bootstrap-admin"
+    {
+        let mut password = String::new();
+        std::io::stdin()
+            .read_line(&mut password)
+            .expect("A password must be provided on stdin");
+        let user = bootstrap_admin(&db, email, password.trim_end())
+            .await
+            .expect("Failed to bootstrap admin");
+        println!("User {} ({}) is now an admin", user.email, user.id);
+        return;
+    }
+
     let platform: Platform = Platform::from(
         dotenvy::var("PLATFORM")
             .unwrap_or("prod".to_string())
@@ -52,56 +103,281 @@ async fn main() {
     let jwt_secret = dotenvy::var("JWT_SECRET").expect("A key must be provided for creating and validating jwt tokens for authentication of users.");
     let jwt_key = JwtKey::from(jwt_secret);
 
-    let raw_polka_api_key = dotenvy::var("POLKA_KEY").expect("A Polka API key must be provided");
-    let polka_key = PolkaAPIKey {
-        key: raw_polka_api_key,
-    };
+    let raw_polka_keys = dotenvy::var("POLKA_KEY").expect("A Polka API key must be provided");
+    let polka_keys = PolkaWebhookKeys::new(&raw_polka_keys)
+        .expect("POLKA_KEY must be a comma-separated list of keys");
+
+    jobs::spawn_account_purge(db.clone());
+    jobs::spawn_subscription_expiry(db.clone());
+
+    let media_root = dotenvy::var("MEDIA_ROOT").unwrap_or("app/media".to_string());
+    let media_incoming_root =
+        dotenvy::var("MEDIA_INCOMING_ROOT").unwrap_or("media_incoming".to_string());
+    let media_pipeline = MediaPipeline::new(
+        db.clone(),
+        Arc::new(LocalStorage::new(media_incoming_root, "")),
+        Arc::new(LocalStorage::new(&media_root, "/app/media")),
+    );
+    media_pipeline.spawn_worker();
+
+    let webhook_dispatcher = WebhookDispatcher::new(db.clone(), platform);
+    webhook_dispatcher.spawn_worker();
+    OutboxDispatcher::new(db.clone())
+        .subscribe(Arc::new(webhook_dispatcher))
+        .subscribe(Arc::new(NotificationSubscriber::new(db.clone())))
+        .spawn();
+    jobs::spawn_outbox_purge(db.clone());
+    jobs::spawn_chirp_reaper(db.clone());
+    drafts::spawn_scheduler(db.clone());
+
+    let chirp_stream = ChirpStream::new();
+    chirp_stream.spawn_listener(db.clone());
 
     let mut app_state = AppState::new();
     app_state.config.platform = platform;
+    app_state
+        .analytics
+        .restore_total_visits(&db)
+        .await
+        .expect("Failed to restore static analytics");
+    jobs::spawn_analytics_flush(db.clone(), app_state.analytics.clone());
+    let analytics = app_state.analytics.clone();
+    let analytics_db = db.clone();
+    if let Ok(static_root) = dotenvy::var("STATIC_ROOT") {
+        app_state.config.static_files.root = static_root.into();
+    }
+    if let Ok(policies) = dotenvy::var("STATIC_CACHE_CONTROL") {
+        app_state.config.static_files.cache_policies =
+            StaticFilesConfig::parse_cache_policies(&policies)
+                .expect("STATIC_CACHE_CONTROL must be of the form `pattern=cache-control;...`");
+    }
+    if let Ok(listings) = dotenvy::var("STATIC_DIRECTORY_LISTINGS") {
+        app_state.config.static_files.directory_listings = listings
+            .parse()
+            .expect("STATIC_DIRECTORY_LISTINGS must be `true` or `false`");
+    }
+    if let Ok(not_found_page) = dotenvy::var("STATIC_NOT_FOUND_PAGE") {
+        app_state.config.static_files.not_found_page = not_found_page.into();
+    }
+    if let Ok(spa) = dotenvy::var("STATIC_SPA") {
+        app_state.config.static_files.spa =
+            spa.parse().expect("STATIC_SPA must be `true` or `false`");
+    }
 
-    let file_server = ServeDir::new("").fallback(servedir_fallback.into_service());
+    let file_server = ServiceBuilder::new()
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            static_file_headers,
+        ))
+        .service(
+            ServeDir::new(&app_state.config.static_files.root)
+                .precompressed_br()
+                .precompressed_gzip()
+                .fallback(servedir_fallback.with_state(app_state.clone())),
+        );
+
+    // Processed media never changes, since each variant gets its own file name.
+    let media_server = ServiceBuilder::new()
+        .layer(SetResponseHeaderLayer::if_not_present(
+            CACHE_CONTROL,
+            |res: &Response<_>| {
+                res.status()
+                    .is_success()
+                    .then_some(HeaderValue::from_static(
+                        "public, max-age=31536000, immutable",
+                    ))
+            },
+        ))
+        .service(ServeDir::new(&media_root));
 
     let app_router = Router::new()
-        .route_service("/app/*path", file_server.clone())
-        .route_service("/app", file_server.clone())
-        .route_service("/app/", file_server.clone())
+        .nest_service("/app/media", media_server)
+        .nest_service("/app", file_server)
         .layer(ServiceBuilder::new().layer(middleware::from_fn_with_state(
             app_state.clone(),
-            fileserver_hits_middleware,
+            static_analytics_middleware,
         )));
 
     let admin_router = Router::new()
         .route("/metrics", get(metrics))
-        .route("/reset", post(reset));
+        .route("/reset", post(reset))
+        .route("/users/:user_id/roles", get(list_roles))
+        .route("/users/:user_id/roles", post(grant))
+        .route("/users/:user_id/roles/:role", delete(revoke_role))
+        .route("/webhooks", get(list_webhooks))
+        .route("/webhooks/:webhook_id", get(get_webhook))
+        .route("/webhooks/:webhook_id/replay", post(replay_webhook))
+        .route("/outbox", get(outbox::stats))
+        .route(
+            "/webhook-endpoints",
+            get(webhooks::admin_list_endpoints).post(webhooks::admin_create_endpoint),
+        )
+        .route(
+            "/webhook-endpoints/:endpoint_id",
+            delete(webhooks::admin_delete_endpoint),
+        )
+        .route(
+            "/webhook-endpoints/:endpoint_id/deliveries",
+            get(webhooks::admin_list_deliveries),
+        )
+        .layer(middleware::from_fn_with_state(Role::Admin, require_role))
+        // Added after the role layer, so unknown routes are 404 regardless of authentication.
+        .fallback(api_not_found);
+
+    let moderation_router = Router::new()
+        .route("/reports", get(moderation::reports))
+        .route(
+            "/reports/:report_id/resolve",
+            post(moderation::resolve_report),
+        )
+        .route("/chirps/:chirp_id/hide", post(moderation::hide))
+        .route("/chirps/:chirp_id/unhide", post(moderation::unhide))
+        .route("/users/:user_id/suspend", post(moderation::suspend))
+        .route("/users/:user_id/unsuspend", post(moderation::unsuspend))
+        .route("/log", get(moderation::log))
+        .layer(middleware::from_fn_with_state(
+            Role::Moderator,
+            require_role,
+        ));
 
     let api_router = Router::new()
         .route("/healthz", get(healthz))
         .route("/chirps", post(post_chirp))
         .route("/chirps", get(get_all_chirps))
         .route("/chirps/:chirp_id", get(get_chirp))
+        .route("/chirps/:chirp_id", put(edit_chirp))
         .route("/chirps/:chirp_id", delete(delete_chirp))
+        .route("/chirps/:chirp_id/report", post(report_chirp))
+        .route("/chirps/:chirp_id/repost", post(repost))
+        .route("/chirps/:chirp_id/repost", delete(unrepost))
+        .route("/chirps/:chirp_id/bookmark", post(bookmarks::bookmark))
+        .route("/chirps/:chirp_id/bookmark", delete(bookmarks::unbookmark))
+        .route("/bookmarks", get(bookmarks::list))
+        .route("/drafts", post(drafts::create))
+        .route("/drafts", get(drafts::list))
+        .route("/drafts/:draft_id", get(drafts::get))
+        .route("/drafts/:draft_id", put(drafts::update))
+        .route("/drafts/:draft_id", delete(drafts::delete))
+        .route("/drafts/:draft_id/publish", post(drafts::publish))
+        .route("/lists", post(lists::create_list))
+        .route("/lists", get(lists::my_lists))
+        .route("/lists/:list_id", get(lists::get_list_details))
+        .route("/lists/:list_id", patch(lists::update))
+        .route("/lists/:list_id", delete(lists::delete))
+        .route("/lists/:list_id/members/:handle", put(lists::add_member))
+        .route(
+            "/lists/:list_id/members/:handle",
+            delete(lists::remove_member),
+        )
+        .route("/lists/:list_id/chirps", get(lists::chirps))
         .route("/users", post(create_user))
         .route("/users", put(update_user))
+        .route("/users/me", get(get_me))
+        .route("/users/me", patch(update_me))
+        .route("/users/me", delete(delete_me))
+        .route("/users/me/export", get(export::export_me))
+        .route("/users/me/blocks", get(list_blocks))
+        .route("/users/me/mutes", get(list_mutes))
+        .route("/users/:handle", get(get_profile))
+        .route("/users/:handle/follow", post(follow))
+        .route("/users/:handle/follow", delete(unfollow))
+        .route("/users/:handle/block", post(block))
+        .route("/users/:handle/block", delete(unblock))
+        .route("/users/:handle/mute", post(mute))
+        .route("/users/:handle/mute", delete(unmute))
+        .route("/users/:handle/lists", get(lists::user_lists))
+        .route("/users/:handle/messages", post(messages::send_message))
+        .route("/conversations", get(messages::list_conversations))
+        .route(
+            "/conversations/:conversation_id/messages",
+            get(messages::list_messages),
+        )
+        .route(
+            "/conversations/:conversation_id/read",
+            post(messages::mark_read),
+        )
+        .route("/notifications", get(notifications::list))
+        .route("/notifications/read", post(notifications::mark_all_read))
+        .route(
+            "/notifications/:notification_id/read",
+            post(notifications::mark_read),
+        )
+        .route(
+            "/notifications/preferences",
+            get(notifications::get_preferences).put(notifications::set_preferences),
+        )
         .route("/login", post(login))
         .route("/refresh", post(refresh))
         .route("/revoke", post(revoke))
-        .route("/polka/webhooks", post(polka_webhook));
+        .route("/stream/chirps", get(stream::sse))
+        .route("/stream/chirps/ws", get(stream::websocket))
+        .route(
+            "/polka/webhooks",
+            post(polka_webhook).layer(DefaultBodyLimit::max(MAX_POLKA_WEBHOOK_SIZE)),
+        )
+        .route(
+            "/webhooks",
+            get(webhooks::list_endpoints).post(webhooks::create_endpoint),
+        )
+        .route("/webhooks/:endpoint_id", delete(webhooks::delete_endpoint))
+        .route(
+            "/webhooks/:endpoint_id/deliveries",
+            get(webhooks::list_deliveries),
+        )
+        .route(
+            "/media",
+            post(upload_media).layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE + 64 * 1024)),
+        )
+        .nest("/moderation", moderation_router)
+        .fallback(api_not_found);
 
     let main_router = Router::new()
         .merge(app_router)
         .nest("/api", api_router)
         .nest("/admin", admin_router)
-        .fallback(static_fallback)
+        .fallback(not_found)
         .with_state(app_state)
         .layer(Extension(db))
         .layer(Extension(jwt_key))
-        .layer(Extension(polka_key));
+        .layer(Extension(polka_keys))
+        .layer(Extension(RateLimiter::new()))
+        .layer(Extension(media_pipeline))
+        .layer(Extension(chirp_stream.clone()));
 
     // run our app with hyper, listening globally on port 8080
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
 
-    axum::serve(listener, main_router).await.unwrap();
+    // The connection info identifies unique visitors in the static analytics.
+    axum::serve(
+        listener,
+        main_router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        // Otherwise connected stream clients keep the server from shutting down.
+        chirp_stream.shut_down();
+    })
+    .await
+    .unwrap();
+
+    // Persist the hits since the last periodic flush.
+    if let Err(e) = analytics.flush(&analytics_db).await {
+        eprintln!("Failed to flush static analytics on shutdown: {e}");
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate.recv() => {},
+    }
+}
+
+async fn not_found() -> StatusCode {
+    StatusCode::NOT_FOUND
 }
 
 // `String` implements `IntoResponse`; the response will have statuscode 200 and `text/plain; charset=utf-8` content-type.
@@ -0,0 +1,210 @@
+use std::{future::Future, io, path::PathBuf, pin::Pin, sync::Arc};
+
+use axum::{
+    extract::Multipart,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::{
+    api::extract_user_id_from_bearer, auth::JwtKey, media_processing::MediaPipeline,
+    queries::insert_media,
+};
+
+/// Maximum size of a single uploaded file.
+pub const MAX_UPLOAD_SIZE: usize = 5 * 1024 * 1024;
+
+/// Maximum number of media attachments on a single chirp.
+pub const MAX_MEDIA_PER_CHIRP: usize = 4;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A place to store uploaded files. Files are addressed by a key chosen by the caller.
+pub trait MediaStorage: Send + Sync {
+    fn put<'a>(&'a self, key: &'a str, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>>;
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>>;
+
+    /// The URL under which the file stored at `key` is served.
+    fn url(&self, key: &str) -> String;
+}
+
+pub type Storage = Arc<dyn MediaStorage>;
+
+/// Stores files in a directory. If the directory is served by the static file server, `public_prefix` is the path it is served under.
+pub struct LocalStorage {
+    root: PathBuf,
+    public_prefix: String,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>, public_prefix: &str) -> Self {
+        Self {
+            root: root.into(),
+            public_prefix: public_prefix.trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+impl MediaStorage for LocalStorage {
+    fn put<'a>(&'a self, key: &'a str, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            fs::create_dir_all(&self.root).await?;
+            fs::write(self.root.join(key), data).await
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        Box::pin(async move { fs::read(self.root.join(key)).await })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { fs::remove_file(self.root.join(key)).await })
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{key}", self.public_prefix)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageType {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+}
+
+impl ImageType {
+    /// Determines the image type from the file's magic bytes, so that we never trust the client's claimed content type.
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageType::Png)
+        } else if data.starts_with(b"\xff\xd8\xff") {
+            Some(ImageType::Jpeg)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(ImageType::Gif)
+        } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(ImageType::Webp)
+        } else {
+            None
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageType::Png => "image/png",
+            ImageType::Jpeg => "image/jpeg",
+            ImageType::Gif => "image/gif",
+            ImageType::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageType::Png => "png",
+            ImageType::Jpeg => "jpg",
+            ImageType::Gif => "gif",
+            ImageType::Webp => "webp",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MediaUploadError {
+    error: String,
+}
+
+fn upload_error(status: StatusCode, error: &str) -> axum::response::Response {
+    (
+        status,
+        Json(MediaUploadError {
+            error: error.to_owned(),
+        }),
+    )
+        .into_response()
+}
+
+/// Accepts a multipart form with a single image in the `file` field.
+/// The upload is stored privately until the media pipeline has processed it, so the media is not served until its status is `ready`.
+pub async fn upload_media(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    Extension(pipeline): Extension<MediaPipeline>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    let Ok(user_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let mut field = loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) if field.name() == Some("file") => break field,
+            Ok(Some(_)) => continue,
+            Ok(None) => return upload_error(StatusCode::BAD_REQUEST, "Missing file field"),
+            Err(_) => return upload_error(StatusCode::BAD_REQUEST, "Malformed multipart body"),
+        }
+    };
+
+    // Read in chunks so that we can stop as soon as the limit is exceeded.
+    let mut data = Vec::new();
+    loop {
+        match field.chunk().await {
+            Ok(Some(chunk)) => {
+                if data.len() + chunk.len() > MAX_UPLOAD_SIZE {
+                    return upload_error(StatusCode::PAYLOAD_TOO_LARGE, "File is too large");
+                }
+                data.extend_from_slice(&chunk);
+            }
+            Ok(None) => break,
+            // The body limit layer can cut off the stream before we hit our own limit.
+            Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                return upload_error(StatusCode::PAYLOAD_TOO_LARGE, "File is too large")
+            }
+            Err(_) => return upload_error(StatusCode::BAD_REQUEST, "Malformed multipart body"),
+        }
+    }
+
+    let Some(image_type) = ImageType::sniff(&data) else {
+        return upload_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Only PNG, JPEG, GIF and WebP images are supported",
+        );
+    };
+
+    let media_id = Uuid::new_v4();
+    let storage_key = format!("{media_id}.{}", image_type.extension());
+    if pipeline.incoming.put(&storage_key, &data).await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    match insert_media(
+        &db,
+        media_id,
+        user_id,
+        image_type.content_type(),
+        data.len() as i64,
+        &storage_key,
+        &pipeline.public.url(&storage_key),
+    )
+    .await
+    {
+        Ok(media) => {
+            pipeline.wake();
+            (StatusCode::ACCEPTED, Json(media)).into_response()
+        }
+        Err(_) => {
+            // Don't leave orphaned files behind. There is nothing more to do if this fails too.
+            let _ = pipeline.incoming.delete(&storage_key).await;
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::{io::Cursor, sync::Arc};
+
+use color_eyre::eyre::{eyre, Result};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits,
+};
+use sqlx::PgPool;
+use time::Duration;
+use tokio::sync::Notify;
+
+use crate::{
+    media::{ImageType, Storage},
+    queries::{
+        claim_pending_media, complete_media_processing, fail_media_processing, Media,
+        NewMediaVariant,
+    },
+};
+
+/// Thumbnails are generated for each of these sizes that is smaller than the original image. The size is the maximum of width and height.
+const VARIANT_SIZES: [(&str, u32); 3] = [("small", 160), ("medium", 640), ("large", 1280)];
+
+/// Larger images are rejected to protect against decompression bombs.
+const MAX_DIMENSION: u32 = 10_000;
+
+const MAX_ATTEMPTS: i32 = 3;
+
+/// Media that has been processing for longer than this is assumed to have been abandoned by a crashed worker.
+const STALE_PROCESSING_TIMEOUT: Duration = Duration::minutes(10);
+
+/// How often the queue is checked without being woken up, which picks up media left over from before a restart.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Processes uploaded images in the background: strips metadata such as EXIF/GPS, generates thumbnails and computes a blurhash placeholder.
+///
+/// Uploads are written to `incoming`, which is not publicly served. The processed image and its variants are written to `public`.
+/// The `media` table doubles as the work queue, so no uploads are lost across restarts.
+#[derive(Clone)]
+pub struct MediaPipeline {
+    db: PgPool,
+    pub incoming: Storage,
+    pub public: Storage,
+    wakeup: Arc<Notify>,
+}
+
+impl MediaPipeline {
+    pub fn new(db: PgPool, incoming: Storage, public: Storage) -> Self {
+        Self {
+            db,
+            incoming,
+            public,
+            wakeup: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Signals the worker that new media is waiting.
+    pub fn wake(&self) {
+        self.wakeup.notify_one();
+    }
+
+    pub fn spawn_worker(&self) {
+        let pipeline = self.clone();
+        tokio::spawn(async move { pipeline.run().await });
+    }
+
+    async fn run(self) {
+        loop {
+            match claim_pending_media(&self.db, STALE_PROCESSING_TIMEOUT).await {
+                Ok(Some(media)) => {
+                    if let Err(e) = self.process(&media).await {
+                        eprintln!("Failed to process media {}: {e}", media.media_id);
+                        if let Err(e) =
+                            fail_media_processing(&self.db, media.media_id, MAX_ATTEMPTS).await
+                        {
+                            eprintln!("Failed to requeue media {}: {e}", media.media_id);
+                        }
+                    }
+                }
+                Ok(None) => {
+                    let _ = tokio::time::timeout(POLL_INTERVAL, self.wakeup.notified()).await;
+                }
+                Err(e) => {
+                    eprintln!("Failed to claim pending media: {e}");
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn process(&self, media: &Media) -> Result<()> {
+        let data = self.incoming.get(&media.storage_key).await?;
+        let processed = tokio::task::spawn_blocking(move || process_image(data)).await??;
+
+        self.public
+            .put(&media.storage_key, &processed.original)
+            .await?;
+
+        let mut variants = Vec::new();
+        for variant in &processed.variants {
+            let storage_key = format!(
+                "{}_{}.{}",
+                media.media_id,
+                variant.name,
+                variant.image_type.extension()
+            );
+            self.public.put(&storage_key, &variant.data).await?;
+            variants.push(NewMediaVariant {
+                variant: variant.name.to_owned(),
+                width: variant.width as i32,
+                height: variant.height as i32,
+                content_type: variant.image_type.content_type().to_owned(),
+                url: self.public.url(&storage_key),
+                storage_key,
+            });
+        }
+
+        complete_media_processing(
+            &self.db,
+            media.media_id,
+            processed.original.len() as i64,
+            processed.width as i32,
+            processed.height as i32,
+            &processed.blurhash,
+            &variants,
+        )
+        .await?;
+
+        // The upload is no longer needed once the processed version is stored.
+        if let Err(e) = self.incoming.delete(&media.storage_key).await {
+            eprintln!("Failed to delete upload of media {}: {e}", media.media_id);
+        }
+        Ok(())
+    }
+}
+
+struct ProcessedImage {
+    /// The original image, re-encoded without metadata.
+    original: Vec<u8>,
+    width: u32,
+    height: u32,
+    blurhash: String,
+    variants: Vec<EncodedVariant>,
+}
+
+struct EncodedVariant {
+    name: &'static str,
+    width: u32,
+    height: u32,
+    image_type: ImageType,
+    data: Vec<u8>,
+}
+
+/// CPU-bound, so it should be run with `spawn_blocking`.
+fn process_image(data: Vec<u8>) -> Result<ProcessedImage> {
+    let image_type = ImageType::sniff(&data).ok_or_else(|| eyre!("Unsupported image type"))?;
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    let mut reader = ImageReader::new(Cursor::new(&data)).with_guessed_format()?;
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    // The orientation lives in the EXIF data we are about to strip, so it has to be applied to the pixels instead.
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    // Re-encoding drops all metadata. GIFs are rebuilt from their frames instead, so that animations survive.
+    let original = match image_type {
+        ImageType::Gif => strip_gif(&data)?,
+        _ => encode(&image, image_type)?,
+    };
+
+    let (width, height) = (image.width(), image.height());
+    let mut variants = Vec::new();
+    for (name, size) in VARIANT_SIZES {
+        if width.max(height) <= size {
+            continue;
+        }
+        let thumbnail = image.thumbnail(size, size);
+        // JPEG has no transparency, so images with an alpha channel get PNG thumbnails.
+        let variant_type = if image.color().has_alpha() {
+            ImageType::Png
+        } else {
+            ImageType::Jpeg
+        };
+        variants.push(EncodedVariant {
+            name,
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+            data: encode(&thumbnail, variant_type)?,
+            image_type: variant_type,
+        });
+    }
+
+    let placeholder = image.thumbnail(32, 32).to_rgba8();
+    let blurhash = blurhash::encode(
+        4,
+        3,
+        placeholder.width(),
+        placeholder.height(),
+        placeholder.as_raw(),
+    )
+    .map_err(|e| eyre!("Failed to compute blurhash: {e:?}"))?;
+
+    Ok(ProcessedImage {
+        original,
+        width,
+        height,
+        blurhash,
+        variants,
+    })
+}
+
+/// Copies the frames of a GIF as they are, without its comment and application extensions, which can carry metadata such as XMP.
+/// The loop count is the only extension that is kept.
+fn strip_gif(data: &[u8]) -> Result<Vec<u8>> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data)?;
+    let mut encoder = gif::Encoder::new(
+        Vec::new(),
+        decoder.width(),
+        decoder.height(),
+        decoder.global_palette().unwrap_or_default(),
+    )?;
+    let mut first = true;
+    while let Some(mut frame) = decoder.read_next_frame()?.cloned() {
+        // The loop count comes before the first frame, but is only known once the decoder has read up to it.
+        // Without it the animation plays once, which is also what the decoder reports when there is none.
+        if first && decoder.repeat() != gif::Repeat::Finite(0) {
+            encoder.set_repeat(decoder.repeat())?;
+        }
+        first = false;
+        // The decoder has already deinterlaced the frame.
+        frame.interlaced = false;
+        encoder.write_frame(&frame)?;
+    }
+    Ok(encoder.into_inner()?)
+}
+
+fn encode(image: &DynamicImage, image_type: ImageType) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    match image_type {
+        ImageType::Jpeg => {
+            // JPEG cannot store an alpha channel.
+            DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut data, 85))?
+        }
+        ImageType::Png => image.write_with_encoder(PngEncoder::new(&mut data))?,
+        ImageType::Webp => DynamicImage::ImageRgba8(image.to_rgba8())
+            .write_with_encoder(WebPEncoder::new_lossless(&mut data))?,
+        ImageType::Gif => image.write_to(&mut Cursor::new(&mut data), ImageFormat::Gif)?,
+    }
+    Ok(data)
+}
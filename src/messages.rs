@@ -0,0 +1,180 @@
+use axum::{
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    api::{authenticate_active_user, clean_chirp},
+    auth::JwtKey,
+    entitlements::{Entitlements, RateLimiter},
+    queries::{
+        get_conversations, get_messages, get_public_profile, is_blocked_between,
+        is_conversation_participant, mark_conversation_read, send_direct_message,
+    },
+};
+
+pub const MAX_MESSAGE_LENGTH: usize = 1000;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+/// The body of a direct message. Validated and cleaned like a `ChirpBody`, but with its own length limit.
+pub struct MessageBody(String);
+
+impl MessageBody {
+    pub fn new(body: String) -> Result<Self, String> {
+        if body.len() > MAX_MESSAGE_LENGTH {
+            return Err("Message is too long".to_owned());
+        }
+        let body = clean_chirp(body);
+        if body.is_empty() {
+            Err("Message is empty".to_owned())
+        } else {
+            Ok(MessageBody(body))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MessageValidationError {
+    error: String,
+}
+
+#[derive(Deserialize)]
+pub struct SendMessagePayload {
+    body: String,
+}
+
+/// Sends a direct message to the user with the given handle. Shares its rate limit with posting chirps.
+/// Users can't message someone who has blocked them, or whom they have blocked. Existing conversations stay readable.
+pub async fn send_message(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    Extension(rate_limiter): Extension<RateLimiter>,
+    headers: HeaderMap,
+    Path(handle): Path<String>,
+    Json(payload): Json<SendMessagePayload>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    let body = match MessageBody::new(payload.body) {
+        Ok(body) => body,
+        Err(error) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(MessageValidationError { error }),
+            )
+                .into_response()
+        }
+    };
+    let Ok(recipient) = get_public_profile(&db, &handle).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if recipient.id == user.id {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    match is_blocked_between(&db, user.id, recipient.id).await {
+        Ok(false) => {}
+        Ok(true) => return StatusCode::FORBIDDEN.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+
+    let entitlements = Entitlements::for_user(&user);
+    if let Err(limited) = rate_limiter.check(user.id, entitlements.chirp_writes_per_minute()) {
+        return limited.into_response();
+    }
+
+    match send_direct_message(&db, user.id, recipient.id, &body.0).await {
+        Ok(message) => (StatusCode::CREATED, Json(message)).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PageParams {
+    limit: Option<i64>,
+    /// Id of the oldest message the client has, to fetch the messages before it.
+    before: Option<Uuid>,
+}
+
+impl PageParams {
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+/// The authenticated user's conversations, most recently active first, with the last message and the number of unread messages in each.
+pub async fn list_conversations(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Query(params): Query<PageParams>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    match get_conversations(&db, user.id, params.limit()).await {
+        Ok(conversations) => Json(conversations).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// A page of messages, newest first. Conversations the user isn't part of are reported as not found.
+pub async fn list_messages(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(conversation_id): Path<Uuid>,
+    Query(params): Query<PageParams>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    match is_conversation_participant(&db, conversation_id, user.id).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    match get_messages(&db, conversation_id, params.before, params.limit()).await {
+        Ok(messages) => Json(messages).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct MarkReadResponse {
+    marked: u64,
+}
+
+/// Marks the messages the user has received in the conversation as read, which the sender sees as `read_at`.
+pub async fn mark_read(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(conversation_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    match is_conversation_participant(&db, conversation_id, user.id).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    match mark_conversation_read(&db, conversation_id, user.id).await {
+        Ok(marked) => Json(MarkReadResponse { marked }).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
@@ -1,13 +1,26 @@
+use std::net::SocketAddr;
+
 use axum::{
-    extract::{Request, State},
-    http::StatusCode,
+    extract::{ConnectInfo, Request, State},
+    http::{
+        header::{REFERER, USER_AGENT},
+        StatusCode,
+    },
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
+    Extension,
 };
+use sqlx::PgPool;
 
 use super::AppState;
+use crate::{
+    api::extract_user_id_from_bearer,
+    auth::JwtKey,
+    queries::{get_user_role, Role},
+};
 
-pub async fn fileserver_hits_middleware(
+/// Records every request to the static file server, including redirects and errors, so that they show up in the status code breakdown.
+pub async fn static_analytics_middleware(
     State(app_state): State<AppState>,
     // you can add more extractors here but the last
     // extractor must implement `FromRequest` which
@@ -15,11 +28,42 @@ pub async fn fileserver_hits_middleware(
     request: Request,
     next: Next,
 ) -> Response {
+    let path = request.uri().path().to_owned();
+    let headers = request.headers();
+    let referrer = headers.get(REFERER).cloned();
+    let user_agent = headers.get(USER_AGENT).cloned();
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+
     let resp = next.run(request).await;
-    // tower_http::ServeDir redirects paths to directories without trailing slash to the version with trailing slash with a 307 temporary redirect. This causes double counting of hits in those situations.
-    if resp.status() != StatusCode::TEMPORARY_REDIRECT {
-        let mut data_mux = app_state.data.lock().unwrap();
-        data_mux.fileserver_hits += 1;
-    }
+    app_state.analytics.record(
+        &path,
+        resp.status(),
+        referrer.as_ref(),
+        user_agent.as_ref(),
+        client,
+    );
     resp
 }
+
+/// Rejects requests that are not made by a user holding at least the role given as state.
+/// Use with `middleware::from_fn_with_state(Role::Admin, require_role)`.
+pub async fn require_role(
+    State(required_role): State<Role>,
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Ok(user_id) = extract_user_id_from_bearer(request.headers(), &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    match get_user_role(&db, user_id).await {
+        Ok(role) if role >= required_role => next.run(request).await,
+        Ok(_) => StatusCode::FORBIDDEN.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
@@ -0,0 +1,132 @@
+use axum::{
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    api::extract_user_id_from_bearer,
+    auth::JwtKey,
+    queries::{
+        self, get_moderation_log, get_open_reports, get_user_role, hide_chirp, unhide_chirp,
+        unsuspend_user,
+    },
+};
+
+// All routes in this module are behind `require_role(Role::Moderator)`, so the bearer token has already been validated by the time the handlers run.
+
+#[derive(Deserialize)]
+pub struct ModerationPayload {
+    reason: String,
+}
+
+pub async fn reports(Extension(db): Extension<PgPool>) -> impl IntoResponse {
+    match get_open_reports(&db).await {
+        Ok(reports) => Json(reports).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+pub async fn resolve_report(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(report_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let Ok(moderator_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+
+    match queries::resolve_report(&db, moderator_id, report_id).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::NOT_FOUND,
+    }
+}
+
+pub async fn hide(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(chirp_id): Path<Uuid>,
+    Json(payload): Json<ModerationPayload>,
+) -> impl IntoResponse {
+    let Ok(moderator_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    match hide_chirp(&db, moderator_id, chirp_id, &payload.reason).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub async fn unhide(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(chirp_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let Ok(moderator_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    match unhide_chirp(&db, moderator_id, chirp_id).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub async fn suspend(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<ModerationPayload>,
+) -> impl IntoResponse {
+    let Ok(moderator_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    // Moderators may only suspend users with less privileges than themselves.
+    let (Ok(moderator_role), Ok(user_role)) = (
+        get_user_role(&db, moderator_id).await,
+        get_user_role(&db, user_id).await,
+    ) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    if user_role >= moderator_role {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    match queries::suspend_user(&db, moderator_id, user_id, &payload.reason).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub async fn unsuspend(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let Ok(moderator_id) = extract_user_id_from_bearer(&headers, &key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    match unsuspend_user(&db, moderator_id, user_id).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub async fn log(Extension(db): Extension<PgPool>) -> impl IntoResponse {
+    match get_moderation_log(&db).await {
+        Ok(entries) => Json(entries).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
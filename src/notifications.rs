@@ -0,0 +1,264 @@
+use axum::{
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    api::authenticate_active_user,
+    auth::JwtKey,
+    outbox::{ChirpSnapshot, DomainEvent, HandlerFuture, OutboxEvent, OutboxSubscriber},
+    queries::{
+        count_unread_notifications, get_chirp_author_id, get_notification_mutes, get_notifications,
+        get_user_ids_by_handles, insert_notifications, mark_notifications_read,
+        set_notification_mutes, Notification, NotificationType,
+    },
+};
+
+/// Mentions beyond this many in a single chirp don't notify anyone, so a chirp can't be used to notify users in bulk.
+const MAX_MENTIONS_PER_CHIRP: usize = 10;
+
+const DEFAULT_NOTIFICATIONS_LIMIT: i64 = 50;
+const MAX_NOTIFICATIONS_LIMIT: i64 = 200;
+
+/// Turns domain events into notifications for the users they concern.
+pub struct NotificationSubscriber {
+    db: PgPool,
+}
+
+impl NotificationSubscriber {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// A reply that also mentions the parent's author notifies them once, as a reply.
+    async fn chirp_created(
+        &self,
+        event_id: Uuid,
+        chirp: &ChirpSnapshot,
+    ) -> Result<(), sqlx::Error> {
+        if let Some(parent_id) = chirp.in_reply_to {
+            // The parent may have been deleted since, in which case there is nobody to notify.
+            if let Some(parent_author) = get_chirp_author_id(&self.db, parent_id).await? {
+                insert_notifications(
+                    &self.db,
+                    event_id,
+                    NotificationType::Reply,
+                    chirp.user_id,
+                    Some(chirp.id),
+                    &[parent_author],
+                )
+                .await?;
+            }
+        }
+
+        let handles = mentioned_handles(&chirp.body);
+        if !handles.is_empty() {
+            let mentioned = get_user_ids_by_handles(&self.db, &handles).await?;
+            insert_notifications(
+                &self.db,
+                event_id,
+                NotificationType::Mention,
+                chirp.user_id,
+                Some(chirp.id),
+                &mentioned,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+impl OutboxSubscriber for NotificationSubscriber {
+    fn name(&self) -> &'static str {
+        "notifications"
+    }
+
+    fn handle<'a>(&'a self, event: &'a OutboxEvent) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            match &event.event {
+                DomainEvent::ChirpCreated { chirp } => {
+                    self.chirp_created(event.event_id, chirp).await?
+                }
+                &DomainEvent::UserFollowed {
+                    follower_id,
+                    followee_id,
+                } => {
+                    insert_notifications(
+                        &self.db,
+                        event.event_id,
+                        NotificationType::Follow,
+                        follower_id,
+                        None,
+                        &[followee_id],
+                    )
+                    .await?;
+                }
+                DomainEvent::ChirpDeleted { .. } | DomainEvent::UserUpgraded { .. } => {}
+            }
+            Ok(())
+        })
+    }
+}
+
+/// The distinct handles mentioned as `@handle` in the body, in the order they first appear.
+/// An `@` only starts a mention at the start of a word, so email addresses are not mentions.
+fn mentioned_handles(body: &str) -> Vec<String> {
+    let mut handles: Vec<String> = Vec::new();
+    let mut previous = None;
+    let mut chars = body.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let starts_word = previous.is_none_or(|p: char| !(p.is_alphanumeric() || p == '_'));
+        previous = Some(c);
+        if c != '@' || !starts_word {
+            continue;
+        }
+        let start = i + 1;
+        let mut end = start;
+        while let Some(&(j, c)) = chars.peek() {
+            if !(c.is_ascii_alphanumeric() || c == '_') {
+                break;
+            }
+            end = j + c.len_utf8();
+            previous = Some(c);
+            chars.next();
+        }
+        let handle = &body[start..end];
+        if (3..=30).contains(&handle.len())
+            && !handles.iter().any(|h| h.eq_ignore_ascii_case(handle))
+        {
+            handles.push(handle.to_owned());
+            if handles.len() == MAX_MENTIONS_PER_CHIRP {
+                break;
+            }
+        }
+    }
+    handles
+}
+
+#[derive(Deserialize)]
+pub struct NotificationsParams {
+    #[serde(default)]
+    unread_only: bool,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct NotificationsResponse {
+    unread_count: i64,
+    notifications: Vec<Notification>,
+}
+
+/// The newest notifications of the authenticated user, together with the number of unread ones.
+pub async fn list(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Query(params): Query<NotificationsParams>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_NOTIFICATIONS_LIMIT)
+        .clamp(1, MAX_NOTIFICATIONS_LIMIT);
+    let (Ok(unread_count), Ok(notifications)) = (
+        count_unread_notifications(&db, user.id).await,
+        get_notifications(&db, user.id, params.unread_only, limit).await,
+    ) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    Json(NotificationsResponse {
+        unread_count,
+        notifications,
+    })
+    .into_response()
+}
+
+pub async fn mark_read(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(notification_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status,
+    };
+    match mark_notifications_read(&db, user.id, Some(&[notification_id])).await {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MarkReadPayload {
+    /// All unread notifications are marked as read if not given, so `{}` marks everything as read.
+    ids: Option<Vec<Uuid>>,
+}
+
+#[derive(Serialize)]
+struct MarkReadResponse {
+    marked: u64,
+}
+
+/// Marks the given notifications as read, or all of them. Ids of other users' notifications are ignored.
+pub async fn mark_all_read(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Json(payload): Json<MarkReadPayload>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    match mark_notifications_read(&db, user.id, payload.ids.as_deref()).await {
+        Ok(marked) => Json(MarkReadResponse { marked }).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NotificationPreferences {
+    /// Types of notification the user doesn't receive. Muting a type doesn't affect notifications already received.
+    muted: Vec<NotificationType>,
+}
+
+pub async fn get_preferences(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    match get_notification_mutes(&db, user.id).await {
+        Ok(muted) => Json(NotificationPreferences { muted }).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+pub async fn set_preferences(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Json(preferences): Json<NotificationPreferences>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    match set_notification_mutes(&db, user.id, &preferences.muted).await {
+        Ok(()) => Json(preferences).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
@@ -0,0 +1,205 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
+
+use crate::{
+    api::Chirp,
+    queries::{
+        fail_outbox_event, get_outbox_stats, lock_pending_outbox_events,
+        mark_outbox_event_dispatched,
+    },
+};
+
+/// How often the outbox is checked for new events.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Events are dispatched in batches of up to this many.
+const DISPATCH_BATCH_SIZE: i64 = 100;
+
+const RETRY_BASE_DELAY: Duration = Duration::seconds(10);
+
+/// Failing events are retried indefinitely, but at most this far apart.
+const MAX_RETRY_DELAY: Duration = Duration::hours(1);
+
+/// A chirp as it was when the event happened. The body is kept as a plain string, since it may be longer than the default limit that `ChirpBody` validates against.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChirpSnapshot {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub created_at: Option<OffsetDateTime>,
+    pub updated_at: Option<OffsetDateTime>,
+    pub body: String,
+    #[serde(default)]
+    pub in_reply_to: Option<Uuid>,
+    #[serde(default)]
+    pub repost_of: Option<Uuid>,
+    #[serde(default)]
+    pub quote_of: Option<Uuid>,
+    #[serde(default)]
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+impl From<&Chirp> for ChirpSnapshot {
+    fn from(chirp: &Chirp) -> Self {
+        Self {
+            id: chirp.chirp_id,
+            user_id: chirp.user_id,
+            created_at: chirp.created_at,
+            updated_at: chirp.updated_at,
+            body: chirp.body.to_string(),
+            in_reply_to: chirp.in_reply_to,
+            repost_of: chirp.repost_of,
+            quote_of: chirp.quote_of,
+            expires_at: chirp.expires_at,
+        }
+    }
+}
+
+/// Something that happened, recorded in the outbox by the transaction that made it happen.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DomainEvent {
+    ChirpCreated {
+        chirp: ChirpSnapshot,
+    },
+    ChirpDeleted {
+        chirp: ChirpSnapshot,
+    },
+    /// The user subscribed to Chirpy Red, or resubscribed after their subscription ended.
+    UserUpgraded {
+        user_id: Uuid,
+    },
+    UserFollowed {
+        follower_id: Uuid,
+        followee_id: Uuid,
+    },
+}
+
+impl DomainEvent {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            DomainEvent::ChirpCreated { .. } => "chirp_created",
+            DomainEvent::ChirpDeleted { .. } => "chirp_deleted",
+            DomainEvent::UserUpgraded { .. } => "user_upgraded",
+            DomainEvent::UserFollowed { .. } => "user_followed",
+        }
+    }
+}
+
+/// An event read back from the outbox. `event_id` is stable across redeliveries, so subscribers can use it to deduplicate.
+pub struct OutboxEvent {
+    pub event_id: Uuid,
+    pub created_at: OffsetDateTime,
+    pub event: DomainEvent,
+}
+
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Reacts to domain events. Events are delivered at least once, so handlers must be idempotent.
+pub trait OutboxSubscriber: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn handle<'a>(&'a self, event: &'a OutboxEvent) -> HandlerFuture<'a>;
+}
+
+/// Publishes events from the outbox to in-process subscribers.
+///
+/// Events are locked while they are dispatched, so several server instances can run dispatchers side by side.
+/// An event is marked as dispatched once every subscriber has handled it. If any subscriber fails, the event is retried later for all of them,
+/// without holding up the events after it.
+pub struct OutboxDispatcher {
+    db: PgPool,
+    subscribers: Vec<Arc<dyn OutboxSubscriber>>,
+}
+
+impl OutboxDispatcher {
+    pub fn new(db: PgPool) -> Self {
+        Self {
+            db,
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub fn subscribe(mut self, subscriber: Arc<dyn OutboxSubscriber>) -> Self {
+        self.subscribers.push(subscriber);
+        self
+    }
+
+    pub fn spawn(self) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                loop {
+                    match self.dispatch_batch().await {
+                        Ok(0) => break,
+                        Ok(_) => {}
+                        Err(e) => {
+                            eprintln!("Failed to dispatch outbox events: {e}");
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Returns the number of events that were attempted.
+    async fn dispatch_batch(&self) -> Result<usize, sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+        let events = lock_pending_outbox_events(&mut tx, DISPATCH_BATCH_SIZE).await?;
+        for pending in &events {
+            let result = match serde_json::from_str(&pending.payload) {
+                Ok(event) => {
+                    self.handle(&OutboxEvent {
+                        event_id: pending.event_id,
+                        created_at: pending.created_at,
+                        event,
+                    })
+                    .await
+                }
+                Err(e) => Err(eyre!("Invalid outbox event: {e}")),
+            };
+            match result {
+                Ok(()) => mark_outbox_event_dispatched(&mut tx, pending.event_id).await?,
+                Err(e) => {
+                    let retry_at = OffsetDateTime::now_utc() + retry_delay(pending.attempts + 1);
+                    fail_outbox_event(&mut tx, pending.event_id, &e.to_string(), retry_at).await?;
+                }
+            }
+        }
+        tx.commit().await?;
+        Ok(events.len())
+    }
+
+    async fn handle(&self, event: &OutboxEvent) -> Result<()> {
+        let mut errors = Vec::new();
+        for subscriber in &self.subscribers {
+            if let Err(e) = subscriber.handle(event).await {
+                errors.push(format!("{}: {e}", subscriber.name()));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!(errors.join("; ")))
+        }
+    }
+}
+
+fn retry_delay(attempts: i32) -> Duration {
+    (RETRY_BASE_DELAY * 2i32.pow((attempts - 1).clamp(0, 16) as u32)).min(MAX_RETRY_DELAY)
+}
+
+/// How far the dispatcher has got. Behind `require_role(Role::Admin)`.
+pub async fn stats(Extension(db): Extension<PgPool>) -> impl IntoResponse {
+    match get_outbox_stats(&db).await {
+        Ok(stats) => Json(stats).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
@@ -2,13 +2,15 @@ use std::fmt::Display;
 
 use password_auth::{generate_hash, verify_password};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
-use time::OffsetDateTime;
+use sqlx::{PgConnection, PgPool};
+use time::{Date, Duration, OffsetDateTime, Time, UtcOffset};
+use tokio_stream::Stream;
 use uuid::Uuid;
 
 use crate::{
     api::{Chirp, ChirpBody},
     auth::make_refresh_token,
+    outbox::{ChirpSnapshot, DomainEvent},
     state::Platform,
 };
 
@@ -21,12 +23,29 @@ pub struct User {
     #[serde(skip_serializing)]
     hashed_password: String,
     pub is_chirpy_red: bool,
+    #[serde(skip_serializing)]
+    pub suspended_at: Option<OffsetDateTime>,
+    #[serde(skip_serializing)]
+    pub deleted_at: Option<OffsetDateTime>,
+    pub handle: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
 }
 
 impl User {
     pub fn verify(&self, password: &str) -> Result<(), password_auth::VerifyError> {
         verify_password(password, &self.hashed_password)
     }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_at.is_some()
+    }
+
+    /// Whether the user has requested deletion of their account, which has yet to be purged.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
 }
 
 pub async fn insert_user(db: &PgPool, email: &str, password: &str) -> Result<User, sqlx::Error> {
@@ -63,6 +82,18 @@ pub async fn get_user_by_email(db: &PgPool, email: &str) -> Result<User, sqlx::E
     .await
 }
 
+pub async fn get_user(db: &PgPool, user_id: Uuid) -> Result<User, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT * FROM users WHERE id = $1
+"#,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+}
+
 pub async fn update_user_credentials(
     db: &PgPool,
     user_id: Uuid,
@@ -86,203 +117,3421 @@ RETURNING *
     .await
 }
 
-pub async fn make_user_red(db: &PgPool, user_id: Uuid) -> Result<User, sqlx::Error> {
-    sqlx::query_as!(
-        User,
+/// Length of a Chirpy Red billing period, used when Polka doesn't say when the subscription expires.
+pub const SUBSCRIPTION_PERIOD: Duration = Duration::days(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum PolkaEvent {
+    #[serde(rename = "user.upgraded")]
+    Upgraded,
+    #[serde(rename = "user.renewed")]
+    Renewed,
+    #[serde(rename = "user.payment_failed")]
+    PaymentFailed,
+    #[serde(rename = "user.downgraded")]
+    Downgraded,
+    #[serde(rename = "user.refunded")]
+    Refunded,
+    /// Events we don't handle are acknowledged and ignored.
+    #[serde(other)]
+    Unknown,
+}
+
+impl PolkaEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolkaEvent::Upgraded => "user.upgraded",
+            PolkaEvent::Renewed => "user.renewed",
+            PolkaEvent::PaymentFailed => "user.payment_failed",
+            PolkaEvent::Downgraded => "user.downgraded",
+            PolkaEvent::Refunded => "user.refunded",
+            PolkaEvent::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "subscription_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    Active,
+    /// A payment failed. The member keeps Chirpy Red until the end of the period, giving Polka time to retry.
+    PastDue,
+    /// Downgraded by the member. They keep Chirpy Red until the end of the period they paid for.
+    Canceled,
+    Expired,
+    /// Refunded subscriptions end immediately.
+    Refunded,
+}
+
+/// Applies a subscription event from Polka, unless the event has been processed before.
+/// Returns whether the event was new.
+///
+/// `expires_at` is the end of the paid period, if Polka sent it. Otherwise upgrades and renewals last for `SUBSCRIPTION_PERIOD`.
+pub async fn apply_polka_event(
+    db: &PgPool,
+    event_id: &str,
+    event: PolkaEvent,
+    user_id: Uuid,
+    expires_at: Option<OffsetDateTime>,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let new_event = sqlx::query!(
         r#"
-UPDATE users
-SET is_chirpy_red = true
+INSERT INTO polka_events (event_id, event, received_at) VALUES ($1, $2, NOW())
+ON CONFLICT (event_id) DO NOTHING
+"#,
+        event_id,
+        event.as_str()
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if !new_event {
+        return Ok(false);
+    }
+
+    // Unknown users are RowNotFound, like events that don't apply to the user's subscription, rather than a foreign key violation.
+    sqlx::query_scalar!(
+        r#"
+SELECT id FROM users WHERE id = $1 FOR SHARE
+"#,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let period_end = expires_at.unwrap_or(OffsetDateTime::now_utc() + SUBSCRIPTION_PERIOD);
+    // An error rolls back the transaction, leaving the event unrecorded so that a retry can still succeed.
+    match event {
+        PolkaEvent::Upgraded => {
+            // Resubscribing after a subscription ended starts a new one.
+            sqlx::query!(
+                r#"
+INSERT INTO subscriptions (user_id, status, started_at, current_period_end, updated_at)
+VALUES ($1, 'active', NOW(), $2, NOW())
+ON CONFLICT (user_id) DO UPDATE SET
+    status = 'active',
+    started_at = CASE WHEN subscriptions.status IN ('expired', 'refunded') THEN NOW() ELSE subscriptions.started_at END,
+    current_period_end = EXCLUDED.current_period_end,
+    canceled_at = NULL,
+    updated_at = NOW()
+"#,
+                user_id,
+                period_end
+            )
+            .execute(&mut *tx)
+            .await?;
+            record_outbox_event(&mut tx, &DomainEvent::UserUpgraded { user_id }).await?;
+        }
+        PolkaEvent::Renewed => {
+            // Without an explicit expiry, a renewal extends the period from its current end, so renewing early doesn't lose time.
+            sqlx::query!(
+                r#"
+UPDATE subscriptions SET
+    status = 'active',
+    current_period_end = COALESCE($2, GREATEST(current_period_end, NOW()) + $3::INTERVAL),
+    canceled_at = NULL,
+    updated_at = NOW()
+WHERE user_id = $1
+RETURNING user_id
+"#,
+                user_id,
+                expires_at,
+                SUBSCRIPTION_PERIOD as _
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+        }
+        PolkaEvent::PaymentFailed => {
+            sqlx::query!(
+                r#"
+UPDATE subscriptions SET status = 'past_due', updated_at = NOW()
+WHERE user_id = $1 AND status IN ('active', 'past_due')
+RETURNING user_id
+"#,
+                user_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+        }
+        PolkaEvent::Downgraded => {
+            sqlx::query!(
+                r#"
+UPDATE subscriptions SET status = 'canceled', canceled_at = NOW(), updated_at = NOW()
+WHERE user_id = $1 AND status IN ('active', 'past_due')
+RETURNING user_id
+"#,
+                user_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+        }
+        PolkaEvent::Refunded => {
+            sqlx::query!(
+                r#"
+UPDATE subscriptions SET
+    status = 'refunded',
+    current_period_end = LEAST(current_period_end, NOW()),
+    canceled_at = COALESCE(canceled_at, NOW()),
+    updated_at = NOW()
+WHERE user_id = $1
+RETURNING user_id
+"#,
+                user_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+        }
+        PolkaEvent::Unknown => {}
+    }
+
+    sync_chirpy_red(&mut tx, user_id).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Recomputes `users.is_chirpy_red` from the user's subscription.
+async fn sync_chirpy_red(conn: &mut PgConnection, user_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+UPDATE users SET is_chirpy_red = EXISTS (
+    SELECT 1 FROM subscriptions
+    WHERE user_id = $1
+    AND status IN ('active', 'past_due', 'canceled')
+    AND current_period_end > NOW()
+)
 WHERE id = $1
-RETURNING *
 "#,
         user_id
     )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Ends subscriptions whose period has passed and takes away Chirpy Red. Returns the number of expired subscriptions.
+pub async fn expire_subscriptions(db: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let expired = sqlx::query_scalar!(
+        r#"
+UPDATE subscriptions SET status = 'expired', updated_at = NOW()
+WHERE status IN ('active', 'past_due', 'canceled') AND current_period_end <= NOW()
+RETURNING user_id
+"#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+UPDATE users SET is_chirpy_red = false WHERE id = ANY($1)
+"#,
+        &expired
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(expired.len() as u64)
+}
+
+/// Where a request to the Polka webhook got to. `Received` is only seen while the request is being processed, or if the server stopped before it was done.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "webhook_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookStatus {
+    Received,
+    Processed,
+    /// The event had already been processed.
+    Duplicate,
+    /// An event we don't handle.
+    Ignored,
+    /// The signature or timestamp didn't verify.
+    Rejected,
+    /// The body wasn't a valid Polka event.
+    Malformed,
+    Failed,
+}
+
+/// A logged webhook request without its headers and payload.
+#[derive(Serialize, Debug)]
+pub struct PolkaWebhookSummary {
+    pub webhook_id: Uuid,
+    pub received_at: OffsetDateTime,
+    pub event_id: Option<String>,
+    pub event: Option<String>,
+    pub status: WebhookStatus,
+    pub error: Option<String>,
+    pub attempts: i32,
+    pub last_attempt_at: Option<OffsetDateTime>,
+}
+
+#[derive(Debug)]
+pub struct PolkaWebhook {
+    pub summary: PolkaWebhookSummary,
+    /// One `name: value` line per header.
+    pub headers: String,
+    pub payload: Vec<u8>,
+}
+
+/// Logs a webhook request before it is verified or processed, so that requests that fail still leave a trace.
+pub async fn log_polka_webhook(
+    db: &PgPool,
+    headers: &str,
+    payload: &[u8],
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+INSERT INTO polka_webhook_log (webhook_id, received_at, headers, payload, status)
+VALUES (gen_random_uuid(), NOW(), $1, $2, 'received')
+RETURNING webhook_id
+"#,
+        headers,
+        payload
+    )
     .fetch_one(db)
     .await
 }
 
-pub async fn insert_chirp(
-    db: PgPool,
-    body: ChirpBody,
-    user_id: Uuid,
-) -> Result<Chirp, sqlx::Error> {
+/// Records the result of processing a logged webhook, either when it is received or when it is replayed.
+/// The event is only known once the payload has been parsed, so an earlier value is kept if none is given.
+pub async fn record_polka_webhook_attempt(
+    db: &PgPool,
+    webhook_id: Uuid,
+    status: WebhookStatus,
+    event_id: Option<&str>,
+    event: Option<&str>,
+    error: Option<&str>,
+) -> Result<PolkaWebhookSummary, sqlx::Error> {
     sqlx::query_as!(
-        Chirp,
+        PolkaWebhookSummary,
         r#"
-        INSERT INTO chirps(chirp_id, user_id, created_at, updated_at, body)
-        VALUES (
-        gen_random_uuid(),
-        $1,
-        NOW(),
-        NOW(),
-        $2
-        )
-        RETURNING chirp_id, user_id, created_at, updated_at, body as "body: _"
-        "#,
-        user_id,
-        &body
+UPDATE polka_webhook_log SET
+    status = $2,
+    event_id = COALESCE($3, event_id),
+    event = COALESCE($4, event),
+    error = $5,
+    attempts = attempts + 1,
+    last_attempt_at = NOW()
+WHERE webhook_id = $1
+RETURNING webhook_id, received_at, event_id, event, status as "status: _", error, attempts, last_attempt_at
+"#,
+        webhook_id,
+        status as WebhookStatus,
+        event_id,
+        event,
+        error
     )
-    .fetch_one(&db)
+    .fetch_one(db)
     .await
 }
 
-pub enum SortOrder {
-    Asc,
-    Desc,
+/// The most recent webhook requests, optionally only those with the given status.
+pub async fn get_polka_webhooks(
+    db: &PgPool,
+    status: Option<WebhookStatus>,
+    limit: i64,
+) -> Result<Vec<PolkaWebhookSummary>, sqlx::Error> {
+    sqlx::query_as!(
+        PolkaWebhookSummary,
+        r#"
+SELECT webhook_id, received_at, event_id, event, status as "status: _", error, attempts, last_attempt_at
+FROM polka_webhook_log
+WHERE $1::webhook_status IS NULL OR status = $1
+ORDER BY received_at DESC
+LIMIT $2
+"#,
+        status as Option<WebhookStatus>,
+        limit
+    )
+    .fetch_all(db)
+    .await
 }
 
-impl Display for SortOrder {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                SortOrder::Asc => "ASC",
-                SortOrder::Desc => "DESC",
-            }
-        )
-    }
+pub async fn get_polka_webhook(db: &PgPool, webhook_id: Uuid) -> Result<PolkaWebhook, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+SELECT webhook_id, received_at, headers, payload, event_id, event, status as "status: WebhookStatus", error, attempts, last_attempt_at
+FROM polka_webhook_log
+WHERE webhook_id = $1
+"#,
+        webhook_id
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(PolkaWebhook {
+        summary: PolkaWebhookSummary {
+            webhook_id: row.webhook_id,
+            received_at: row.received_at,
+            event_id: row.event_id,
+            event: row.event,
+            status: row.status,
+            error: row.error,
+            attempts: row.attempts,
+            last_attempt_at: row.last_attempt_at,
+        },
+        headers: row.headers,
+        payload: row.payload,
+    })
 }
 
-pub async fn get_all_chirps_sorted_by_creation(
-    db: &PgPool,
-    sort_order: SortOrder,
-) -> Result<Vec<Chirp>, sqlx::Error> {
-    // The compile time checked `query_as!` macro does not accept dynamically determined sort order.
-    // Since the only user-provided input goes through SortOrder::fmt, which takes predetermined values, this is still safe.
-    let raw_sql = format!(
-        "
-SELECT * FROM chirps
-ORDER BY created_at {sort_order}
-"
-    );
+/// Records a domain event as part of the caller's transaction, so that the event is published if and only if the change is committed.
+pub async fn record_outbox_event(
+    conn: &mut PgConnection,
+    event: &DomainEvent,
+) -> Result<(), sqlx::Error> {
+    let payload = serde_json::to_string(event).expect("Domain events serialize to JSON");
+    sqlx::query!(
+        r#"
+INSERT INTO outbox (event_id, event_type, payload, created_at, next_attempt_at)
+VALUES (gen_random_uuid(), $1, $2, NOW(), NOW())
+"#,
+        event.event_type(),
+        payload
+    )
+    .execute(conn)
+    .await
+    .map(|_| ())
+}
 
-    sqlx::query_as(&raw_sql).fetch_all(db).await
+pub struct PendingOutboxEvent {
+    pub event_id: Uuid,
+    pub payload: String,
+    pub created_at: OffsetDateTime,
+    pub attempts: i32,
 }
 
-pub async fn get_all_chirps_by_author_sorted_by_creation(
-    db: &PgPool,
-    author_id: Uuid,
-    sort_order: SortOrder,
-) -> Result<Vec<Chirp>, sqlx::Error> {
-    // The compile time checked `query_as!` macro does not accept dynamically determined sort order.
-    // Since the only user-provided inputs go through Uuid::to_string SortOrder::to_string, which only take safe values, this is still safe.
-    let raw_sql = format!(
-        "
-SELECT * FROM chirps
-WHERE user_id = {author_id}
-ORDER BY created_at {sort_order}
-"
-    );
+/// Locks the oldest events that are due for dispatch until the transaction ends. Events locked by another dispatcher are skipped.
+pub async fn lock_pending_outbox_events(
+    conn: &mut PgConnection,
+    limit: i64,
+) -> Result<Vec<PendingOutboxEvent>, sqlx::Error> {
+    sqlx::query_as!(
+        PendingOutboxEvent,
+        r#"
+SELECT event_id, payload, created_at, attempts FROM outbox
+WHERE dispatched_at IS NULL AND next_attempt_at <= NOW()
+ORDER BY created_at, event_id
+LIMIT $1
+FOR UPDATE SKIP LOCKED
+"#,
+        limit
+    )
+    .fetch_all(conn)
+    .await
+}
 
-    sqlx::query_as(&raw_sql).fetch_all(db).await
+pub async fn mark_outbox_event_dispatched(
+    conn: &mut PgConnection,
+    event_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+UPDATE outbox SET dispatched_at = NOW(), attempts = attempts + 1, last_error = NULL
+WHERE event_id = $1
+"#,
+        event_id
+    )
+    .execute(conn)
+    .await
+    .map(|_| ())
 }
 
-pub async fn get_chirp(db: PgPool, chirp_id: Uuid) -> Result<Chirp, sqlx::Error> {
-    sqlx::query_as!(
-        Chirp,
+pub async fn fail_outbox_event(
+    conn: &mut PgConnection,
+    event_id: Uuid,
+    error: &str,
+    retry_at: OffsetDateTime,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
         r#"
-SELECT chirp_id, user_id, created_at, updated_at, body as "body: _" FROM chirps
-WHERE chirp_id = $1
+UPDATE outbox SET attempts = attempts + 1, last_error = $2, next_attempt_at = $3
+WHERE event_id = $1
 "#,
-        chirp_id
+        event_id,
+        error,
+        retry_at
     )
-    .fetch_one(&db)
+    .execute(conn)
     .await
+    .map(|_| ())
 }
-pub async fn delete_chirp_if_author(
-    db: &PgPool,
-    chirp_id: &Uuid,
-    user_id: &Uuid,
-) -> Result<Chirp, sqlx::Error> {
+
+#[derive(Serialize, Debug)]
+pub struct OutboxStats {
+    pub pending: i64,
+    /// Pending events that have failed at least once.
+    pub failing: i64,
+    pub oldest_pending_at: Option<OffsetDateTime>,
+    pub last_dispatched_at: Option<OffsetDateTime>,
+}
+
+pub async fn get_outbox_stats(db: &PgPool) -> Result<OutboxStats, sqlx::Error> {
     sqlx::query_as!(
-        Chirp,
+        OutboxStats,
         r#"
-DELETE FROM chirps
-WHERE chirp_id = $1 AND user_id = $2
-RETURNING chirp_id, user_id, created_at, updated_at, body as "body: _"
-"#,
-        chirp_id,
-        user_id
+SELECT
+    COUNT(*) FILTER (WHERE dispatched_at IS NULL) as "pending!",
+    COUNT(*) FILTER (WHERE dispatched_at IS NULL AND attempts > 0) as "failing!",
+    MIN(created_at) FILTER (WHERE dispatched_at IS NULL) as oldest_pending_at,
+    MAX(dispatched_at) as last_dispatched_at
+FROM outbox
+"#
     )
     .fetch_one(db)
     .await
 }
-/// Take `platform` as input to safeguard against accidental deletion.
-/// WARNING: The caller should never call this function with anything other than Platform::Dev, but because of how dangerous this endpoint is, we add an additional safeguard here.
-/// Returns the number of deleted rows as result if successful.
-pub async fn delete_all_users(db: PgPool, platform: Platform) -> Result<u64, sqlx::Error> {
-    assert_eq!(platform, Platform::Dev);
+
+/// Deletes events that were dispatched more than `retention` ago.
+pub async fn purge_dispatched_outbox_events(
+    db: &PgPool,
+    retention: Duration,
+) -> Result<u64, sqlx::Error> {
     sqlx::query!(
         r#"
-        DELETE FROM users
-        "#
+DELETE FROM outbox WHERE dispatched_at < NOW() - $1::interval
+"#,
+        retention as Duration
     )
-    .execute(&db)
+    .execute(db)
     .await
-    .map(|ok| ok.rows_affected())
+    .map(|result| result.rows_affected())
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
-pub struct RefreshTokenEntry {
-    pub token: String,
+#[derive(Serialize, Debug)]
+pub struct WebhookEndpoint {
+    pub endpoint_id: Uuid,
+    /// `None` for endpoints registered by admins, which receive events about all users.
+    pub owner_id: Option<Uuid>,
+    pub url: String,
+    pub events: Vec<String>,
     pub created_at: OffsetDateTime,
-    pub updated_at: OffsetDateTime,
-    pub user_id: Uuid,
-    pub expires_at: OffsetDateTime,
-    pub revoked_at: Option<OffsetDateTime>,
 }
 
-pub async fn new_refresh_token(db: &PgPool, user: &User) -> Result<RefreshTokenEntry, sqlx::Error> {
-    let refresh_token = make_refresh_token().await;
+pub async fn insert_webhook_endpoint(
+    db: &PgPool,
+    owner_id: Option<Uuid>,
+    url: &str,
+    secret: &str,
+    events: &[String],
+) -> Result<WebhookEndpoint, sqlx::Error> {
     sqlx::query_as!(
-        RefreshTokenEntry,
+        WebhookEndpoint,
         r#"
-INSERT INTO refresh_tokens(token, created_at, updated_at, user_id, expires_at, revoked_at)
-VALUES (
-$1,
-NOW(),
-NOW(),
-$2,
-NOW() + INTERVAL '60 days',
-NULL
-) RETURNING *
+INSERT INTO webhook_endpoints (endpoint_id, owner_id, url, secret, events, created_at)
+VALUES (gen_random_uuid(), $1, $2, $3, $4, NOW())
+RETURNING endpoint_id, owner_id, url, events, created_at
 "#,
-        refresh_token,
-        user.id
+        owner_id,
+        url,
+        secret,
+        events
     )
     .fetch_one(db)
     .await
 }
 
-pub async fn get_refresh_token_entry(
+pub async fn count_webhook_endpoints(db: &PgPool, owner_id: Uuid) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT COUNT(*) as "count!" FROM webhook_endpoints WHERE owner_id = $1
+"#,
+        owner_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// The endpoints registered by `owner_id`, or all endpoints if it is `None`.
+pub async fn get_webhook_endpoints(
     db: &PgPool,
-    token: &str,
-) -> Result<RefreshTokenEntry, sqlx::Error> {
+    owner_id: Option<Uuid>,
+) -> Result<Vec<WebhookEndpoint>, sqlx::Error> {
     sqlx::query_as!(
-        RefreshTokenEntry,
+        WebhookEndpoint,
         r#"
-SELECT * FROM refresh_tokens WHERE token = $1
+SELECT endpoint_id, owner_id, url, events, created_at FROM webhook_endpoints
+WHERE $1::UUID IS NULL OR owner_id = $1
+ORDER BY created_at
 "#,
-        token
+        owner_id
     )
-    .fetch_one(db)
+    .fetch_all(db)
     .await
 }
 
-pub async fn revoke_refresh_token(
+/// Fails with `RowNotFound` if the endpoint doesn't exist or, unless `owner_id` is `None`, belongs to someone else.
+pub async fn get_webhook_endpoint(
     db: &PgPool,
-    token: &str,
-) -> Result<RefreshTokenEntry, sqlx::Error> {
+    endpoint_id: Uuid,
+    owner_id: Option<Uuid>,
+) -> Result<WebhookEndpoint, sqlx::Error> {
     sqlx::query_as!(
-        RefreshTokenEntry,
-        r#"UPDATE refresh_tokens
-SET updated_at = NOW(), revoked_at = NOW()
-WHERE token = $1
-RETURNING *"#,
-        token
+        WebhookEndpoint,
+        r#"
+SELECT endpoint_id, owner_id, url, events, created_at FROM webhook_endpoints
+WHERE endpoint_id = $1 AND ($2::UUID IS NULL OR owner_id = $2)
+"#,
+        endpoint_id,
+        owner_id
     )
     .fetch_one(db)
     .await
 }
+
+/// Pending deliveries to the endpoint are dropped along with it.
+pub async fn delete_webhook_endpoint(
+    db: &PgPool,
+    endpoint_id: Uuid,
+    owner_id: Option<Uuid>,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+DELETE FROM webhook_endpoints
+WHERE endpoint_id = $1 AND ($2::UUID IS NULL OR owner_id = $2)
+"#,
+        endpoint_id,
+        owner_id
+    )
+    .execute(db)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "delivery_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Succeeded,
+    /// Every attempt failed.
+    Failed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WebhookDelivery {
+    pub delivery_id: Uuid,
+    pub event_id: Uuid,
+    pub event: String,
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: OffsetDateTime,
+    pub last_attempt_at: Option<OffsetDateTime>,
+    /// The status code of the last response, if the endpoint responded.
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub created_at: OffsetDateTime,
+    pub delivered_at: Option<OffsetDateTime>,
+}
+
+/// The most recent deliveries to an endpoint.
+pub async fn get_webhook_deliveries(
+    db: &PgPool,
+    endpoint_id: Uuid,
+    limit: i64,
+) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+    sqlx::query_as!(
+        WebhookDelivery,
+        r#"
+SELECT delivery_id, event_id, event, payload, status as "status: _", attempts, next_attempt_at, last_attempt_at, response_status, error, created_at, delivered_at
+FROM webhook_deliveries
+WHERE endpoint_id = $1
+ORDER BY created_at DESC
+LIMIT $2
+"#,
+        endpoint_id,
+        limit
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Queues a delivery of the event to every endpoint subscribed to it that may see events about `user_id`.
+pub async fn enqueue_webhook_deliveries(
+    db: &PgPool,
+    event_id: Uuid,
+    event: &str,
+    user_id: Uuid,
+    payload: &str,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+INSERT INTO webhook_deliveries (delivery_id, endpoint_id, event_id, event, payload, status, next_attempt_at, created_at)
+SELECT gen_random_uuid(), endpoint_id, $1, $2, $4, 'pending', NOW(), NOW()
+FROM webhook_endpoints
+WHERE $2 = ANY(events) AND (owner_id IS NULL OR owner_id = $3)
+ON CONFLICT (endpoint_id, event_id) DO NOTHING
+"#,
+        event_id,
+        event,
+        user_id,
+        payload
+    )
+    .execute(db)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+/// A delivery claimed by `claim_webhook_deliveries`, with what is needed to send it.
+#[derive(Debug)]
+pub struct PendingDelivery {
+    pub delivery_id: Uuid,
+    pub event: String,
+    pub payload: String,
+    /// Including the current attempt.
+    pub attempts: i32,
+    pub url: String,
+    pub secret: String,
+    /// The owner of the endpoint, `None` for endpoints registered by admins.
+    pub owner_id: Option<Uuid>,
+}
+
+/// Claims up to `limit` deliveries that are due, counting an attempt for each.
+/// Claimed deliveries are not due again until `lease` has passed, so a delivery abandoned by a crashed worker is retried after that.
+pub async fn claim_webhook_deliveries(
+    db: &PgPool,
+    lease: Duration,
+    limit: i64,
+) -> Result<Vec<PendingDelivery>, sqlx::Error> {
+    sqlx::query_as!(
+        PendingDelivery,
+        r#"
+UPDATE webhook_deliveries d
+SET attempts = d.attempts + 1, last_attempt_at = NOW(), next_attempt_at = NOW() + $1::interval
+FROM webhook_endpoints e
+WHERE e.endpoint_id = d.endpoint_id
+AND d.delivery_id IN (
+SELECT delivery_id FROM webhook_deliveries
+WHERE status = 'pending' AND next_attempt_at <= NOW()
+ORDER BY next_attempt_at
+LIMIT $2
+FOR UPDATE SKIP LOCKED
+)
+RETURNING d.delivery_id, d.event, d.payload, d.attempts, e.url, e.secret, e.owner_id
+"#,
+        lease as Duration,
+        limit
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn complete_webhook_delivery(
+    db: &PgPool,
+    delivery_id: Uuid,
+    response_status: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+UPDATE webhook_deliveries
+SET status = 'succeeded', response_status = $2, error = NULL, delivered_at = NOW()
+WHERE delivery_id = $1
+"#,
+        delivery_id,
+        response_status
+    )
+    .execute(db)
+    .await
+    .map(|_| ())
+}
+
+/// Schedules another attempt at `retry_at`, or gives up on the delivery if it is `None`.
+pub async fn fail_webhook_delivery(
+    db: &PgPool,
+    delivery_id: Uuid,
+    response_status: Option<i32>,
+    error: &str,
+    retry_at: Option<OffsetDateTime>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+UPDATE webhook_deliveries
+SET status = CASE WHEN $4::TIMESTAMPTZ IS NULL THEN 'failed' ELSE 'pending' END::delivery_status,
+    next_attempt_at = COALESCE($4, next_attempt_at),
+    response_status = $2,
+    error = $3
+WHERE delivery_id = $1
+"#,
+        delivery_id,
+        response_status,
+        error,
+        retry_at
+    )
+    .execute(db)
+    .await
+    .map(|_| ())
+}
+
+/// The publicly visible part of a `User`.
+#[derive(Serialize, Debug)]
+pub struct PublicProfile {
+    pub id: Uuid,
+    pub created_at: Option<OffsetDateTime>,
+    pub handle: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
+    pub is_chirpy_red: bool,
+}
+
+/// Handles are matched case-insensitively. Suspended and deleted users have no public profile.
+pub async fn get_public_profile(db: &PgPool, handle: &str) -> Result<PublicProfile, sqlx::Error> {
+    sqlx::query_as!(
+        PublicProfile,
+        r#"
+SELECT id, created_at, handle, display_name, bio, avatar_url, is_chirpy_red FROM users
+WHERE lower(handle) = lower($1) AND suspended_at IS NULL AND deleted_at IS NULL
+"#,
+        handle
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Following someone who is already followed does nothing.
+pub async fn follow_user(
+    db: &PgPool,
+    follower_id: Uuid,
+    followee_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let inserted = sqlx::query!(
+        r#"
+INSERT INTO follows (follower_id, followee_id, created_at) VALUES ($1, $2, NOW())
+ON CONFLICT DO NOTHING
+"#,
+        follower_id,
+        followee_id
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    // Following someone again is a no-op, and shouldn't notify them again.
+    if inserted > 0 {
+        record_outbox_event(
+            &mut tx,
+            &DomainEvent::UserFollowed {
+                follower_id,
+                followee_id,
+            },
+        )
+        .await?;
+    }
+    tx.commit().await
+}
+
+pub async fn unfollow_user(
+    db: &PgPool,
+    follower_id: Uuid,
+    followee_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+DELETE FROM follows WHERE follower_id = $1 AND followee_id = $2
+"#,
+        follower_id,
+        followee_id
+    )
+    .execute(db)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+/// Blocking someone also removes any follows between the two users.
+pub async fn block_user(
+    db: &PgPool,
+    blocker_id: Uuid,
+    blocked_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        r#"
+INSERT INTO blocks (blocker_id, blocked_id, created_at) VALUES ($1, $2, NOW())
+ON CONFLICT DO NOTHING
+"#,
+        blocker_id,
+        blocked_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+DELETE FROM follows
+WHERE (follower_id = $1 AND followee_id = $2) OR (follower_id = $2 AND followee_id = $1)
+"#,
+        blocker_id,
+        blocked_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+pub async fn unblock_user(
+    db: &PgPool,
+    blocker_id: Uuid,
+    blocked_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+DELETE FROM blocks WHERE blocker_id = $1 AND blocked_id = $2
+"#,
+        blocker_id,
+        blocked_id
+    )
+    .execute(db)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+/// Whether either user has blocked the other.
+pub async fn is_blocked_between(db: &PgPool, a: Uuid, b: Uuid) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT EXISTS (
+    SELECT 1 FROM blocks WHERE (blocker_id = $1 AND blocked_id = $2) OR (blocker_id = $2 AND blocked_id = $1)
+) as "exists!"
+"#,
+        a,
+        b
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn get_blocked_users(
+    db: &PgPool,
+    blocker_id: Uuid,
+) -> Result<Vec<AuthorSummary>, sqlx::Error> {
+    sqlx::query_as!(
+        AuthorSummary,
+        r#"
+SELECT id, handle, display_name, avatar_url FROM blocks
+JOIN users ON users.id = blocks.blocked_id
+WHERE blocker_id = $1
+ORDER BY blocks.created_at DESC
+"#,
+        blocker_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn mute_user(db: &PgPool, muter_id: Uuid, muted_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+INSERT INTO mutes (muter_id, muted_id, created_at) VALUES ($1, $2, NOW())
+ON CONFLICT DO NOTHING
+"#,
+        muter_id,
+        muted_id
+    )
+    .execute(db)
+    .await
+    .map(|_| ())
+}
+
+pub async fn unmute_user(db: &PgPool, muter_id: Uuid, muted_id: Uuid) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+DELETE FROM mutes WHERE muter_id = $1 AND muted_id = $2
+"#,
+        muter_id,
+        muted_id
+    )
+    .execute(db)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+pub async fn get_muted_users(
+    db: &PgPool,
+    muter_id: Uuid,
+) -> Result<Vec<AuthorSummary>, sqlx::Error> {
+    sqlx::query_as!(
+        AuthorSummary,
+        r#"
+SELECT id, handle, display_name, avatar_url FROM mutes
+JOIN users ON users.id = mutes.muted_id
+WHERE muter_id = $1
+ORDER BY mutes.created_at DESC
+"#,
+        muter_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Authors whose chirps are hidden from the user's timelines: those they muted and those they blocked.
+pub async fn get_hidden_author_ids(db: &PgPool, user_id: Uuid) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT muted_id as "id!" FROM mutes WHERE muter_id = $1
+UNION
+SELECT blocked_id FROM blocks WHERE blocker_id = $1
+"#,
+        user_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn get_followee_ids(db: &PgPool, follower_id: Uuid) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT followee_id FROM follows WHERE follower_id = $1
+"#,
+        follower_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Ids of the active users with the given handles. Handles are matched case-insensitively, and unknown handles are skipped.
+pub async fn get_user_ids_by_handles(
+    db: &PgPool,
+    handles: &[String],
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT id FROM users
+WHERE lower(handle) = ANY(SELECT lower(h) FROM UNNEST($1::text[]) AS h) AND suspended_at IS NULL AND deleted_at IS NULL
+"#,
+        handles
+    )
+    .fetch_all(db)
+    .await
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "notification_type", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationType {
+    /// Someone replied to one of the user's chirps.
+    Reply,
+    /// Someone mentioned the user by `@handle` in a chirp.
+    Mention,
+    /// Someone followed the user.
+    Follow,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Notification {
+    #[serde(rename = "id")]
+    pub notification_id: Uuid,
+    #[serde(rename = "type")]
+    pub notification_type: NotificationType,
+    pub actor_id: Uuid,
+    pub actor_handle: String,
+    pub chirp_id: Option<Uuid>,
+    pub created_at: OffsetDateTime,
+    pub read_at: Option<OffsetDateTime>,
+}
+
+/// Notifies each recipient of the event, except the actor themselves, recipients who muted this type of notification,
+/// and recipients who have blocked the actor or been blocked by them.
+/// Recipients already notified of the event are skipped, so handling an event twice notifies nobody twice.
+/// Nothing is inserted if the chirp has been deleted in the meantime.
+pub async fn insert_notifications(
+    db: &PgPool,
+    event_id: Uuid,
+    notification_type: NotificationType,
+    actor_id: Uuid,
+    chirp_id: Option<Uuid>,
+    recipient_ids: &[Uuid],
+) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+INSERT INTO notifications (notification_id, user_id, type, actor_id, chirp_id, event_id, created_at)
+SELECT gen_random_uuid(), recipient, $2, $3, $4, $1, NOW()
+FROM UNNEST($5::uuid[]) AS recipient
+WHERE recipient <> $3
+AND NOT EXISTS (SELECT 1 FROM notification_mutes WHERE user_id = recipient AND type = $2)
+AND NOT EXISTS (
+    SELECT 1 FROM blocks WHERE (blocker_id = recipient AND blocked_id = $3) OR (blocker_id = $3 AND blocked_id = recipient)
+)
+AND ($4::uuid IS NULL OR EXISTS (SELECT 1 FROM chirps WHERE chirp_id = $4 AND (expires_at IS NULL OR expires_at > NOW())))
+ON CONFLICT (user_id, event_id) DO NOTHING
+"#,
+        event_id,
+        notification_type as NotificationType,
+        actor_id,
+        chirp_id,
+        recipient_ids
+    )
+    .execute(db)
+    .await
+    .map(|r| r.rows_affected())
+}
+
+/// Newest first. Notifications from suspended or deleted users, or about chirps that have expired, are left out.
+pub async fn get_notifications(
+    db: &PgPool,
+    user_id: Uuid,
+    unread_only: bool,
+    limit: i64,
+) -> Result<Vec<Notification>, sqlx::Error> {
+    sqlx::query_as!(
+        Notification,
+        r#"
+SELECT notification_id, type as "notification_type: _", actor_id, users.handle as actor_handle, notifications.chirp_id, notifications.created_at, read_at
+FROM notifications
+JOIN users ON users.id = notifications.actor_id
+LEFT JOIN chirps ON chirps.chirp_id = notifications.chirp_id
+WHERE notifications.user_id = $1 AND (NOT $2 OR read_at IS NULL) AND users.suspended_at IS NULL AND users.deleted_at IS NULL
+AND (chirps.expires_at IS NULL OR chirps.expires_at > NOW())
+ORDER BY notifications.created_at DESC
+LIMIT $3
+"#,
+        user_id,
+        unread_only,
+        limit
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn count_unread_notifications(db: &PgPool, user_id: Uuid) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT COUNT(*) as "count!" FROM notifications
+JOIN users ON users.id = notifications.actor_id
+LEFT JOIN chirps ON chirps.chirp_id = notifications.chirp_id
+WHERE notifications.user_id = $1 AND read_at IS NULL AND users.suspended_at IS NULL AND users.deleted_at IS NULL
+AND (chirps.expires_at IS NULL OR chirps.expires_at > NOW())
+"#,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Marks the given notifications as read, or all of them if `notification_ids` is `None`.
+/// Returns the number of notifications matched. Notifications that were already read keep their original `read_at`.
+pub async fn mark_notifications_read(
+    db: &PgPool,
+    user_id: Uuid,
+    notification_ids: Option<&[Uuid]>,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+UPDATE notifications SET read_at = COALESCE(read_at, NOW())
+WHERE user_id = $1 AND (notification_id = ANY($2) OR ($2 IS NULL AND read_at IS NULL))
+"#,
+        user_id,
+        notification_ids
+    )
+    .execute(db)
+    .await
+    .map(|r| r.rows_affected())
+}
+
+pub async fn get_notification_mutes(
+    db: &PgPool,
+    user_id: Uuid,
+) -> Result<Vec<NotificationType>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT type as "type: NotificationType" FROM notification_mutes WHERE user_id = $1 ORDER BY type
+"#,
+        user_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Replaces the user's mutes with the given ones.
+pub async fn set_notification_mutes(
+    db: &PgPool,
+    user_id: Uuid,
+    muted: &[NotificationType],
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        r#"
+DELETE FROM notification_mutes WHERE user_id = $1
+"#,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    for &notification_type in muted {
+        sqlx::query!(
+            r#"
+INSERT INTO notification_mutes (user_id, type) VALUES ($1, $2)
+ON CONFLICT DO NOTHING
+"#,
+            user_id,
+            notification_type as NotificationType
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+#[derive(Serialize, Debug)]
+pub struct Message {
+    #[serde(rename = "id")]
+    pub message_id: Uuid,
+    pub conversation_id: Uuid,
+    pub sender_id: Uuid,
+    pub body: String,
+    pub created_at: OffsetDateTime,
+    pub read_at: Option<OffsetDateTime>,
+}
+
+/// Sends a message, starting a conversation between the two users if they don't have one yet.
+pub async fn send_direct_message(
+    db: &PgPool,
+    sender_id: Uuid,
+    recipient_id: Uuid,
+    body: &str,
+) -> Result<Message, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    // Updating the existing row on conflict both returns its id and locks it until the message is in.
+    let conversation_id = sqlx::query_scalar!(
+        r#"
+INSERT INTO conversations (conversation_id, user_a, user_b, created_at, last_message_at)
+VALUES (gen_random_uuid(), LEAST($1::uuid, $2::uuid), GREATEST($1::uuid, $2::uuid), NOW(), NOW())
+ON CONFLICT (user_a, user_b) DO UPDATE SET last_message_at = NOW()
+RETURNING conversation_id
+"#,
+        sender_id,
+        recipient_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let message = sqlx::query_as!(
+        Message,
+        r#"
+INSERT INTO messages (message_id, conversation_id, sender_id, body, created_at)
+VALUES (gen_random_uuid(), $1, $2, $3, NOW())
+RETURNING message_id, conversation_id, sender_id, body, created_at, read_at
+"#,
+        conversation_id,
+        sender_id,
+        body
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(message)
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConversationSummary {
+    #[serde(rename = "id")]
+    pub conversation_id: Uuid,
+    /// The other participant.
+    pub with: AuthorSummary,
+    pub created_at: OffsetDateTime,
+    pub last_message_at: OffsetDateTime,
+    pub last_message: Option<Message>,
+    /// Messages from the other participant that the user hasn't read.
+    pub unread_count: i64,
+}
+
+/// The user's conversations, most recently active first. Conversations with suspended or deleted users are left out.
+pub async fn get_conversations(
+    db: &PgPool,
+    user_id: Uuid,
+    limit: i64,
+) -> Result<Vec<ConversationSummary>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+SELECT c.conversation_id, c.created_at, c.last_message_at,
+users.id, users.handle, users.display_name, users.avatar_url,
+last.message_id as "last_message_id?", last.sender_id as "last_sender_id?", last.body as "last_body?",
+last.created_at as "last_created_at?", last.read_at as last_read_at,
+(SELECT COUNT(*) FROM messages WHERE conversation_id = c.conversation_id AND sender_id <> $1 AND read_at IS NULL) as "unread_count!"
+FROM conversations c
+JOIN users ON users.id = CASE WHEN c.user_a = $1 THEN c.user_b ELSE c.user_a END
+LEFT JOIN LATERAL (
+    SELECT message_id, sender_id, body, created_at, read_at FROM messages
+    WHERE conversation_id = c.conversation_id
+    ORDER BY created_at DESC, message_id DESC
+    LIMIT 1
+) last ON TRUE
+WHERE (c.user_a = $1 OR c.user_b = $1) AND users.suspended_at IS NULL AND users.deleted_at IS NULL
+ORDER BY c.last_message_at DESC
+LIMIT $2
+"#,
+        user_id,
+        limit
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| ConversationSummary {
+            conversation_id: row.conversation_id,
+            with: AuthorSummary {
+                id: row.id,
+                handle: row.handle,
+                display_name: row.display_name,
+                avatar_url: row.avatar_url,
+            },
+            created_at: row.created_at,
+            last_message_at: row.last_message_at,
+            last_message: match (
+                row.last_message_id,
+                row.last_sender_id,
+                row.last_body,
+                row.last_created_at,
+            ) {
+                (Some(message_id), Some(sender_id), Some(body), Some(created_at)) => {
+                    Some(Message {
+                        message_id,
+                        conversation_id: row.conversation_id,
+                        sender_id,
+                        body,
+                        created_at,
+                        read_at: row.last_read_at,
+                    })
+                }
+                _ => None,
+            },
+            unread_count: row.unread_count,
+        })
+        .collect())
+}
+
+pub async fn is_conversation_participant(
+    db: &PgPool,
+    conversation_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT EXISTS (
+    SELECT 1 FROM conversations WHERE conversation_id = $1 AND (user_a = $2 OR user_b = $2)
+) as "exists!"
+"#,
+        conversation_id,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Newest first. With `before`, only messages older than that message are returned, so clients can page back through the history.
+pub async fn get_messages(
+    db: &PgPool,
+    conversation_id: Uuid,
+    before: Option<Uuid>,
+    limit: i64,
+) -> Result<Vec<Message>, sqlx::Error> {
+    sqlx::query_as!(
+        Message,
+        r#"
+SELECT message_id, conversation_id, sender_id, body, created_at, read_at FROM messages
+WHERE conversation_id = $1
+AND ($2::uuid IS NULL OR (created_at, message_id) < (SELECT created_at, message_id FROM messages WHERE message_id = $2 AND conversation_id = $1))
+ORDER BY created_at DESC, message_id DESC
+LIMIT $3
+"#,
+        conversation_id,
+        before,
+        limit
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Marks every message the reader has received in the conversation as read. Returns the number of messages marked.
+pub async fn mark_conversation_read(
+    db: &PgPool,
+    conversation_id: Uuid,
+    reader_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+UPDATE messages SET read_at = NOW()
+WHERE conversation_id = $1 AND sender_id <> $2 AND read_at IS NULL
+"#,
+        conversation_id,
+        reader_id
+    )
+    .execute(db)
+    .await
+    .map(|r| r.rows_affected())
+}
+
+/// Fields left as `None` are not changed. Empty strings clear the optional fields.
+pub struct ProfileUpdate<'a> {
+    pub handle: Option<&'a str>,
+    pub display_name: Option<&'a str>,
+    pub bio: Option<&'a str>,
+    pub avatar_url: Option<&'a str>,
+}
+
+pub async fn update_profile(
+    db: &PgPool,
+    user_id: Uuid,
+    update: ProfileUpdate<'_>,
+) -> Result<User, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"
+UPDATE users
+SET handle = COALESCE($2, handle),
+display_name = CASE WHEN $3::text IS NULL THEN display_name ELSE NULLIF($3, '') END,
+bio = CASE WHEN $4::text IS NULL THEN bio ELSE NULLIF($4, '') END,
+avatar_url = CASE WHEN $5::text IS NULL THEN avatar_url ELSE NULLIF($5, '') END,
+updated_at = NOW()
+WHERE id = $1
+RETURNING *
+"#,
+        user_id,
+        update.handle,
+        update.display_name,
+        update.bio,
+        update.avatar_url
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// A lightweight summary of a chirp's author, for embedding in chirp responses.
+#[derive(Serialize, Debug, Clone)]
+pub struct AuthorSummary {
+    pub id: Uuid,
+    pub handle: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+/// Fetches the summaries of all given authors in a single query.
+pub async fn get_author_summaries(
+    db: &PgPool,
+    user_ids: &[Uuid],
+) -> Result<Vec<AuthorSummary>, sqlx::Error> {
+    sqlx::query_as!(
+        AuthorSummary,
+        r#"
+SELECT id, handle, display_name, avatar_url FROM users
+WHERE id = ANY($1)
+"#,
+        user_ids
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Marks the account for deletion and revokes all of its refresh tokens. The account is purged by `purge_deleted_users` once the grace period has passed.
+pub async fn schedule_user_deletion(db: &PgPool, user_id: Uuid) -> Result<User, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let user = sqlx::query_as!(
+        User,
+        r#"
+UPDATE users
+SET deleted_at = NOW()
+WHERE id = $1 AND deleted_at IS NULL
+RETURNING *
+"#,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+UPDATE refresh_tokens
+SET updated_at = NOW(), revoked_at = NOW()
+WHERE user_id = $1 AND revoked_at IS NULL
+"#,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(user)
+}
+
+pub async fn cancel_user_deletion(db: &PgPool, user_id: Uuid) -> Result<User, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"
+UPDATE users
+SET deleted_at = NULL
+WHERE id = $1
+RETURNING *
+"#,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Chirps, refresh tokens and other data owned by the user are removed by `ON DELETE CASCADE`.
+pub async fn delete_user(db: &PgPool, user_id: Uuid) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+DELETE FROM users
+WHERE id = $1
+"#,
+        user_id
+    )
+    .execute(db)
+    .await
+    .map(|ok| ok.rows_affected())
+}
+
+/// Returns the number of purged accounts.
+pub async fn purge_deleted_users(db: &PgPool, grace_period: Duration) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+DELETE FROM users
+WHERE deleted_at < NOW() - $1::interval
+"#,
+        grace_period as Duration
+    )
+    .execute(db)
+    .await
+    .map(|ok| ok.rows_affected())
+}
+
+/// All chirps by the user, including hidden ones, for data exports. Expired chirps are gone even if they haven't been purged yet.
+pub fn stream_chirps_for_export(
+    db: &PgPool,
+    user_id: Uuid,
+) -> impl Stream<Item = Result<Chirp, sqlx::Error>> + Send + '_ {
+    sqlx::query_as!(
+        Chirp,
+        r#"
+SELECT chirp_id, user_id, created_at, updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at FROM chirps
+WHERE user_id = $1 AND (expires_at IS NULL OR expires_at > NOW())
+ORDER BY created_at ASC
+"#,
+        user_id
+    )
+    .fetch(db)
+}
+
+/// A refresh token without the token itself, so that it can be shown to the user.
+#[derive(Serialize, Debug)]
+pub struct Session {
+    pub created_at: OffsetDateTime,
+    pub expires_at: OffsetDateTime,
+    pub revoked_at: Option<OffsetDateTime>,
+}
+
+pub async fn get_user_sessions(db: &PgPool, user_id: Uuid) -> Result<Vec<Session>, sqlx::Error> {
+    sqlx::query_as!(
+        Session,
+        r#"
+SELECT created_at, expires_at, revoked_at FROM refresh_tokens
+WHERE user_id = $1
+ORDER BY created_at ASC
+"#,
+        user_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Roles are ordered by privilege, so a role implies every role below it.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, sqlx::Type,
+)]
+#[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+/// Returns the most privileged role held by the user. Every user implicitly holds `Role::User`.
+pub async fn get_user_role(db: &PgPool, user_id: Uuid) -> Result<Role, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT MAX(role) as "role: Role" FROM user_roles
+WHERE user_id = $1
+"#,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+    .map(|role| role.unwrap_or(Role::User))
+}
+
+pub async fn get_user_roles(db: &PgPool, user_id: Uuid) -> Result<Vec<Role>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT role as "role: Role" FROM user_roles
+WHERE user_id = $1
+ORDER BY role
+"#,
+        user_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn grant_role(db: &PgPool, user_id: Uuid, role: Role) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+INSERT INTO user_roles(user_id, role, granted_at)
+VALUES ($1, $2, NOW())
+ON CONFLICT DO NOTHING
+"#,
+        user_id,
+        role as Role
+    )
+    .execute(db)
+    .await
+    .map(|_| ())
+}
+
+/// Returns the number of deleted rows, i.e. 0 if the user did not hold the role.
+pub async fn revoke_role(db: &PgPool, user_id: Uuid, role: Role) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+DELETE FROM user_roles
+WHERE user_id = $1 AND role = $2
+"#,
+        user_id,
+        role as Role
+    )
+    .execute(db)
+    .await
+    .map(|ok| ok.rows_affected())
+}
+
+pub async fn count_users_with_role(db: &PgPool, role: Role) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT COUNT(*) as "count!" FROM user_roles
+WHERE role = $1
+"#,
+        role as Role
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Inserts the chirp together with its media attachments, in the order given. Chirps with `expires_in` expire that many seconds from now.
+/// The caller is responsible for checking that the media belongs to the author, see `count_owned_media`.
+pub async fn insert_chirp(
+    db: PgPool,
+    body: ChirpBody,
+    user_id: Uuid,
+    media_ids: &[Uuid],
+    in_reply_to: Option<Uuid>,
+    quote_of: Option<Uuid>,
+    expires_in: Option<i32>,
+) -> Result<Chirp, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let chirp = create_chirp(
+        &mut tx,
+        &body,
+        user_id,
+        media_ids,
+        in_reply_to,
+        quote_of,
+        expires_in,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(chirp)
+}
+
+/// Like `insert_chirp`, within the caller's transaction. The body must already be validated.
+async fn create_chirp(
+    conn: &mut PgConnection,
+    body: &str,
+    user_id: Uuid,
+    media_ids: &[Uuid],
+    in_reply_to: Option<Uuid>,
+    quote_of: Option<Uuid>,
+    expires_in: Option<i32>,
+) -> Result<Chirp, sqlx::Error> {
+    let chirp = sqlx::query_as!(
+        Chirp,
+        r#"
+        INSERT INTO chirps(chirp_id, user_id, created_at, updated_at, body, in_reply_to, quote_of, expires_at)
+        VALUES (
+        gen_random_uuid(),
+        $1,
+        NOW(),
+        NOW(),
+        $2,
+        $3,
+        $4,
+        NOW() + $5::integer * INTERVAL '1 second'
+        )
+        RETURNING chirp_id, user_id, created_at, updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at
+        "#,
+        user_id,
+        body,
+        in_reply_to,
+        quote_of,
+        expires_in
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    sqlx::query!(
+        r#"
+INSERT INTO chirp_media(chirp_id, media_id, position)
+SELECT $1, media_id, position FROM UNNEST($2::uuid[]) WITH ORDINALITY AS t(media_id, position)
+"#,
+        chirp.chirp_id,
+        media_ids
+    )
+    .execute(&mut *conn)
+    .await?;
+    record_outbox_event(
+        conn,
+        &DomainEvent::ChirpCreated {
+            chirp: ChirpSnapshot::from(&chirp),
+        },
+    )
+    .await?;
+    Ok(chirp)
+}
+
+#[derive(Serialize, Debug)]
+pub struct Draft {
+    #[serde(rename = "id")]
+    pub draft_id: Uuid,
+    pub user_id: Uuid,
+    pub body: String,
+    pub media_ids: Vec<Uuid>,
+    pub in_reply_to: Option<Uuid>,
+    pub quote_of: Option<Uuid>,
+    /// When the chirp is published. Drafts without one are kept until the user publishes them.
+    pub publish_at: Option<OffsetDateTime>,
+    /// Seconds from publishing until the chirp expires.
+    pub expires_in: Option<i32>,
+    pub created_at: OffsetDateTime,
+    pub updated_at: OffsetDateTime,
+    pub last_error: Option<String>,
+}
+
+/// The content of a draft. The caller is responsible for validating it like a new chirp.
+pub struct DraftContent<'a> {
+    pub body: &'a str,
+    pub media_ids: &'a [Uuid],
+    pub in_reply_to: Option<Uuid>,
+    pub quote_of: Option<Uuid>,
+    pub publish_at: Option<OffsetDateTime>,
+    pub expires_in: Option<i32>,
+}
+
+pub async fn insert_draft(
+    db: &PgPool,
+    user_id: Uuid,
+    content: DraftContent<'_>,
+) -> Result<Draft, sqlx::Error> {
+    sqlx::query_as!(
+        Draft,
+        r#"
+INSERT INTO chirp_drafts (draft_id, user_id, body, media_ids, in_reply_to, quote_of, publish_at, expires_in, created_at, updated_at)
+VALUES (gen_random_uuid(), $1, $2, $3, $4, $5, $6, $7, NOW(), NOW())
+RETURNING *
+"#,
+        user_id,
+        content.body,
+        content.media_ids,
+        content.in_reply_to,
+        content.quote_of,
+        content.publish_at,
+        content.expires_in
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Replaces the content of the user's draft. Clears the error of a previous failed attempt to publish it.
+pub async fn update_draft(
+    db: &PgPool,
+    draft_id: Uuid,
+    user_id: Uuid,
+    content: DraftContent<'_>,
+) -> Result<Draft, sqlx::Error> {
+    sqlx::query_as!(
+        Draft,
+        r#"
+UPDATE chirp_drafts
+SET body = $3, media_ids = $4, in_reply_to = $5, quote_of = $6, publish_at = $7, expires_in = $8, updated_at = NOW(), last_error = NULL
+WHERE draft_id = $1 AND user_id = $2
+RETURNING *
+"#,
+        draft_id,
+        user_id,
+        content.body,
+        content.media_ids,
+        content.in_reply_to,
+        content.quote_of,
+        content.publish_at,
+        content.expires_in
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn count_drafts(db: &PgPool, user_id: Uuid) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT COUNT(*) as "count!" FROM chirp_drafts WHERE user_id = $1
+"#,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// With `scheduled`, only scheduled chirps or only unscheduled drafts. Scheduled chirps come first, soonest first, followed by the most recently edited drafts.
+pub async fn get_drafts(
+    db: &PgPool,
+    user_id: Uuid,
+    scheduled: Option<bool>,
+) -> Result<Vec<Draft>, sqlx::Error> {
+    sqlx::query_as!(
+        Draft,
+        r#"
+SELECT * FROM chirp_drafts
+WHERE user_id = $1 AND ($2::bool IS NULL OR $2 = (publish_at IS NOT NULL))
+ORDER BY publish_at ASC NULLS LAST, updated_at DESC
+"#,
+        user_id,
+        scheduled
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn get_draft(db: &PgPool, draft_id: Uuid, user_id: Uuid) -> Result<Draft, sqlx::Error> {
+    sqlx::query_as!(
+        Draft,
+        r#"
+SELECT * FROM chirp_drafts WHERE draft_id = $1 AND user_id = $2
+"#,
+        draft_id,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn delete_draft(db: &PgPool, draft_id: Uuid, user_id: Uuid) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+DELETE FROM chirp_drafts WHERE draft_id = $1 AND user_id = $2
+"#,
+        draft_id,
+        user_id
+    )
+    .execute(db)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+/// Locks the user's draft until the transaction ends, waiting for the scheduler if it is publishing the draft right now.
+pub async fn lock_draft(
+    conn: &mut PgConnection,
+    draft_id: Uuid,
+    user_id: Uuid,
+) -> Result<Draft, sqlx::Error> {
+    sqlx::query_as!(
+        Draft,
+        r#"
+SELECT * FROM chirp_drafts WHERE draft_id = $1 AND user_id = $2
+FOR UPDATE
+"#,
+        draft_id,
+        user_id
+    )
+    .fetch_one(conn)
+    .await
+}
+
+/// Locks scheduled chirps that are due until the transaction ends. Drafts locked by another transaction are skipped, so several schedulers can run side by side.
+pub async fn lock_due_drafts(
+    conn: &mut PgConnection,
+    limit: i64,
+) -> Result<Vec<Draft>, sqlx::Error> {
+    sqlx::query_as!(
+        Draft,
+        r#"
+SELECT * FROM chirp_drafts
+WHERE publish_at <= NOW()
+ORDER BY publish_at
+LIMIT $1
+FOR UPDATE SKIP LOCKED
+"#,
+        limit
+    )
+    .fetch_all(conn)
+    .await
+}
+
+/// Publishes a draft locked by the caller's transaction, so that the chirp is inserted and the draft deleted together.
+/// `in_reply_to` is the draft's parent as checked by the caller. If it has been deleted or has expired since, the chirp is published without it.
+pub async fn publish_draft(
+    conn: &mut PgConnection,
+    draft: &Draft,
+    in_reply_to: Option<Uuid>,
+) -> Result<Chirp, sqlx::Error> {
+    let in_reply_to = match in_reply_to {
+        Some(parent_id) => {
+            sqlx::query_scalar!(
+                r#"
+SELECT chirp_id FROM chirps WHERE chirp_id = $1 AND (expires_at IS NULL OR expires_at > NOW())
+"#,
+                parent_id
+            )
+            .fetch_optional(&mut *conn)
+            .await?
+        }
+        None => None,
+    };
+    let chirp = create_chirp(
+        conn,
+        &draft.body,
+        draft.user_id,
+        &draft.media_ids,
+        in_reply_to,
+        draft.quote_of,
+        draft.expires_in,
+    )
+    .await?;
+    sqlx::query!(
+        r#"
+DELETE FROM chirp_drafts WHERE draft_id = $1
+"#,
+        draft.draft_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(chirp)
+}
+
+/// Turns a scheduled chirp that failed to publish back into a draft, so it isn't retried until the user reschedules it.
+pub async fn unschedule_draft(
+    conn: &mut PgConnection,
+    draft_id: Uuid,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+UPDATE chirp_drafts SET publish_at = NULL, last_error = $2 WHERE draft_id = $1
+"#,
+        draft_id,
+        error
+    )
+    .execute(conn)
+    .await
+    .map(|_| ())
+}
+
+/// Reposts the chirp as the user. Returns `None` if the user has already reposted it.
+/// The caller is responsible for checking that the original is visible and isn't itself a repost.
+pub async fn insert_repost(
+    db: &PgPool,
+    user_id: Uuid,
+    original_id: Uuid,
+) -> Result<Option<Chirp>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let Some(chirp) = sqlx::query_as!(
+        Chirp,
+        r#"
+INSERT INTO chirps (chirp_id, user_id, created_at, updated_at, body, repost_of)
+VALUES (gen_random_uuid(), $1, NOW(), NOW(), '', $2)
+ON CONFLICT (repost_of, user_id) DO NOTHING
+RETURNING chirp_id, user_id, created_at, updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at
+"#,
+        user_id,
+        original_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+    record_outbox_event(
+        &mut tx,
+        &DomainEvent::ChirpCreated {
+            chirp: ChirpSnapshot::from(&chirp),
+        },
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(Some(chirp))
+}
+
+/// Undoes the user's repost of the chirp, returning the deleted repost.
+pub async fn delete_repost(
+    db: &PgPool,
+    user_id: Uuid,
+    original_id: Uuid,
+) -> Result<Chirp, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let chirp = sqlx::query_as!(
+        Chirp,
+        r#"
+DELETE FROM chirps WHERE repost_of = $1 AND user_id = $2
+RETURNING chirp_id, user_id, created_at, updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at
+"#,
+        original_id,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    record_outbox_event(
+        &mut tx,
+        &DomainEvent::ChirpDeleted {
+            chirp: ChirpSnapshot::from(&chirp),
+        },
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(chirp)
+}
+
+/// The chirps among the given ones that are visible. Chirps that are hidden, deleted or by suspended users are left out.
+pub async fn get_visible_chirps(
+    db: &PgPool,
+    chirp_ids: &[Uuid],
+) -> Result<Vec<Chirp>, sqlx::Error> {
+    sqlx::query_as!(
+        Chirp,
+        r#"
+SELECT chirp_id, user_id, chirps.created_at, chirps.updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at FROM chirps
+JOIN users ON users.id = chirps.user_id
+WHERE chirp_id = ANY($1) AND chirps.hidden_at IS NULL AND users.suspended_at IS NULL AND users.deleted_at IS NULL AND (chirps.expires_at IS NULL OR chirps.expires_at > NOW())
+"#,
+        chirp_ids
+    )
+    .fetch_all(db)
+    .await
+}
+
+#[derive(Debug)]
+pub struct RepostCounts {
+    pub chirp_id: Uuid,
+    pub reposts: i64,
+    pub quotes: i64,
+}
+
+/// Visible reposts and quotes of each of the given chirps. Chirps without any are left out.
+pub async fn get_repost_counts(
+    db: &PgPool,
+    chirp_ids: &[Uuid],
+) -> Result<Vec<RepostCounts>, sqlx::Error> {
+    sqlx::query_as!(
+        RepostCounts,
+        r#"
+SELECT COALESCE(repost_of, quote_of) as "chirp_id!",
+COUNT(*) FILTER (WHERE repost_of IS NOT NULL) as "reposts!",
+COUNT(*) FILTER (WHERE quote_of IS NOT NULL) as "quotes!"
+FROM chirps
+JOIN users ON users.id = chirps.user_id
+WHERE (repost_of = ANY($1) OR quote_of = ANY($1))
+AND chirps.hidden_at IS NULL AND users.suspended_at IS NULL AND users.deleted_at IS NULL AND (chirps.expires_at IS NULL OR chirps.expires_at > NOW())
+GROUP BY COALESCE(repost_of, quote_of)
+"#,
+        chirp_ids
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl Display for SortOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                SortOrder::Asc => "ASC",
+                SortOrder::Desc => "DESC",
+            }
+        )
+    }
+}
+
+/// Visible chirps matching `filter`, a condition on `chirps` in which `$1` is bound to `arg`. Reposts of chirps that are no longer visible are left out.
+/// With a `viewer_id`, chirps by authors the viewer has muted or blocked are left out too.
+async fn get_visible_chirps_sorted_by_creation(
+    db: &PgPool,
+    filter: &'static str,
+    arg: Option<Uuid>,
+    viewer_id: Option<Uuid>,
+    sort_order: SortOrder,
+) -> Result<Vec<Chirp>, sqlx::Error> {
+    // The compile time checked `query_as!` macro does not accept dynamically determined sort order.
+    // The filter is always a fixed string, and the only user-provided input goes through SortOrder::fmt, which takes predetermined values, so this is still safe.
+    let raw_sql = format!(
+        "
+SELECT chirps.* FROM chirps
+JOIN users ON users.id = chirps.user_id
+WHERE chirps.hidden_at IS NULL AND users.suspended_at IS NULL AND users.deleted_at IS NULL AND (chirps.expires_at IS NULL OR chirps.expires_at > NOW())
+AND (chirps.repost_of IS NULL OR EXISTS (
+    SELECT 1 FROM chirps originals
+    JOIN users authors ON authors.id = originals.user_id
+    WHERE originals.chirp_id = chirps.repost_of AND originals.hidden_at IS NULL AND authors.suspended_at IS NULL AND authors.deleted_at IS NULL AND (originals.expires_at IS NULL OR originals.expires_at > NOW())
+))
+AND ($2::uuid IS NULL OR NOT EXISTS (
+    SELECT 1 FROM mutes WHERE muter_id = $2 AND muted_id = chirps.user_id
+    UNION ALL
+    SELECT 1 FROM blocks WHERE blocker_id = $2 AND blocked_id = chirps.user_id
+))
+AND ({filter})
+ORDER BY chirps.created_at {sort_order}
+"
+    );
+
+    sqlx::query_as(&raw_sql)
+        .bind(arg)
+        .bind(viewer_id)
+        .fetch_all(db)
+        .await
+}
+
+/// With a `viewer_id`, chirps by authors the viewer has muted or blocked are left out.
+pub async fn get_all_chirps_sorted_by_creation(
+    db: &PgPool,
+    sort_order: SortOrder,
+    viewer_id: Option<Uuid>,
+) -> Result<Vec<Chirp>, sqlx::Error> {
+    get_visible_chirps_sorted_by_creation(db, "TRUE", None, viewer_id, sort_order).await
+}
+
+pub async fn get_all_chirps_by_author_sorted_by_creation(
+    db: &PgPool,
+    author_id: Uuid,
+    sort_order: SortOrder,
+) -> Result<Vec<Chirp>, sqlx::Error> {
+    get_visible_chirps_sorted_by_creation(
+        db,
+        "chirps.user_id = $1",
+        Some(author_id),
+        None,
+        sort_order,
+    )
+    .await
+}
+
+/// Chirps by the members of the list. With a `viewer_id`, chirps by authors the viewer has muted or blocked are left out.
+pub async fn get_all_chirps_by_list_sorted_by_creation(
+    db: &PgPool,
+    list_id: Uuid,
+    sort_order: SortOrder,
+    viewer_id: Option<Uuid>,
+) -> Result<Vec<Chirp>, sqlx::Error> {
+    get_visible_chirps_sorted_by_creation(
+        db,
+        "chirps.user_id IN (SELECT user_id FROM list_members WHERE list_id = $1)",
+        Some(list_id),
+        viewer_id,
+        sort_order,
+    )
+    .await
+}
+
+/// Bookmarking a chirp twice is a no-op.
+pub async fn add_bookmark(db: &PgPool, user_id: Uuid, chirp_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+INSERT INTO bookmarks (user_id, chirp_id, created_at) VALUES ($1, $2, NOW())
+ON CONFLICT DO NOTHING
+"#,
+        user_id,
+        chirp_id
+    )
+    .execute(db)
+    .await
+    .map(|_| ())
+}
+
+pub async fn remove_bookmark(
+    db: &PgPool,
+    user_id: Uuid,
+    chirp_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+DELETE FROM bookmarks WHERE user_id = $1 AND chirp_id = $2
+"#,
+        user_id,
+        chirp_id
+    )
+    .execute(db)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+/// The user's bookmarked chirps, most recently bookmarked first. Bookmarks of chirps that are no longer visible are left out, but kept in case the chirp becomes visible again.
+pub async fn get_bookmarked_chirps(db: &PgPool, user_id: Uuid) -> Result<Vec<Chirp>, sqlx::Error> {
+    sqlx::query_as!(
+        Chirp,
+        r#"
+SELECT chirps.chirp_id, chirps.user_id, chirps.created_at, chirps.updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at
+FROM bookmarks
+JOIN chirps ON chirps.chirp_id = bookmarks.chirp_id
+JOIN users ON users.id = chirps.user_id
+WHERE bookmarks.user_id = $1 AND chirps.hidden_at IS NULL AND users.suspended_at IS NULL AND users.deleted_at IS NULL AND (chirps.expires_at IS NULL OR chirps.expires_at > NOW())
+ORDER BY bookmarks.created_at DESC
+"#,
+        user_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "list_visibility", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ListVisibility {
+    /// Only the owner can see the list.
+    Private,
+    /// Anyone can see the list, its members and its chirps.
+    Public,
+}
+
+#[derive(Serialize, Debug)]
+pub struct List {
+    #[serde(rename = "id")]
+    pub list_id: Uuid,
+    pub owner_id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub visibility: ListVisibility,
+    pub created_at: OffsetDateTime,
+    pub updated_at: OffsetDateTime,
+}
+
+pub async fn insert_list(
+    db: &PgPool,
+    owner_id: Uuid,
+    name: &str,
+    description: Option<&str>,
+    visibility: ListVisibility,
+) -> Result<List, sqlx::Error> {
+    sqlx::query_as!(
+        List,
+        r#"
+INSERT INTO lists (list_id, owner_id, name, description, visibility, created_at, updated_at)
+VALUES (gen_random_uuid(), $1, $2, $3, $4, NOW(), NOW())
+RETURNING list_id, owner_id, name, description, visibility as "visibility: _", created_at, updated_at
+"#,
+        owner_id,
+        name,
+        description,
+        visibility as ListVisibility
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn count_lists(db: &PgPool, owner_id: Uuid) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT COUNT(*) as "count!" FROM lists WHERE owner_id = $1
+"#,
+        owner_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// With `public_only`, the owner's private lists are left out.
+pub async fn get_lists(
+    db: &PgPool,
+    owner_id: Uuid,
+    public_only: bool,
+) -> Result<Vec<List>, sqlx::Error> {
+    sqlx::query_as!(
+        List,
+        r#"
+SELECT list_id, owner_id, name, description, visibility as "visibility: _", created_at, updated_at FROM lists
+WHERE owner_id = $1 AND (NOT $2 OR visibility = 'public')
+ORDER BY created_at
+"#,
+        owner_id,
+        public_only
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// The list, if it is public or owned by the viewer.
+pub async fn get_list(
+    db: &PgPool,
+    list_id: Uuid,
+    viewer_id: Option<Uuid>,
+) -> Result<List, sqlx::Error> {
+    sqlx::query_as!(
+        List,
+        r#"
+SELECT list_id, owner_id, name, description, visibility as "visibility: _", created_at, updated_at FROM lists
+WHERE list_id = $1 AND (visibility = 'public' OR owner_id = $2)
+"#,
+        list_id,
+        viewer_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Fields left as `None` are not changed. An empty description clears it.
+pub struct ListUpdate<'a> {
+    pub name: Option<&'a str>,
+    pub description: Option<&'a str>,
+    pub visibility: Option<ListVisibility>,
+}
+
+pub async fn update_list(
+    db: &PgPool,
+    list_id: Uuid,
+    owner_id: Uuid,
+    update: ListUpdate<'_>,
+) -> Result<List, sqlx::Error> {
+    sqlx::query_as!(
+        List,
+        r#"
+UPDATE lists
+SET name = COALESCE($3, name),
+description = CASE WHEN $4::text IS NULL THEN description ELSE NULLIF($4, '') END,
+visibility = COALESCE($5, visibility),
+updated_at = NOW()
+WHERE list_id = $1 AND owner_id = $2
+RETURNING list_id, owner_id, name, description, visibility as "visibility: _", created_at, updated_at
+"#,
+        list_id,
+        owner_id,
+        update.name,
+        update.description,
+        update.visibility as Option<ListVisibility>
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn delete_list(db: &PgPool, list_id: Uuid, owner_id: Uuid) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+DELETE FROM lists WHERE list_id = $1 AND owner_id = $2
+"#,
+        list_id,
+        owner_id
+    )
+    .execute(db)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+/// Members of the list, in the order they were added. Suspended and deleted users are left out.
+pub async fn get_list_members(
+    db: &PgPool,
+    list_id: Uuid,
+) -> Result<Vec<AuthorSummary>, sqlx::Error> {
+    sqlx::query_as!(
+        AuthorSummary,
+        r#"
+SELECT id, handle, display_name, avatar_url FROM list_members
+JOIN users ON users.id = list_members.user_id
+WHERE list_id = $1 AND users.suspended_at IS NULL AND users.deleted_at IS NULL
+ORDER BY added_at
+"#,
+        list_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn count_list_members(db: &PgPool, list_id: Uuid) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT COUNT(*) as "count!" FROM list_members WHERE list_id = $1
+"#,
+        list_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Adding a member twice is a no-op. The caller is responsible for checking that the list belongs to the user making the change.
+pub async fn add_list_member(db: &PgPool, list_id: Uuid, user_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+INSERT INTO list_members (list_id, user_id, added_at) VALUES ($1, $2, NOW())
+ON CONFLICT DO NOTHING
+"#,
+        list_id,
+        user_id
+    )
+    .execute(db)
+    .await
+    .map(|_| ())
+}
+
+pub async fn remove_list_member(
+    db: &PgPool,
+    list_id: Uuid,
+    user_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+DELETE FROM list_members WHERE list_id = $1 AND user_id = $2
+"#,
+        list_id,
+        user_id
+    )
+    .execute(db)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+pub async fn get_chirp(db: PgPool, chirp_id: Uuid) -> Result<Chirp, sqlx::Error> {
+    sqlx::query_as!(
+        Chirp,
+        r#"
+SELECT chirp_id, user_id, chirps.created_at, chirps.updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at FROM chirps
+JOIN users ON users.id = chirps.user_id
+WHERE chirp_id = $1 AND chirps.hidden_at IS NULL AND users.suspended_at IS NULL AND users.deleted_at IS NULL AND (chirps.expires_at IS NULL OR chirps.expires_at > NOW())
+"#,
+        chirp_id
+    )
+    .fetch_one(&db)
+    .await
+}
+/// The author of the chirp, whether or not the chirp is hidden. Expired chirps have no author, as they are gone.
+pub async fn get_chirp_author_id(db: &PgPool, chirp_id: Uuid) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT user_id FROM chirps WHERE chirp_id = $1 AND (expires_at IS NULL OR expires_at > NOW())
+"#,
+        chirp_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn delete_chirp_if_author(
+    db: &PgPool,
+    chirp_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<Chirp, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let chirp = sqlx::query_as!(
+        Chirp,
+        r#"
+DELETE FROM chirps
+WHERE chirp_id = $1 AND user_id = $2
+RETURNING chirp_id, user_id, created_at, updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at
+"#,
+        chirp_id,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    record_outbox_event(
+        &mut tx,
+        &DomainEvent::ChirpDeleted {
+            chirp: ChirpSnapshot::from(&chirp),
+        },
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(chirp)
+}
+
+/// Deletes up to `limit` expired chirps, recording their deletion like a deletion by the author. Returns the number of deleted chirps.
+/// Chirps locked by another transaction are skipped, so several reapers can run side by side.
+pub async fn delete_expired_chirps(db: &PgPool, limit: i64) -> Result<u64, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let chirps = sqlx::query_as!(
+        Chirp,
+        r#"
+DELETE FROM chirps
+WHERE chirp_id IN (
+    SELECT chirp_id FROM chirps
+    WHERE expires_at <= NOW()
+    LIMIT $1
+    FOR UPDATE SKIP LOCKED
+)
+RETURNING chirp_id, user_id, created_at, updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at
+"#,
+        limit
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for chirp in &chirps {
+        record_outbox_event(
+            &mut tx,
+            &DomainEvent::ChirpDeleted {
+                chirp: ChirpSnapshot::from(chirp),
+            },
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(chirps.len() as u64)
+}
+
+/// Replaces the body of a chirp, if the user is its author and it hasn't been hidden by a moderator or expired.
+pub async fn update_chirp_if_author(
+    db: &PgPool,
+    chirp_id: Uuid,
+    user_id: Uuid,
+    body: ChirpBody,
+) -> Result<Chirp, sqlx::Error> {
+    sqlx::query_as!(
+        Chirp,
+        r#"
+UPDATE chirps
+SET body = $3, updated_at = NOW()
+WHERE chirp_id = $1 AND user_id = $2 AND hidden_at IS NULL AND repost_of IS NULL AND (expires_at IS NULL OR expires_at > NOW())
+RETURNING chirp_id, user_id, created_at, updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at
+"#,
+        chirp_id,
+        user_id,
+        &body
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Take `platform` as input to safeguard against accidental deletion.
+/// WARNING: The caller should never call this function with anything other than Platform::Dev, but because of how dangerous this endpoint is, we add an additional safeguard here.
+/// Returns the number of deleted rows as result if successful.
+pub async fn delete_all_users(db: PgPool, platform: Platform) -> Result<u64, sqlx::Error> {
+    assert_eq!(platform, Platform::Dev);
+    sqlx::query!(
+        r#"
+        DELETE FROM users
+        "#
+    )
+    .execute(&db)
+    .await
+    .map(|ok| ok.rows_affected())
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct RefreshTokenEntry {
+    pub token: String,
+    pub created_at: OffsetDateTime,
+    pub updated_at: OffsetDateTime,
+    pub user_id: Uuid,
+    pub expires_at: OffsetDateTime,
+    pub revoked_at: Option<OffsetDateTime>,
+}
+
+pub async fn new_refresh_token(db: &PgPool, user: &User) -> Result<RefreshTokenEntry, sqlx::Error> {
+    let refresh_token = make_refresh_token().await;
+    sqlx::query_as!(
+        RefreshTokenEntry,
+        r#"
+INSERT INTO refresh_tokens(token, created_at, updated_at, user_id, expires_at, revoked_at)
+VALUES (
+$1,
+NOW(),
+NOW(),
+$2,
+NOW() + INTERVAL '60 days',
+NULL
+) RETURNING *
+"#,
+        refresh_token,
+        user.id
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn get_refresh_token_entry(
+    db: &PgPool,
+    token: &str,
+) -> Result<RefreshTokenEntry, sqlx::Error> {
+    sqlx::query_as!(
+        RefreshTokenEntry,
+        r#"
+SELECT * FROM refresh_tokens WHERE token = $1
+"#,
+        token
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn revoke_refresh_token(
+    db: &PgPool,
+    token: &str,
+) -> Result<RefreshTokenEntry, sqlx::Error> {
+    sqlx::query_as!(
+        RefreshTokenEntry,
+        r#"UPDATE refresh_tokens
+SET updated_at = NOW(), revoked_at = NOW()
+WHERE token = $1
+RETURNING *"#,
+        token
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Returns the number of inserted rows, i.e. 0 if the user had already reported the chirp.
+pub async fn report_chirp(
+    db: &PgPool,
+    chirp_id: Uuid,
+    reporter_id: Uuid,
+    reason: &str,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        r#"
+INSERT INTO chirp_reports(report_id, chirp_id, reporter_id, reason, created_at)
+VALUES (gen_random_uuid(), $1, $2, $3, NOW())
+ON CONFLICT (chirp_id, reporter_id) DO NOTHING
+"#,
+        chirp_id,
+        reporter_id,
+        reason
+    )
+    .execute(db)
+    .await
+    .map(|ok| ok.rows_affected())
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChirpReport {
+    pub report_id: Uuid,
+    pub chirp_id: Uuid,
+    pub chirp_author_id: Uuid,
+    pub chirp_body: String,
+    pub chirp_hidden_at: Option<OffsetDateTime>,
+    pub reporter_id: Uuid,
+    pub reason: String,
+    pub created_at: OffsetDateTime,
+}
+
+/// The moderation review queue: all unresolved reports of chirps that haven't expired, oldest first.
+pub async fn get_open_reports(db: &PgPool) -> Result<Vec<ChirpReport>, sqlx::Error> {
+    sqlx::query_as!(
+        ChirpReport,
+        r#"
+SELECT report_id, chirp_reports.chirp_id, chirps.user_id as chirp_author_id, chirps.body as chirp_body,
+chirps.hidden_at as chirp_hidden_at, reporter_id, reason, chirp_reports.created_at
+FROM chirp_reports
+JOIN chirps ON chirps.chirp_id = chirp_reports.chirp_id
+WHERE resolved_at IS NULL AND (chirps.expires_at IS NULL OR chirps.expires_at > NOW())
+ORDER BY chirp_reports.created_at ASC
+"#
+    )
+    .fetch_all(db)
+    .await
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "moderation_action", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    HideChirp,
+    UnhideChirp,
+    SuspendUser,
+    UnsuspendUser,
+    ResolveReport,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ModerationLogEntry {
+    pub entry_id: Uuid,
+    pub moderator_id: Option<Uuid>,
+    pub action: ModerationAction,
+    pub target_chirp_id: Option<Uuid>,
+    pub target_user_id: Option<Uuid>,
+    pub target_report_id: Option<Uuid>,
+    pub reason: Option<String>,
+    pub created_at: OffsetDateTime,
+}
+
+/// The target of a moderation action, as recorded in the moderation log.
+pub enum ModerationTarget {
+    Chirp(Uuid),
+    User(Uuid),
+    Report(Uuid),
+}
+
+/// Moderation actions call this inside the transaction that performs the action, so that no action goes unlogged.
+async fn log_moderation_action(
+    conn: &mut PgConnection,
+    moderator_id: Uuid,
+    action: ModerationAction,
+    target: ModerationTarget,
+    reason: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let (chirp_id, user_id, report_id) = match target {
+        ModerationTarget::Chirp(id) => (Some(id), None, None),
+        ModerationTarget::User(id) => (None, Some(id), None),
+        ModerationTarget::Report(id) => (None, None, Some(id)),
+    };
+    sqlx::query!(
+        r#"
+INSERT INTO moderation_log(entry_id, moderator_id, action, target_chirp_id, target_user_id, target_report_id, reason, created_at)
+VALUES (gen_random_uuid(), $1, $2, $3, $4, $5, $6, NOW())
+"#,
+        moderator_id,
+        action as ModerationAction,
+        chirp_id,
+        user_id,
+        report_id,
+        reason
+    )
+    .execute(conn)
+    .await
+    .map(|_| ())
+}
+
+pub async fn get_moderation_log(db: &PgPool) -> Result<Vec<ModerationLogEntry>, sqlx::Error> {
+    sqlx::query_as!(
+        ModerationLogEntry,
+        r#"
+SELECT entry_id, moderator_id, action as "action: _", target_chirp_id, target_user_id, target_report_id, reason, created_at
+FROM moderation_log
+ORDER BY created_at DESC
+"#
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Hiding a chirp also resolves all open reports against it.
+pub async fn hide_chirp(
+    db: &PgPool,
+    moderator_id: Uuid,
+    chirp_id: Uuid,
+    reason: &str,
+) -> Result<Chirp, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let chirp = sqlx::query_as!(
+        Chirp,
+        r#"
+UPDATE chirps
+SET hidden_at = NOW()
+WHERE chirp_id = $1 AND hidden_at IS NULL
+RETURNING chirp_id, user_id, created_at, updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at
+"#,
+        chirp_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+UPDATE chirp_reports
+SET resolved_at = NOW(), resolved_by = $2
+WHERE chirp_id = $1 AND resolved_at IS NULL
+"#,
+        chirp_id,
+        moderator_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    log_moderation_action(
+        &mut tx,
+        moderator_id,
+        ModerationAction::HideChirp,
+        ModerationTarget::Chirp(chirp_id),
+        Some(reason),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(chirp)
+}
+
+pub async fn unhide_chirp(
+    db: &PgPool,
+    moderator_id: Uuid,
+    chirp_id: Uuid,
+) -> Result<Chirp, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let chirp = sqlx::query_as!(
+        Chirp,
+        r#"
+UPDATE chirps
+SET hidden_at = NULL
+WHERE chirp_id = $1 AND hidden_at IS NOT NULL
+RETURNING chirp_id, user_id, created_at, updated_at, body as "body: _", in_reply_to, repost_of, quote_of, expires_at
+"#,
+        chirp_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    log_moderation_action(
+        &mut tx,
+        moderator_id,
+        ModerationAction::UnhideChirp,
+        ModerationTarget::Chirp(chirp_id),
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(chirp)
+}
+
+/// Suspending a user also revokes all of their refresh tokens.
+pub async fn suspend_user(
+    db: &PgPool,
+    moderator_id: Uuid,
+    user_id: Uuid,
+    reason: &str,
+) -> Result<User, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let user = sqlx::query_as!(
+        User,
+        r#"
+UPDATE users
+SET suspended_at = NOW()
+WHERE id = $1 AND suspended_at IS NULL
+RETURNING *
+"#,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+UPDATE refresh_tokens
+SET updated_at = NOW(), revoked_at = NOW()
+WHERE user_id = $1 AND revoked_at IS NULL
+"#,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    log_moderation_action(
+        &mut tx,
+        moderator_id,
+        ModerationAction::SuspendUser,
+        ModerationTarget::User(user_id),
+        Some(reason),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(user)
+}
+
+pub async fn unsuspend_user(
+    db: &PgPool,
+    moderator_id: Uuid,
+    user_id: Uuid,
+) -> Result<User, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let user = sqlx::query_as!(
+        User,
+        r#"
+UPDATE users
+SET suspended_at = NULL
+WHERE id = $1 AND suspended_at IS NOT NULL
+RETURNING *
+"#,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    log_moderation_action(
+        &mut tx,
+        moderator_id,
+        ModerationAction::UnsuspendUser,
+        ModerationTarget::User(user_id),
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(user)
+}
+
+/// Resolves a report without acting on the reported chirp.
+pub async fn resolve_report(
+    db: &PgPool,
+    moderator_id: Uuid,
+    report_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        r#"
+UPDATE chirp_reports
+SET resolved_at = NOW(), resolved_by = $2
+WHERE report_id = $1 AND resolved_at IS NULL
+RETURNING report_id
+"#,
+        report_id,
+        moderator_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    log_moderation_action(
+        &mut tx,
+        moderator_id,
+        ModerationAction::ResolveReport,
+        ModerationTarget::Report(report_id),
+        None,
+    )
+    .await?;
+    tx.commit().await
+}
+
+/// Uploaded media is processed in the background before it is served, see `media_processing`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "media_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MediaStatus {
+    Pending,
+    Processing,
+    Ready,
+    Failed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Media {
+    #[serde(rename = "id")]
+    pub media_id: Uuid,
+    pub owner_id: Uuid,
+    pub content_type: String,
+    pub size_bytes: i64,
+    #[serde(skip_serializing)]
+    pub storage_key: String,
+    pub url: String,
+    pub created_at: OffsetDateTime,
+    pub status: MediaStatus,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub blurhash: Option<String>,
+}
+
+pub async fn insert_media(
+    db: &PgPool,
+    media_id: Uuid,
+    owner_id: Uuid,
+    content_type: &str,
+    size_bytes: i64,
+    storage_key: &str,
+    url: &str,
+) -> Result<Media, sqlx::Error> {
+    sqlx::query_as!(
+        Media,
+        r#"
+INSERT INTO media(media_id, owner_id, content_type, size_bytes, storage_key, url, created_at)
+VALUES ($1, $2, $3, $4, $5, $6, NOW())
+RETURNING media_id, owner_id, content_type, size_bytes, storage_key, url, created_at,
+status as "status: _", width, height, blurhash
+"#,
+        media_id,
+        owner_id,
+        content_type,
+        size_bytes,
+        storage_key,
+        url
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn get_owned_media(
+    db: &PgPool,
+    media_id: Uuid,
+    owner_id: Uuid,
+) -> Result<Media, sqlx::Error> {
+    sqlx::query_as!(
+        Media,
+        r#"
+SELECT media_id, owner_id, content_type, size_bytes, storage_key, url, created_at,
+status as "status: _", width, height, blurhash
+FROM media
+WHERE media_id = $1 AND owner_id = $2
+"#,
+        media_id,
+        owner_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Claims the oldest media that is waiting to be processed, or whose processing was started more than `stale_after` ago and presumably crashed.
+pub async fn claim_pending_media(
+    db: &PgPool,
+    stale_after: Duration,
+) -> Result<Option<Media>, sqlx::Error> {
+    sqlx::query_as!(
+        Media,
+        r#"
+UPDATE media
+SET status = 'processing', processing_started_at = NOW(), attempts = attempts + 1
+WHERE media_id = (
+SELECT media_id FROM media
+WHERE status = 'pending' OR (status = 'processing' AND processing_started_at < NOW() - $1::interval)
+ORDER BY created_at ASC
+LIMIT 1
+FOR UPDATE SKIP LOCKED
+)
+RETURNING media_id, owner_id, content_type, size_bytes, storage_key, url, created_at,
+status as "status: _", width, height, blurhash
+"#,
+        stale_after as Duration
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub struct NewMediaVariant {
+    pub variant: String,
+    pub width: i32,
+    pub height: i32,
+    pub content_type: String,
+    pub storage_key: String,
+    pub url: String,
+}
+
+/// Records the result of processing and makes the media available.
+pub async fn complete_media_processing(
+    db: &PgPool,
+    media_id: Uuid,
+    size_bytes: i64,
+    width: i32,
+    height: i32,
+    blurhash: &str,
+    variants: &[NewMediaVariant],
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    for variant in variants {
+        sqlx::query!(
+            r#"
+INSERT INTO media_variants(media_id, variant, width, height, content_type, storage_key, url)
+VALUES ($1, $2, $3, $4, $5, $6, $7)
+ON CONFLICT (media_id, variant) DO UPDATE
+SET width = EXCLUDED.width, height = EXCLUDED.height, content_type = EXCLUDED.content_type,
+storage_key = EXCLUDED.storage_key, url = EXCLUDED.url
+"#,
+            media_id,
+            variant.variant,
+            variant.width,
+            variant.height,
+            variant.content_type,
+            variant.storage_key,
+            variant.url
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query!(
+        r#"
+UPDATE media
+SET status = 'ready', processed_at = NOW(), size_bytes = $2, width = $3, height = $4, blurhash = $5
+WHERE media_id = $1
+"#,
+        media_id,
+        size_bytes,
+        width,
+        height,
+        blurhash
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+/// Puts the media back in the queue, or gives up on it after `max_attempts`.
+pub async fn fail_media_processing(
+    db: &PgPool,
+    media_id: Uuid,
+    max_attempts: i32,
+) -> Result<MediaStatus, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+UPDATE media
+SET status = CASE WHEN attempts >= $2 THEN 'failed'::media_status ELSE 'pending'::media_status END
+WHERE media_id = $1
+RETURNING status as "status: MediaStatus"
+"#,
+        media_id,
+        max_attempts
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Counts how many of the given media ids exist, belong to the owner and can still be shown: media that failed processing never will be.
+pub async fn count_owned_media(
+    db: &PgPool,
+    owner_id: Uuid,
+    media_ids: &[Uuid],
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT COUNT(*) as "count!" FROM media
+WHERE owner_id = $1 AND media_id = ANY($2) AND status <> 'failed'
+"#,
+        owner_id,
+        media_ids
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Media attached to a chirp, as embedded in chirp responses.
+#[derive(Serialize, Debug, Clone)]
+pub struct ChirpMedia {
+    #[serde(skip_serializing)]
+    pub chirp_id: Uuid,
+    #[serde(rename = "id")]
+    pub media_id: Uuid,
+    pub content_type: String,
+    /// Only set once the media is ready, as there is nothing to serve before then. Clients can show a placeholder in the meantime.
+    pub url: Option<String>,
+    pub status: MediaStatus,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub blurhash: Option<String>,
+}
+
+/// Fetches the media of all given chirps in a single query, ordered by chirp and attachment position. Media that failed processing is left out.
+pub async fn get_media_for_chirps(
+    db: &PgPool,
+    chirp_ids: &[Uuid],
+) -> Result<Vec<ChirpMedia>, sqlx::Error> {
+    sqlx::query_as!(
+        ChirpMedia,
+        r#"
+SELECT chirp_id, media.media_id, content_type, CASE WHEN status = 'ready' THEN url END as url,
+status as "status: _", width, height, blurhash
+FROM chirp_media
+JOIN media ON media.media_id = chirp_media.media_id
+WHERE chirp_id = ANY($1) AND status <> 'failed'
+ORDER BY chirp_id, position
+"#,
+        chirp_ids
+    )
+    .fetch_all(db)
+    .await
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MediaVariant {
+    #[serde(skip_serializing)]
+    pub media_id: Uuid,
+    #[serde(rename = "name")]
+    pub variant: String,
+    pub width: i32,
+    pub height: i32,
+    pub content_type: String,
+    pub url: String,
+}
+
+/// Fetches the variants of all given media in a single query, smallest first.
+pub async fn get_variants_for_media(
+    db: &PgPool,
+    media_ids: &[Uuid],
+) -> Result<Vec<MediaVariant>, sqlx::Error> {
+    sqlx::query_as!(
+        MediaVariant,
+        r#"
+SELECT media_id, variant, width, height, content_type, url FROM media_variants
+WHERE media_id = ANY($1)
+ORDER BY media_id, width
+"#,
+        media_ids
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Static file server hits recorded in memory since the last flush.
+pub struct StaticAnalyticsDelta {
+    /// Path, status code and number of hits.
+    pub path_hits: Vec<(String, i32, i64)>,
+    pub referrer_hits: Vec<(String, i64)>,
+    pub user_agent_hits: Vec<(String, i64)>,
+    /// HyperLogLog registers of the visitors seen since the last flush.
+    pub visitor_registers: Vec<u8>,
+    /// The hits that count as visits, i.e. everything but redirects.
+    pub visits: i64,
+}
+
+/// Adds the delta to the totals of the day and hour that `hour` falls in.
+pub async fn record_static_analytics(
+    db: &PgPool,
+    hour: OffsetDateTime,
+    delta: &StaticAnalyticsDelta,
+) -> Result<(), sqlx::Error> {
+    let hour = hour.to_offset(UtcOffset::UTC);
+    let hour = hour.replace_time(Time::MIDNIGHT) + Duration::hours(hour.hour().into());
+    let day = hour.date();
+    let mut tx = db.begin().await?;
+
+    sqlx::query!(
+        r#"
+INSERT INTO static_hourly_visits (hour, visits) VALUES ($1, $2)
+ON CONFLICT (hour) DO UPDATE SET visits = static_hourly_visits.visits + EXCLUDED.visits
+"#,
+        hour,
+        delta.visits
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let (paths, (statuses, hits)): (Vec<_>, (Vec<_>, Vec<_>)) = delta
+        .path_hits
+        .iter()
+        .map(|(path, status, hits)| (path.clone(), (*status, *hits)))
+        .unzip();
+    sqlx::query!(
+        r#"
+INSERT INTO static_path_hits (day, path, status, hits)
+SELECT $1, * FROM UNNEST($2::TEXT[], $3::INTEGER[], $4::BIGINT[])
+ON CONFLICT (day, path, status) DO UPDATE SET hits = static_path_hits.hits + EXCLUDED.hits
+"#,
+        day,
+        &paths,
+        &statuses,
+        &hits
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let (referrers, hits): (Vec<_>, Vec<_>) = delta.referrer_hits.iter().cloned().unzip();
+    sqlx::query!(
+        r#"
+INSERT INTO static_referrer_hits (day, referrer, hits)
+SELECT $1, * FROM UNNEST($2::TEXT[], $3::BIGINT[])
+ON CONFLICT (day, referrer) DO UPDATE SET hits = static_referrer_hits.hits + EXCLUDED.hits
+"#,
+        day,
+        &referrers,
+        &hits
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let (families, hits): (Vec<_>, Vec<_>) = delta.user_agent_hits.iter().cloned().unzip();
+    sqlx::query!(
+        r#"
+INSERT INTO static_user_agent_hits (day, family, hits)
+SELECT $1, * FROM UNNEST($2::TEXT[], $3::BIGINT[])
+ON CONFLICT (day, family) DO UPDATE SET hits = static_user_agent_hits.hits + EXCLUDED.hits
+"#,
+        day,
+        &families,
+        &hits
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if delta.visitor_registers.iter().any(|&register| register > 0) {
+        // Registers are merged by taking the maximum of each, which SQL can't do for byte strings, so lock the row and merge here.
+        sqlx::query!(
+            r#"
+INSERT INTO static_visitors (day, registers) VALUES ($1, $2)
+ON CONFLICT (day) DO NOTHING
+"#,
+            day,
+            &vec![0; delta.visitor_registers.len()]
+        )
+        .execute(&mut *tx)
+        .await?;
+        let mut registers = sqlx::query_scalar!(
+            r#"
+SELECT registers FROM static_visitors WHERE day = $1 FOR UPDATE
+"#,
+            day
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        registers.resize(delta.visitor_registers.len(), 0);
+        for (register, &new) in registers.iter_mut().zip(&delta.visitor_registers) {
+            *register = (*register).max(new);
+        }
+        sqlx::query!(
+            r#"
+UPDATE static_visitors SET registers = $2 WHERE day = $1
+"#,
+            day,
+            &registers
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+#[derive(Serialize, Debug)]
+pub struct NamedHits {
+    pub name: String,
+    pub hits: i64,
+}
+
+/// The most visited paths since `since`, counting all status codes.
+pub async fn get_top_paths(
+    db: &PgPool,
+    since: Date,
+    limit: i64,
+) -> Result<Vec<NamedHits>, sqlx::Error> {
+    sqlx::query_as!(
+        NamedHits,
+        r#"
+SELECT path as "name!", SUM(hits)::BIGINT as "hits!" FROM static_path_hits
+WHERE day >= $1
+GROUP BY path
+ORDER BY 2 DESC, 1
+LIMIT $2
+"#,
+        since,
+        limit
+    )
+    .fetch_all(db)
+    .await
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatusHits {
+    pub status: i32,
+    pub hits: i64,
+}
+
+pub async fn get_status_totals(db: &PgPool, since: Date) -> Result<Vec<StatusHits>, sqlx::Error> {
+    sqlx::query_as!(
+        StatusHits,
+        r#"
+SELECT status as "status!", SUM(hits)::BIGINT as "hits!" FROM static_path_hits
+WHERE day >= $1
+GROUP BY status
+ORDER BY status
+"#,
+        since
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn get_top_referrers(
+    db: &PgPool,
+    since: Date,
+    limit: i64,
+) -> Result<Vec<NamedHits>, sqlx::Error> {
+    sqlx::query_as!(
+        NamedHits,
+        r#"
+SELECT referrer as "name!", SUM(hits)::BIGINT as "hits!" FROM static_referrer_hits
+WHERE day >= $1
+GROUP BY referrer
+ORDER BY 2 DESC, 1
+LIMIT $2
+"#,
+        since,
+        limit
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn get_user_agent_totals(
+    db: &PgPool,
+    since: Date,
+) -> Result<Vec<NamedHits>, sqlx::Error> {
+    sqlx::query_as!(
+        NamedHits,
+        r#"
+SELECT family as "name!", SUM(hits)::BIGINT as "hits!" FROM static_user_agent_hits
+WHERE day >= $1
+GROUP BY family
+ORDER BY 2 DESC, 1
+"#,
+        since
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// The HyperLogLog registers of each day since `since`.
+pub async fn get_visitor_registers(db: &PgPool, since: Date) -> Result<Vec<Vec<u8>>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT registers FROM static_visitors WHERE day >= $1
+"#,
+        since
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn delete_static_analytics(db: &PgPool, platform: Platform) -> Result<(), sqlx::Error> {
+    assert_eq!(platform, Platform::Dev);
+    sqlx::query!(
+        r#"
+TRUNCATE static_path_hits, static_referrer_hits, static_user_agent_hits, static_visitors, static_hourly_visits
+"#
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_total_visits(db: &PgPool) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+SELECT COALESCE(SUM(visits), 0)::BIGINT as "visits!" FROM static_hourly_visits
+"#
+    )
+    .fetch_one(db)
+    .await
+}
+
+#[derive(Serialize, Debug)]
+pub struct HourlyVisits {
+    pub hour: OffsetDateTime,
+    pub visits: i64,
+}
+
+/// Visits per hour from the hour `since` falls in up to the current one, including hours without visits.
+pub async fn get_hourly_visits(
+    db: &PgPool,
+    since: OffsetDateTime,
+) -> Result<Vec<HourlyVisits>, sqlx::Error> {
+    sqlx::query_as!(
+        HourlyVisits,
+        r#"
+SELECT series.hour as "hour!", COALESCE(visits, 0) as "visits!"
+FROM generate_series(
+    date_trunc('hour', $1::TIMESTAMPTZ, 'UTC'),
+    date_trunc('hour', NOW(), 'UTC'),
+    INTERVAL '1 hour'
+) AS series(hour)
+LEFT JOIN static_hourly_visits ON static_hourly_visits.hour = series.hour
+ORDER BY series.hour
+"#,
+        since
+    )
+    .fetch_all(db)
+    .await
+}
+
+#[derive(Serialize, Debug)]
+pub struct DailyVisits {
+    pub day: Date,
+    pub visits: i64,
+}
+
+/// Visits per UTC day from `since` up to today, including days without visits.
+pub async fn get_daily_visits(db: &PgPool, since: Date) -> Result<Vec<DailyVisits>, sqlx::Error> {
+    sqlx::query_as!(
+        DailyVisits,
+        r#"
+SELECT series.day::DATE as "day!", COALESCE(SUM(visits), 0)::BIGINT as "visits!"
+FROM generate_series($1::DATE, (NOW() AT TIME ZONE 'UTC')::DATE, INTERVAL '1 day') AS series(day)
+LEFT JOIN static_hourly_visits ON (hour AT TIME ZONE 'UTC')::DATE = series.day
+GROUP BY series.day
+ORDER BY series.day
+"#,
+        since
+    )
+    .fetch_all(db)
+    .await
+}
@@ -1,30 +1,22 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+
+use crate::{analytics::StaticAnalytics, static_files::StaticFilesConfig};
 
 #[derive(Clone)]
 pub struct AppState {
-    pub data: Arc<Mutex<AppStateData>>,
+    pub analytics: Arc<StaticAnalytics>,
     pub config: AppConfig,
 }
 
 impl AppState {
     pub fn new() -> Self {
         Self {
-            data: Arc::new(Mutex::new(AppStateData::new())),
+            analytics: Arc::new(StaticAnalytics::new()),
             config: AppConfig::new(),
         }
     }
 }
 
-pub struct AppStateData {
-    pub fileserver_hits: u64,
-}
-
-impl AppStateData {
-    fn new() -> Self {
-        Self { fileserver_hits: 0 }
-    }
-}
-
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Platform {
     Dev,
@@ -51,6 +43,7 @@ impl From<&str> for Platform {
 #[derive(Clone)]
 pub struct AppConfig {
     pub platform: Platform,
+    pub static_files: StaticFilesConfig,
 }
 
 impl AppConfig {
@@ -58,6 +51,7 @@ impl AppConfig {
         // Use safest options as default
         AppConfig {
             platform: Platform::new(),
+            static_files: StaticFilesConfig::new(),
         }
     }
 }
@@ -0,0 +1,238 @@
+use std::path::PathBuf;
+
+use axum::{
+    extract::{OriginalUri, Request, State},
+    http::{
+        header::{
+            CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, ETAG, IF_MODIFIED_SINCE,
+            IF_NONE_MATCH, LAST_MODIFIED, LOCATION, VARY,
+        },
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use percent_encoding::percent_decode_str;
+use tokio::fs;
+
+use crate::state::AppState;
+
+#[derive(Clone)]
+pub struct StaticFilesConfig {
+    /// The directory served under `/app`.
+    pub root: PathBuf,
+    /// The first policy whose pattern matches the requested path decides the `Cache-Control` header.
+    pub cache_policies: Vec<CachePolicy>,
+    /// Used when no policy matches.
+    pub default_cache_control: HeaderValue,
+    /// Whether directories without an `index.html` get a listing of their contents.
+    pub directory_listings: bool,
+    /// Served with 404 Not Found for unknown paths if it exists. Relative to the static root.
+    pub not_found_page: PathBuf,
+    /// Single-page app mode: unknown paths that don't look like files are client-side routes, and get the root `index.html`.
+    pub spa: bool,
+}
+
+impl StaticFilesConfig {
+    pub fn new() -> Self {
+        StaticFilesConfig {
+            root: PathBuf::from("app"),
+            cache_policies: vec![
+                CachePolicy::new("*.html", HeaderValue::from_static("no-cache")),
+                CachePolicy::new(
+                    "assets/*",
+                    HeaderValue::from_static("public, max-age=604800"),
+                ),
+            ],
+            // Clients may cache, but must revalidate with the ETag before every use.
+            default_cache_control: HeaderValue::from_static("no-cache"),
+            directory_listings: true,
+            not_found_page: PathBuf::from("404.html"),
+            spa: false,
+        }
+    }
+
+    /// Parses policies of the form `pattern=cache-control`, separated by `;`, e.g. `*.html=no-cache;assets/*=public, max-age=604800`.
+    pub fn parse_cache_policies(policies: &str) -> Option<Vec<CachePolicy>> {
+        policies
+            .split(';')
+            .filter(|policy| !policy.trim().is_empty())
+            .map(|policy| {
+                let (pattern, cache_control) = policy.split_once('=')?;
+                let cache_control = HeaderValue::from_str(cache_control.trim()).ok()?;
+                Some(CachePolicy::new(pattern.trim(), cache_control))
+            })
+            .collect()
+    }
+
+    /// Assets such as `main.js` have an extension in their last segment, routes such as `/settings/profile` don't.
+    pub fn is_client_route(&self, relative_path: &str) -> bool {
+        self.spa
+            && !relative_path
+                .rsplit('/')
+                .next()
+                .is_some_and(|segment| segment.contains('.'))
+    }
+
+    fn cache_control(&self, path: &str) -> HeaderValue {
+        self.cache_policies
+            .iter()
+            .find(|policy| glob_match(&policy.pattern, path))
+            .map(|policy| policy.cache_control.clone())
+            .unwrap_or(self.default_cache_control.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct CachePolicy {
+    /// Matched against the path relative to the static root. `*` matches any sequence of characters, including `/`.
+    pattern: String,
+    cache_control: HeaderValue,
+}
+
+impl CachePolicy {
+    pub fn new(pattern: &str, cache_control: HeaderValue) -> Self {
+        Self {
+            pattern: pattern.trim_start_matches('/').to_owned(),
+            cache_control,
+        }
+    }
+}
+
+fn glob_match(pattern: &str, path: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == path,
+        Some((prefix, rest)) => {
+            let Some(path) = path.strip_prefix(prefix) else {
+                return false;
+            };
+            // Try every possible length for the part matched by `*`.
+            (0..=path.len())
+                .filter(|&i| path.is_char_boundary(i))
+                .any(|i| glob_match(rest, &path[i..]))
+        }
+    }
+}
+
+/// Returns the decoded path relative to the static root, or `None` if the path tries to escape the root or refers to a hidden file.
+pub fn sanitize_path(path: &str) -> Option<String> {
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    let relative = decoded.trim_start_matches('/');
+    let is_safe = !relative.contains('\\')
+        && !relative.contains('\0')
+        && relative.split('/').all(|segment| !segment.starts_with('.'));
+    is_safe.then(|| relative.to_owned())
+}
+
+/// Serves the configured 404 page, or a minimal one if it doesn't exist.
+pub async fn not_found_page(config: &StaticFilesConfig) -> Response {
+    let page = fs::read_to_string(config.root.join(&config.not_found_page))
+        .await
+        .unwrap_or_else(|_| {
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Not found</title>\n</head>\n<body>\n<h1>Not found</h1>\n</body>\n</html>\n".to_owned()
+        });
+    (StatusCode::NOT_FOUND, Html::from(page)).into_response()
+}
+
+/// Wraps the `/app` file server, which sees paths relative to the static root.
+///
+/// Rejects hidden files and path traversal, adds `ETag`, `Cache-Control` and `Vary` headers and answers `If-None-Match` with 304 Not Modified.
+/// `ServeDir` already handles `Last-Modified` and `If-Modified-Since`.
+pub async fn static_file_headers(
+    State(app_state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    // Checking the segments also covers `..`, since it starts with a dot.
+    let Some(relative_path) = sanitize_path(request.uri().path()) else {
+        return not_found_page(&app_state.config.static_files).await;
+    };
+
+    // `If-None-Match` takes precedence over `If-Modified-Since` (RFC 9110 13.1.3), so don't let `ServeDir` answer based on the latter.
+    let if_none_match = request.headers_mut().remove(IF_NONE_MATCH);
+    if if_none_match.is_some() {
+        request.headers_mut().remove(IF_MODIFIED_SINCE);
+    }
+    let original_uri = request.extensions().get::<OriginalUri>().cloned();
+
+    // Nesting turns both `/app` and `/app/` into `/`, so `ServeDir` would serve the index without the trailing slash that relative links rely on.
+    if request.uri().path() == "/"
+        && let Some(OriginalUri(uri)) = &original_uri
+        && !uri.path().ends_with('/')
+    {
+        return Redirect::temporary(&format!("{}/", uri.path())).into_response();
+    }
+
+    let mut response = next.run(request).await;
+
+    // `ServeDir` redirects directories to the path with a trailing slash, but does not know that it is nested under `/app`.
+    if response.status() == StatusCode::TEMPORARY_REDIRECT
+        && let Some(OriginalUri(uri)) = original_uri
+        && let Ok(location) = HeaderValue::from_str(&format!("{}/", uri.path()))
+    {
+        response.headers_mut().insert(LOCATION, location);
+        return response;
+    }
+
+    if !matches!(response.status(), StatusCode::OK | StatusCode::NOT_MODIFIED) {
+        return response;
+    }
+
+    let config = &app_state.config.static_files;
+    // Directories are served through their index.html.
+    let served_path = if relative_path.is_empty() || relative_path.ends_with('/') {
+        format!("{relative_path}index.html")
+    } else {
+        relative_path
+    };
+    let headers = response.headers_mut();
+    headers.insert(CACHE_CONTROL, config.cache_control(&served_path));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+
+    let Some(etag) = etag(headers) else {
+        return response;
+    };
+    headers.insert(ETAG, etag.clone());
+
+    if if_none_match.is_some_and(|inm| etag_matches(&inm, &etag)) {
+        let mut not_modified = StatusCode::NOT_MODIFIED.into_response();
+        for name in [ETAG, LAST_MODIFIED, CACHE_CONTROL, VARY] {
+            if let Some(value) = response.headers().get(&name) {
+                not_modified.headers_mut().insert(name, value.clone());
+            }
+        }
+        return not_modified;
+    }
+
+    response
+}
+
+/// A weak ETag derived from the modification time, size and encoding of the file.
+fn etag(headers: &HeaderMap) -> Option<HeaderValue> {
+    let last_modified = headers.get(LAST_MODIFIED)?.to_str().ok()?;
+    let content_length = headers.get(CONTENT_LENGTH)?.to_str().ok()?;
+    let encoding = headers
+        .get(CONTENT_ENCODING)
+        .and_then(|encoding| encoding.to_str().ok())
+        .unwrap_or("identity");
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in last_modified.bytes() {
+        // FNV-1a
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    HeaderValue::from_str(&format!("W/\"{content_length}-{hash:x}-{encoding}\"")).ok()
+}
+
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let Ok(if_none_match) = if_none_match.to_str() else {
+        return false;
+    };
+    let etag = etag.to_str().unwrap_or_default().trim_start_matches("W/");
+    // If-None-Match uses weak comparison.
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
@@ -0,0 +1,331 @@
+use std::{collections::HashSet, convert::Infallible, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgListener, PgPool};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    watch,
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, WatchStream},
+    StreamExt,
+};
+use uuid::Uuid;
+
+use crate::{
+    api::{hydrate_chirps, optional_user_id_from_bearer},
+    auth::JwtKey,
+    queries::{get_chirp, get_followee_ids, get_hidden_author_ids},
+};
+
+/// The channel that the `chirps_notify` trigger publishes to.
+const CHIRP_EVENTS_CHANNEL: &str = "chirp_events";
+
+/// Events buffered per subscriber. Subscribers that fall further behind get a `lagged` event with the number of events they missed.
+const SUBSCRIBER_BUFFER: usize = 256;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ChirpEventKind {
+    Created,
+    Deleted,
+}
+
+/// The payload of a notification sent by the `chirps_notify` trigger.
+#[derive(Deserialize)]
+struct ChirpNotification {
+    event: ChirpEventKind,
+    chirp_id: Uuid,
+    user_id: Uuid,
+}
+
+#[derive(Serialize)]
+struct DeletedChirp {
+    id: Uuid,
+    user_id: Uuid,
+}
+
+/// An event as sent to clients. The data is serialized once, however many clients receive it.
+pub struct StreamEvent {
+    name: &'static str,
+    author_id: Uuid,
+    data: String,
+}
+
+/// Pushes new and deleted chirps to connected clients.
+///
+/// Changes to chirps are announced by Postgres with `NOTIFY`, so clients see chirps posted through any server instance.
+/// Each instance listens for them and fans them out to its own clients.
+#[derive(Clone)]
+pub struct ChirpStream {
+    sender: broadcast::Sender<Arc<StreamEvent>>,
+    /// Set when the server shuts down. Streams to clients never end on their own, and would keep graceful shutdown waiting forever.
+    shutdown: watch::Sender<bool>,
+}
+
+impl ChirpStream {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(SUBSCRIBER_BUFFER).0,
+            shutdown: watch::channel(false).0,
+        }
+    }
+
+    /// Ends the streams of all connected clients, and of clients that connect from now on.
+    pub fn shut_down(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn spawn_listener(&self, db: PgPool) {
+        let stream = self.clone();
+        tokio::spawn(async move { stream.listen(db).await });
+    }
+
+    /// Notifications sent while the connection is down are lost, so clients may miss events when the database restarts.
+    async fn listen(self, db: PgPool) {
+        loop {
+            let mut listener = match PgListener::connect_with(&db).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Failed to connect chirp stream listener: {e}");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+            if let Err(e) = listener.listen(CHIRP_EVENTS_CHANNEL).await {
+                eprintln!("Failed to listen for chirp events: {e}");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+            loop {
+                match listener.recv().await {
+                    Ok(notification) => self.publish(&db, notification.payload()).await,
+                    // The listener reconnects by itself on the next call.
+                    Err(e) => {
+                        eprintln!("Failed to receive chirp events: {e}");
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn publish(&self, db: &PgPool, payload: &str) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let notification: ChirpNotification = match serde_json::from_str(payload) {
+            Ok(notification) => notification,
+            Err(e) => {
+                eprintln!("Invalid chirp event {payload}: {e}");
+                return;
+            }
+        };
+
+        let event = match notification.event {
+            ChirpEventKind::Created => {
+                // Chirps by suspended users are not shown anywhere.
+                let Ok(chirp) = get_chirp(db.clone(), notification.chirp_id).await else {
+                    return;
+                };
+                let chirp = match hydrate_chirps(db, vec![chirp], true).await {
+                    Ok(mut chirps) => chirps.remove(0),
+                    Err(e) => {
+                        eprintln!("Failed to load chirp {}: {e}", notification.chirp_id);
+                        return;
+                    }
+                };
+                StreamEvent {
+                    name: "chirp.created",
+                    author_id: notification.user_id,
+                    data: serde_json::to_string(&chirp).expect("Chirps serialize to JSON"),
+                }
+            }
+            ChirpEventKind::Deleted => StreamEvent {
+                name: "chirp.deleted",
+                author_id: notification.user_id,
+                data: serde_json::to_string(&DeletedChirp {
+                    id: notification.chirp_id,
+                    user_id: notification.user_id,
+                })
+                .expect("Deleted chirps serialize to JSON"),
+            },
+        };
+        // Fails only if every client disconnected in the meantime.
+        let _ = self.sender.send(Arc::new(event));
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StreamParams {
+    author_id: Option<Uuid>,
+    /// Only chirps by the authenticated user and the users they follow.
+    #[serde(default)]
+    following: bool,
+}
+
+/// Which chirps a client is interested in. Both filters apply if both are given.
+struct StreamFilter {
+    author_id: Option<Uuid>,
+    /// Follows, mutes and blocks are looked up when the client connects, so later changes apply once the client reconnects.
+    authors: Option<HashSet<Uuid>>,
+    /// Authors an authenticated client has muted or blocked.
+    hidden_authors: HashSet<Uuid>,
+}
+
+impl StreamFilter {
+    async fn new(
+        db: &PgPool,
+        key: &JwtKey,
+        headers: &HeaderMap,
+        params: StreamParams,
+    ) -> Result<Self, StatusCode> {
+        let user_id = optional_user_id_from_bearer(headers, key)?;
+        let authors = if params.following {
+            let user_id = user_id.ok_or(StatusCode::UNAUTHORIZED)?;
+            let mut authors: HashSet<Uuid> = get_followee_ids(db, user_id)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .into_iter()
+                .collect();
+            authors.insert(user_id);
+            Some(authors)
+        } else {
+            None
+        };
+        let hidden_authors = match user_id {
+            // As in the chirps listing, asking for an author's chirps shows them even if the author is muted.
+            Some(user_id) if params.author_id.is_none() => get_hidden_author_ids(db, user_id)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .into_iter()
+                .collect(),
+            _ => HashSet::new(),
+        };
+        Ok(Self {
+            author_id: params.author_id,
+            authors,
+            hidden_authors,
+        })
+    }
+
+    fn matches(&self, event: &StreamEvent) -> bool {
+        !self.hidden_authors.contains(&event.author_id)
+            && self.author_id.is_none_or(|id| id == event.author_id)
+            && self
+                .authors
+                .as_ref()
+                .is_none_or(|authors| authors.contains(&event.author_id))
+    }
+}
+
+/// Server-Sent Events with the event name as the SSE event type and the chirp as JSON data.
+pub async fn sse(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    Extension(stream): Extension<ChirpStream>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+) -> Response {
+    let filter = match StreamFilter::new(&db, &key, &headers, params).await {
+        Ok(filter) => filter,
+        Err(status) => return status.into_response(),
+    };
+    let shutdown = WatchStream::new(stream.shutdown.subscribe())
+        .filter(|&shutting_down| shutting_down)
+        .map(|_| None);
+    let events = BroadcastStream::new(stream.sender.subscribe())
+        .filter_map(move |event| match event {
+            Ok(event) => filter
+                .matches(&event)
+                .then(|| Event::default().event(event.name).data(&event.data)),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                Some(Event::default().event("lagged").data(missed.to_string()))
+            }
+        })
+        .map(Some)
+        .merge(shutdown)
+        .take_while(Option::is_some)
+        .filter_map(|event| event)
+        .map(Ok::<_, Infallible>);
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Sends each event as a text message of the form `{"event": <name>, "data": <chirp>}`. Messages from the client are ignored.
+pub async fn websocket(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    Extension(stream): Extension<ChirpStream>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let filter = match StreamFilter::new(&db, &key, &headers, params).await {
+        Ok(filter) => filter,
+        Err(status) => return status.into_response(),
+    };
+    let receiver = stream.sender.subscribe();
+    let shutdown = stream.shutdown.subscribe();
+    upgrade.on_upgrade(move |socket| forward_events(socket, receiver, shutdown, filter))
+}
+
+/// Resolves once the server shuts down.
+async fn shutting_down(shutdown: &mut watch::Receiver<bool>) {
+    // Also fails if the sender is gone, which only happens during shutdown.
+    let _ = shutdown.wait_for(|&shutting_down| shutting_down).await;
+}
+
+/// Closes the socket with "going away" when the server shuts down.
+async fn forward_events(
+    mut socket: WebSocket,
+    mut receiver: broadcast::Receiver<Arc<StreamEvent>>,
+    mut shutdown: watch::Receiver<bool>,
+    filter: StreamFilter,
+) {
+    loop {
+        tokio::select! {
+            () = shutting_down(&mut shutdown) => {
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "Server is shutting down".into(),
+                    })))
+                    .await;
+                break;
+            }
+            event = receiver.recv() => {
+                let message = match event {
+                    Ok(event) if filter.matches(&event) => {
+                        format!(r#"{{"event":"{}","data":{}}}"#, event.name, event.data)
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => format!(r#"{{"event":"lagged","data":{missed}}}"#),
+                    Err(RecvError::Closed) => break,
+                };
+                if socket.send(Message::Text(message)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,757 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    random,
+    sync::Arc,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hmac::{Hmac, Mac};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Client, Url,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::PgPool;
+use time::{Duration, OffsetDateTime};
+use tokio::{sync::Notify, task::JoinSet};
+use uuid::Uuid;
+
+use crate::{
+    api::authenticate_active_user,
+    auth::JwtKey,
+    outbox::{DomainEvent, HandlerFuture, OutboxEvent, OutboxSubscriber},
+    queries::{
+        claim_webhook_deliveries, complete_webhook_delivery, count_webhook_endpoints,
+        delete_webhook_endpoint, enqueue_webhook_deliveries, fail_webhook_delivery,
+        get_webhook_deliveries, get_webhook_endpoint, get_webhook_endpoints,
+        insert_webhook_endpoint, PendingDelivery, WebhookEndpoint,
+    },
+    state::{AppState, Platform},
+};
+
+/// Endpoints that don't respond within this time are treated as failed.
+const DELIVERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How long a claimed delivery is reserved for the worker that claimed it.
+const DELIVERY_LEASE: Duration = Duration::minutes(1);
+
+/// Deliveries are sent concurrently in batches of up to this many.
+const DELIVERY_BATCH_SIZE: i64 = 20;
+
+/// With exponential backoff starting at `RETRY_BASE_DELAY`, the last attempt is made a bit over an hour after the first.
+const MAX_DELIVERY_ATTEMPTS: i32 = 8;
+
+const RETRY_BASE_DELAY: Duration = Duration::seconds(30);
+
+/// How often the queue is checked without being woken up, which picks up retries that have become due.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+const MAX_ENDPOINTS_PER_USER: i64 = 10;
+
+/// Number of deliveries returned by the delivery log endpoints.
+const DELIVERY_LOG_LIMIT: i64 = 100;
+
+/// Events that can be sent to webhook endpoints.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum WebhookEvent {
+    #[serde(rename = "chirp.created")]
+    ChirpCreated,
+    #[serde(rename = "chirp.deleted")]
+    ChirpDeleted,
+    #[serde(rename = "user.upgraded")]
+    UserUpgraded,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::ChirpCreated => "chirp.created",
+            WebhookEvent::ChirpDeleted => "chirp.deleted",
+            WebhookEvent::UserUpgraded => "user.upgraded",
+        }
+    }
+}
+
+/// The body of every delivery. `id` is the same for all deliveries of an event, so receivers can deduplicate retries.
+#[derive(Serialize)]
+struct WebhookPayload<'a, T: Serialize> {
+    id: Uuid,
+    event: WebhookEvent,
+    created_at: OffsetDateTime,
+    data: &'a T,
+}
+
+/// Sends events to the endpoints registered for them.
+///
+/// Events are queued in the `webhook_deliveries` table, which doubles as the delivery log, and sent by a background worker.
+/// Failed deliveries are retried with exponential backoff until `MAX_DELIVERY_ATTEMPTS` is reached.
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    db: PgPool,
+    client: Client,
+    /// For endpoints registered by users outside of development, which may only be reached at public addresses.
+    restricted_client: Option<Client>,
+    wakeup: Arc<Notify>,
+}
+
+impl WebhookDispatcher {
+    pub fn new(db: PgPool, platform: Platform) -> Self {
+        Self {
+            db,
+            client: delivery_client(false),
+            restricted_client: (platform != Platform::Dev).then(|| delivery_client(true)),
+            wakeup: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Queues the event for every endpoint subscribed to it that may see events about `user_id`.
+    /// Queuing the same event again does nothing, so it is safe to retry.
+    async fn publish(
+        &self,
+        event_id: Uuid,
+        created_at: OffsetDateTime,
+        event: WebhookEvent,
+        user_id: Uuid,
+        data: &impl Serialize,
+    ) -> Result<(), sqlx::Error> {
+        let payload = serde_json::to_string(&WebhookPayload {
+            id: event_id,
+            event,
+            created_at,
+            data,
+        })
+        .expect("Webhook payloads serialize to JSON");
+        if enqueue_webhook_deliveries(&self.db, event_id, event.as_str(), user_id, &payload).await?
+            > 0
+        {
+            self.wakeup.notify_one();
+        }
+        Ok(())
+    }
+
+    pub fn spawn_worker(&self) {
+        let dispatcher = self.clone();
+        tokio::spawn(async move { dispatcher.run().await });
+    }
+
+    async fn run(self) {
+        loop {
+            match claim_webhook_deliveries(&self.db, DELIVERY_LEASE, DELIVERY_BATCH_SIZE).await {
+                Ok(deliveries) if !deliveries.is_empty() => {
+                    let mut sends = JoinSet::new();
+                    for delivery in deliveries {
+                        let dispatcher = self.clone();
+                        sends.spawn(async move { dispatcher.send(delivery).await });
+                    }
+                    sends.join_all().await;
+                }
+                Ok(_) => {
+                    let _ = tokio::time::timeout(POLL_INTERVAL, self.wakeup.notified()).await;
+                }
+                Err(e) => {
+                    eprintln!("Failed to claim webhook deliveries: {e}");
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn send(&self, delivery: PendingDelivery) {
+        let outcome = match &self.restricted_client {
+            Some(client) if delivery.owner_id.is_some() => {
+                // Literal addresses are not resolved, so they are checked here. The endpoint may have been registered before the current rules.
+                match validate_endpoint_url(&delivery.url, true) {
+                    Ok(_) => deliver(client, &delivery).await,
+                    Err(error) => Err(DeliveryFailure {
+                        status: None,
+                        error,
+                    }),
+                }
+            }
+            _ => deliver(&self.client, &delivery).await,
+        };
+        let result = match outcome {
+            Ok(status) => complete_webhook_delivery(&self.db, delivery.delivery_id, status).await,
+            Err(failure) => {
+                let retry_at = (delivery.attempts < MAX_DELIVERY_ATTEMPTS)
+                    .then(|| OffsetDateTime::now_utc() + retry_delay(delivery.attempts));
+                fail_webhook_delivery(
+                    &self.db,
+                    delivery.delivery_id,
+                    failure.status,
+                    &failure.error,
+                    retry_at,
+                )
+                .await
+            }
+        };
+        if let Err(e) = result {
+            eprintln!(
+                "Failed to record webhook delivery {}: {e}",
+                delivery.delivery_id
+            );
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UserUpgraded {
+    user_id: Uuid,
+}
+
+/// Turns domain events into webhook deliveries. The outbox event id doubles as the webhook event id.
+impl OutboxSubscriber for WebhookDispatcher {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    fn handle<'a>(&'a self, event: &'a OutboxEvent) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let (id, at) = (event.event_id, event.created_at);
+            match &event.event {
+                DomainEvent::ChirpCreated { chirp } => {
+                    self.publish(id, at, WebhookEvent::ChirpCreated, chirp.user_id, chirp)
+                        .await?
+                }
+                DomainEvent::ChirpDeleted { chirp } => {
+                    self.publish(id, at, WebhookEvent::ChirpDeleted, chirp.user_id, chirp)
+                        .await?
+                }
+                &DomainEvent::UserUpgraded { user_id } => {
+                    self.publish(
+                        id,
+                        at,
+                        WebhookEvent::UserUpgraded,
+                        user_id,
+                        &UserUpgraded { user_id },
+                    )
+                    .await?
+                }
+                DomainEvent::UserFollowed { .. } => {}
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Redirects are not followed, so that an endpoint cannot bounce deliveries to an address it could not have registered.
+/// A restricted client refuses to connect to hosts that resolve to internal addresses.
+fn delivery_client(restricted: bool) -> Client {
+    let mut builder = Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .redirect(redirect::Policy::none())
+        .user_agent("Chirpy-Webhooks/1.0");
+    if restricted {
+        builder = builder.dns_resolver(Arc::new(PublicAddressResolver));
+    }
+    builder.build().expect("Failed to build webhook client")
+}
+
+/// Resolves hosts like the system resolver, but fails for hosts with any address that isn't public.
+/// Checking the addresses that are connected to, rather than the URL at registration, also catches hosts that are later pointed at internal addresses.
+struct PublicAddressResolver;
+
+impl Resolve for PublicAddressResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if addrs.iter().any(|addr| !is_public_address(addr.ip())) {
+                return Err(format!("{} resolves to an internal address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// IPv4 ranges that are not reachable on the public internet: this network, private, shared (carrier-grade NAT), loopback, link-local, IETF protocol assignments, documentation, 6to4 relay, benchmarking, multicast and reserved, including broadcast.
+const NON_PUBLIC_IPV4_RANGES: [(Ipv4Addr, u32); 15] = [
+    (Ipv4Addr::new(0, 0, 0, 0), 8),
+    (Ipv4Addr::new(10, 0, 0, 0), 8),
+    (Ipv4Addr::new(100, 64, 0, 0), 10),
+    (Ipv4Addr::new(127, 0, 0, 0), 8),
+    (Ipv4Addr::new(169, 254, 0, 0), 16),
+    (Ipv4Addr::new(172, 16, 0, 0), 12),
+    (Ipv4Addr::new(192, 0, 0, 0), 24),
+    (Ipv4Addr::new(192, 0, 2, 0), 24),
+    (Ipv4Addr::new(192, 88, 99, 0), 24),
+    (Ipv4Addr::new(192, 168, 0, 0), 16),
+    (Ipv4Addr::new(198, 18, 0, 0), 15),
+    (Ipv4Addr::new(198, 51, 100, 0), 24),
+    (Ipv4Addr::new(203, 0, 113, 0), 24),
+    (Ipv4Addr::new(224, 0, 0, 0), 4),
+    (Ipv4Addr::new(240, 0, 0, 0), 4),
+];
+
+/// Ranges within global unicast (`2000::/3`) that are not public: IETF protocol assignments, including Teredo, documentation and 6to4, which embeds arbitrary IPv4 addresses.
+const NON_PUBLIC_IPV6_RANGES: [(Ipv6Addr, u32); 3] = [
+    (Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0), 23),
+    (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0), 32),
+    (Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0), 16),
+];
+
+/// Whether the address is reachable on the public internet.
+/// IPv4-mapped and IPv4-compatible IPv6 addresses are classified by the IPv4 address they contain, so `::ffff:127.0.0.1` is loopback.
+fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !NON_PUBLIC_IPV4_RANGES
+            .iter()
+            .any(|&(network, prefix)| (u32::from(ip) ^ u32::from(network)) >> (32 - prefix) == 0),
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(ip) => is_public_address(IpAddr::V4(ip)),
+            None => {
+                let in_range = |(network, prefix): (Ipv6Addr, u32)| {
+                    (u128::from(ip) ^ u128::from(network)) >> (128 - prefix) == 0
+                };
+                in_range((Ipv6Addr::new(0x2000, 0, 0, 0, 0, 0, 0, 0), 3))
+                    && !NON_PUBLIC_IPV6_RANGES.into_iter().any(in_range)
+            }
+        },
+    }
+}
+
+/// Doubles with every failed attempt.
+fn retry_delay(attempts: i32) -> Duration {
+    RETRY_BASE_DELAY * 2i32.pow((attempts - 1).clamp(0, 16) as u32)
+}
+
+#[derive(Debug)]
+struct DeliveryFailure {
+    /// `None` if the endpoint didn't respond.
+    status: Option<i32>,
+    error: String,
+}
+
+/// Sends a delivery, signed like Polka signs its webhooks to us: `X-Chirpy-Signature` is `v1=` followed by the hex HMAC-SHA256 of `<timestamp>.<body>`, keyed with the endpoint's secret.
+/// Any 2xx response counts as delivered.
+async fn deliver(client: &Client, delivery: &PendingDelivery) -> Result<i32, DeliveryFailure> {
+    let timestamp = OffsetDateTime::now_utc().unix_timestamp().to_string();
+    let response = client
+        .post(&delivery.url)
+        .header("Content-Type", "application/json")
+        .header("X-Chirpy-Event", &delivery.event)
+        .header("X-Chirpy-Delivery", delivery.delivery_id.to_string())
+        .header("X-Chirpy-Timestamp", &timestamp)
+        .header(
+            "X-Chirpy-Signature",
+            format!(
+                "v1={}",
+                sign(&delivery.secret, &timestamp, &delivery.payload)
+            ),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .map_err(|e| DeliveryFailure {
+            status: None,
+            error: e.to_string(),
+        })?;
+
+    let status = response.status();
+    if status.is_success() {
+        Ok(status.as_u16() as i32)
+    } else {
+        Err(DeliveryFailure {
+            status: Some(status.as_u16() as i32),
+            error: format!("Endpoint responded with {status}"),
+        })
+    }
+}
+
+fn sign(secret: &str, timestamp: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn make_webhook_secret() -> String {
+    let bits: (u128, u128) = (random::random(), random::random());
+    format!("whsec_{:032x}{:032x}", bits.0, bits.1)
+}
+
+#[derive(Deserialize)]
+pub struct CreateEndpointPayload {
+    url: String,
+    events: Vec<WebhookEvent>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedEndpoint {
+    #[serde(flatten)]
+    endpoint: WebhookEndpoint,
+    /// Only returned when the endpoint is created.
+    secret: String,
+}
+
+#[derive(Serialize)]
+pub struct EndpointValidationError {
+    error: String,
+}
+
+/// Outside of development, endpoints registered by users must use HTTPS and may not point at the server's own network.
+/// Hosts are not resolved here, so this only catches literal addresses. Hosts that resolve to internal addresses are refused when delivering, see `PublicAddressResolver`.
+fn validate_endpoint_url(url: &str, restricted: bool) -> Result<Url, String> {
+    let url = Url::parse(url).map_err(|e| format!("Invalid URL: {e}"))?;
+    match url.scheme() {
+        "https" => {}
+        "http" if !restricted => {}
+        _ => return Err("Webhook URLs must use HTTPS".to_owned()),
+    }
+    let host = url.host_str().ok_or("Webhook URLs must have a host")?;
+    if restricted {
+        let is_internal = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(ip) => !is_public_address(ip),
+            Err(_) => host.eq_ignore_ascii_case("localhost"),
+        };
+        if is_internal {
+            return Err("Webhook URLs may not point at internal addresses".to_owned());
+        }
+    }
+    Ok(url)
+}
+
+async fn create(
+    db: &PgPool,
+    owner_id: Option<Uuid>,
+    restricted: bool,
+    payload: CreateEndpointPayload,
+) -> Response {
+    let url = match validate_endpoint_url(&payload.url, restricted) {
+        Ok(url) => url,
+        Err(error) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(EndpointValidationError { error }),
+            )
+                .into_response()
+        }
+    };
+    let mut events: Vec<String> = payload
+        .events
+        .iter()
+        .map(|event| event.as_str().to_owned())
+        .collect();
+    events.sort();
+    events.dedup();
+    if events.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(EndpointValidationError {
+                error: "At least one event is required".to_owned(),
+            }),
+        )
+            .into_response();
+    }
+
+    let secret = make_webhook_secret();
+    match insert_webhook_endpoint(db, owner_id, url.as_str(), &secret, &events).await {
+        Ok(endpoint) => (
+            StatusCode::CREATED,
+            Json(CreatedEndpoint { endpoint, secret }),
+        )
+            .into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn list(db: &PgPool, owner_id: Option<Uuid>) -> Response {
+    match get_webhook_endpoints(db, owner_id).await {
+        Ok(endpoints) => Json(endpoints).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn delete(db: &PgPool, endpoint_id: Uuid, owner_id: Option<Uuid>) -> Response {
+    match delete_webhook_endpoint(db, endpoint_id, owner_id).await {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+    .into_response()
+}
+
+async fn deliveries(db: &PgPool, endpoint_id: Uuid, owner_id: Option<Uuid>) -> Response {
+    match get_webhook_endpoint(db, endpoint_id, owner_id).await {
+        Ok(_) => {}
+        Err(sqlx::Error::RowNotFound) => return StatusCode::NOT_FOUND.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    match get_webhook_deliveries(db, endpoint_id, DELIVERY_LOG_LIMIT).await {
+        Ok(deliveries) => Json(deliveries).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Registers an endpoint for events about the authenticated user.
+pub async fn create_endpoint(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateEndpointPayload>,
+) -> impl IntoResponse {
+    let user = match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => user,
+        Err(status) => return status.into_response(),
+    };
+    match count_webhook_endpoints(&db, user.id).await {
+        Ok(count) if count >= MAX_ENDPOINTS_PER_USER => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(EndpointValidationError {
+                    error: format!(
+                        "At most {MAX_ENDPOINTS_PER_USER} webhook endpoints are allowed"
+                    ),
+                }),
+            )
+                .into_response()
+        }
+        Ok(_) => {}
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    create(
+        &db,
+        Some(user.id),
+        state.config.platform != Platform::Dev,
+        payload,
+    )
+    .await
+}
+
+pub async fn list_endpoints(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => list(&db, Some(user.id)).await,
+        Err(status) => status.into_response(),
+    }
+}
+
+pub async fn delete_endpoint(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(endpoint_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => delete(&db, endpoint_id, Some(user.id)).await,
+        Err(status) => status.into_response(),
+    }
+}
+
+pub async fn list_deliveries(
+    Extension(db): Extension<PgPool>,
+    Extension(key): Extension<JwtKey>,
+    headers: HeaderMap,
+    Path(endpoint_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match authenticate_active_user(&db, &headers, &key).await {
+        Ok(user) => deliveries(&db, endpoint_id, Some(user.id)).await,
+        Err(status) => status.into_response(),
+    }
+}
+
+// The admin handlers are behind `require_role(Role::Admin)`. Endpoints they create receive events about all users.
+
+pub async fn admin_create_endpoint(
+    Extension(db): Extension<PgPool>,
+    Json(payload): Json<CreateEndpointPayload>,
+) -> impl IntoResponse {
+    create(&db, None, false, payload).await
+}
+
+/// Lists the endpoints of all users as well as those created by admins.
+pub async fn admin_list_endpoints(Extension(db): Extension<PgPool>) -> impl IntoResponse {
+    list(&db, None).await
+}
+
+pub async fn admin_delete_endpoint(
+    Extension(db): Extension<PgPool>,
+    Path(endpoint_id): Path<Uuid>,
+) -> impl IntoResponse {
+    delete(&db, endpoint_id, None).await
+}
+
+pub async fn admin_list_deliveries(
+    Extension(db): Extension<PgPool>,
+    Path(endpoint_id): Path<Uuid>,
+) -> impl IntoResponse {
+    deliveries(&db, endpoint_id, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Bytes, routing::post, Router};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use super::*;
+
+    /// Starts a server that answers every request with `status` and passes the headers and body on to the returned receiver.
+    async fn stub_endpoint(
+        status: StatusCode,
+    ) -> (String, mpsc::UnboundedReceiver<(HeaderMap, Bytes)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                sender.send((headers, body)).unwrap();
+                status
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, receiver)
+    }
+
+    fn pending_delivery(url: String) -> PendingDelivery {
+        PendingDelivery {
+            delivery_id: Uuid::new_v4(),
+            event: "chirp.created".to_owned(),
+            payload: r#"{"id":"1","event":"chirp.created","data":{}}"#.to_owned(),
+            attempts: 1,
+            url,
+            secret: make_webhook_secret(),
+            owner_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_payload() {
+        let (url, mut received) = stub_endpoint(StatusCode::NO_CONTENT).await;
+        let delivery = pending_delivery(url);
+
+        let status = deliver(&delivery_client(false), &delivery).await.unwrap();
+        assert_eq!(status, 204);
+
+        let (headers, body) = received.recv().await.unwrap();
+        assert_eq!(body, delivery.payload.as_bytes());
+        assert_eq!(headers["x-chirpy-event"], "chirp.created");
+        assert_eq!(
+            headers["x-chirpy-delivery"],
+            delivery.delivery_id.to_string().as_str()
+        );
+        let timestamp = headers["x-chirpy-timestamp"].to_str().unwrap();
+        let expected = format!(
+            "v1={}",
+            sign(&delivery.secret, timestamp, &delivery.payload)
+        );
+        assert_eq!(headers["x-chirpy-signature"], expected.as_str());
+    }
+
+    #[tokio::test]
+    async fn error_responses_fail_the_delivery() {
+        let (url, _received) = stub_endpoint(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let failure = deliver(&delivery_client(false), &pending_delivery(url))
+            .await
+            .unwrap_err();
+        assert_eq!(failure.status, Some(500));
+    }
+
+    #[tokio::test]
+    async fn redirects_are_not_followed() {
+        let (url, _received) = stub_endpoint(StatusCode::TEMPORARY_REDIRECT).await;
+        let failure = deliver(&delivery_client(false), &pending_delivery(url))
+            .await
+            .unwrap_err();
+        assert_eq!(failure.status, Some(307));
+    }
+
+    #[tokio::test]
+    async fn unreachable_endpoints_fail_without_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        drop(listener);
+        let failure = deliver(&delivery_client(false), &pending_delivery(url))
+            .await
+            .unwrap_err();
+        assert_eq!(failure.status, None);
+    }
+
+    #[test]
+    fn retry_delay_doubles() {
+        assert_eq!(retry_delay(1), Duration::seconds(30));
+        assert_eq!(retry_delay(2), Duration::seconds(60));
+        assert_eq!(retry_delay(4), Duration::seconds(240));
+    }
+
+    #[test]
+    fn restricted_urls_must_be_public_https() {
+        assert!(validate_endpoint_url("https://example.com/hook", true).is_ok());
+        for url in [
+            "http://example.com/hook",
+            "https://localhost/hook",
+            "https://10.1.2.3/hook",
+            "https://[::1]/hook",
+            "https://[::ffff:127.0.0.1]/hook",
+            "https://[::ffff:a9fe:a9fe]/hook",
+            "https://100.64.0.1/hook",
+            "https://169.254.169.254/hook",
+            "https://2130706433/hook",
+            "https://[fd00::1]/hook",
+            "ftp://example.com",
+            "not a url",
+        ] {
+            assert!(validate_endpoint_url(url, true).is_err(), "{url}");
+        }
+        assert!(validate_endpoint_url("http://localhost:8000/hook", false).is_ok());
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"] {
+            assert!(is_public_address(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "100.127.255.255",
+            "172.31.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "198.18.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "::127.0.0.1",
+            "fe80::1",
+            "fc00::1",
+            "ff02::1",
+            "2001:db8::1",
+            "2002:7f00:1::",
+        ] {
+            assert!(!is_public_address(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn restricted_client_refuses_hosts_resolving_to_internal_addresses() {
+        let (url, mut received) = stub_endpoint(StatusCode::NO_CONTENT).await;
+        let url = url.replace("127.0.0.1", "localhost");
+        let status = deliver(&delivery_client(false), &pending_delivery(url.clone()))
+            .await
+            .unwrap();
+        assert_eq!(status, 204);
+        received.recv().await.unwrap();
+
+        let failure = deliver(&delivery_client(true), &pending_delivery(url))
+            .await
+            .unwrap_err();
+        assert_eq!(failure.status, None);
+        assert!(received.try_recv().is_err());
+    }
+}